@@ -5,6 +5,7 @@ use actix_framework::auth::get_identity_service;
 use actix_framework::cache::add_cache;
 use actix_framework::config::CONFIG;
 use actix_framework::database::add_pool;
+use actix_framework::mailer::add_mailer;
 use actix_framework::routes::routes;
 use actix_framework::state::new_state;
 use actix_web::{middleware::Logger, App, HttpServer};
@@ -41,6 +42,9 @@ async fn main() -> std::io::Result<()> {
             // Adds a Database Pool for use in the Actix Data Extractor
             // In a handler, add "pool: Data<PoolType>" param for auto extraction
             .configure(add_pool)
+            // Adds the Mailer for use in the Actix Data Extractor
+            // In a handler, add "mailer: MailerType" param for auto extraction
+            .configure(add_mailer)
             // Pull in default framework defaults
             // This can be removed if they're not needed
             .configure(routes)
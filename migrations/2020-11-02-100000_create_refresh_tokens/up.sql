@@ -0,0 +1,12 @@
+CREATE TABLE refresh_tokens (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(36) NOT NULL,
+  family_id VARCHAR(36) NOT NULL,
+  token_hash VARCHAR(64) NOT NULL,
+  expires_at DATETIME NOT NULL,
+  rotated_at DATETIME NULL,
+  revoked_at DATETIME NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE KEY refresh_tokens_token_hash (token_hash),
+  KEY refresh_tokens_family_id (family_id)
+);
@@ -0,0 +1,42 @@
+CREATE TABLE roles (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  name VARCHAR(100) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE KEY roles_name (name)
+);
+
+CREATE TABLE permissions (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  name VARCHAR(100) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE KEY permissions_name (name)
+);
+
+CREATE TABLE role_permissions (
+  role_id VARCHAR(36) NOT NULL,
+  permission_id VARCHAR(36) NOT NULL,
+  PRIMARY KEY (role_id, permission_id),
+  FOREIGN KEY (role_id) REFERENCES roles (id) ON DELETE CASCADE,
+  FOREIGN KEY (permission_id) REFERENCES permissions (id) ON DELETE CASCADE
+);
+
+CREATE TABLE user_roles (
+  user_id VARCHAR(36) NOT NULL,
+  role_id VARCHAR(36) NOT NULL,
+  PRIMARY KEY (user_id, role_id),
+  FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+  FOREIGN KEY (role_id) REFERENCES roles (id) ON DELETE CASCADE
+);
+
+insert into roles (id, name) values
+('00000000-0000-0000-0000-000000000001', 'admin'),
+('00000000-0000-0000-0000-000000000002', 'user');
+
+insert into permissions (id, name) values
+('00000000-0000-0000-0000-000000000101', 'user:delete');
+
+insert into role_permissions (role_id, permission_id) values
+('00000000-0000-0000-0000-000000000001', '00000000-0000-0000-0000-000000000101');
+
+insert into user_roles (user_id, role_id) values
+('00000000-0000-0000-0000-000000000000', '00000000-0000-0000-0000-000000000001');
@@ -0,0 +1,10 @@
+CREATE TABLE password_resets (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(36) NOT NULL,
+  token_hash VARCHAR(64) NOT NULL,
+  expires_at DATETIME NOT NULL,
+  used_at DATETIME NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE KEY password_resets_token_hash (token_hash),
+  KEY password_resets_user_id (user_id)
+);
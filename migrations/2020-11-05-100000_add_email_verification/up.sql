@@ -0,0 +1,15 @@
+ALTER TABLE users ADD COLUMN email_verified_at DATETIME NULL AFTER password;
+
+-- Existing users signed up before verification, so treat them as verified
+UPDATE users SET email_verified_at = NOW();
+
+CREATE TABLE email_verifications (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(36) NOT NULL,
+  token_hash VARCHAR(64) NOT NULL,
+  expires_at DATETIME NOT NULL,
+  used_at DATETIME NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE KEY email_verifications_token_hash (token_hash),
+  KEY email_verifications_user_id (user_id)
+);
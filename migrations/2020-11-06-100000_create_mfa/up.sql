@@ -0,0 +1,17 @@
+CREATE TABLE user_mfa (
+  user_id VARCHAR(36) NOT NULL PRIMARY KEY,
+  secret VARCHAR(64) NOT NULL,
+  confirmed_at DATETIME NULL,
+  last_used_step BIGINT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE mfa_recovery_codes (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(36) NOT NULL,
+  code_hash VARCHAR(64) NOT NULL,
+  used_at DATETIME NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE KEY mfa_recovery_codes_code_hash (code_hash),
+  KEY mfa_recovery_codes_user_id (user_id)
+);
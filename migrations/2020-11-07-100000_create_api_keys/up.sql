@@ -0,0 +1,13 @@
+CREATE TABLE api_keys (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(36) NOT NULL,
+  name VARCHAR(100) NOT NULL,
+  prefix VARCHAR(12) NOT NULL,
+  key_hash VARCHAR(64) NOT NULL,
+  scopes TEXT NOT NULL,
+  expires_at DATETIME NULL,
+  last_used_at DATETIME NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE KEY api_keys_key_hash (key_hash),
+  KEY api_keys_user_id (user_id)
+);
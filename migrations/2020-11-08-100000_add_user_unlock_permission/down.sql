@@ -0,0 +1 @@
+DELETE FROM permissions WHERE id = '00000000-0000-0000-0000-000000000102'
\ No newline at end of file
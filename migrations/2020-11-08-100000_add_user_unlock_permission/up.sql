@@ -0,0 +1,5 @@
+insert into permissions (id, name) values
+('00000000-0000-0000-0000-000000000102', 'user:unlock');
+
+insert into role_permissions (role_id, permission_id) values
+('00000000-0000-0000-0000-000000000001', '00000000-0000-0000-0000-000000000102');
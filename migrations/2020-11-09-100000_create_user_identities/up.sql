@@ -0,0 +1,10 @@
+CREATE TABLE user_identities (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(36) NOT NULL,
+  provider VARCHAR(100) NOT NULL,
+  subject VARCHAR(255) NOT NULL,
+  email VARCHAR(100) NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  UNIQUE KEY user_identities_provider_subject (provider, subject),
+  KEY user_identities_user_id (user_id)
+);
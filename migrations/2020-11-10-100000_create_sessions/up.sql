@@ -0,0 +1,10 @@
+CREATE TABLE sessions (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  user_id VARCHAR(36) NOT NULL,
+  user_agent VARCHAR(255) NULL,
+  ip VARCHAR(45) NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  last_seen_at DATETIME NOT NULL,
+  revoked_at DATETIME NULL,
+  KEY sessions_user_id (user_id)
+);
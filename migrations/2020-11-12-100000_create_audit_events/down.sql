@@ -0,0 +1,2 @@
+DELETE FROM permissions WHERE id = '00000000-0000-0000-0000-000000000104';
+DROP TABLE audit_events
\ No newline at end of file
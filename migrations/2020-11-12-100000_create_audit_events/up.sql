@@ -0,0 +1,21 @@
+CREATE TABLE audit_events (
+  id VARCHAR(36) NOT NULL PRIMARY KEY,
+  actor_id VARCHAR(36) NULL,
+  impersonator_id VARCHAR(36) NULL,
+  action VARCHAR(100) NOT NULL,
+  target_id VARCHAR(255) NULL,
+  ip VARCHAR(45) NULL,
+  user_agent VARCHAR(255) NULL,
+  metadata TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  KEY audit_events_actor_id (actor_id),
+  KEY audit_events_action (action),
+  KEY audit_events_target_id (target_id),
+  KEY audit_events_created_at (created_at)
+);
+
+insert into permissions (id, name) values
+('00000000-0000-0000-0000-000000000104', 'audit:read');
+
+insert into role_permissions (role_id, permission_id) values
+('00000000-0000-0000-0000-000000000001', '00000000-0000-0000-0000-000000000104');
@@ -0,0 +1,167 @@
+//! Record security-relevant actions in the audit_events table
+//!
+//! Handlers describe each event with the Audit builder:
+//!
+//! ```ignore
+//! Audit::new(Action::UserDeleted, &req)
+//!     .actor(&user)
+//!     .target(user_id)
+//!     .record(pool)
+//!     .await;
+//! ```
+//!
+//! The client's IP address and user agent are read from the request, and
+//! the impersonating admin, if any, from the actor.  Recording an event
+//! never fails the request, failures are logged instead.
+
+use crate::database::PoolType;
+use crate::helpers::{client_ip, user_agent};
+use crate::models::audit_event::{create, AuditEvent};
+use crate::models::user::AuthUser;
+use actix_web::web::{block, Data, HttpRequest};
+use chrono::Utc;
+use log::error;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+/// What happened, stored as the event's action
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    ImpersonatedWrite,
+    ImpersonationEnded,
+    ImpersonationStarted,
+    Login,
+    LoginFailed,
+    Logout,
+    LogoutAll,
+    PasswordChanged,
+    PasswordReset,
+    SessionRevoked,
+    UserCreated,
+    UserDeleted,
+    UserUnlocked,
+    UserUpdated,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::ImpersonatedWrite => "impersonation.write",
+            Action::ImpersonationEnded => "impersonation.end",
+            Action::ImpersonationStarted => "impersonation.start",
+            Action::Login => "login",
+            Action::LoginFailed => "login.failed",
+            Action::Logout => "logout",
+            Action::LogoutAll => "logout.all",
+            Action::PasswordChanged => "password.change",
+            Action::PasswordReset => "password.reset",
+            Action::SessionRevoked => "session.revoke",
+            Action::UserCreated => "user.create",
+            Action::UserDeleted => "user.delete",
+            Action::UserUnlocked => "user.unlock",
+            Action::UserUpdated => "user.update",
+        }
+    }
+}
+
+/// An audit event being described, see the module docs
+#[derive(Clone, Debug, PartialEq)]
+pub struct Audit {
+    action: Action,
+    actor_id: Option<String>,
+    impersonator_id: Option<String>,
+    target_id: Option<String>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    metadata: Value,
+}
+
+impl Audit {
+    pub fn new(action: Action, req: &HttpRequest) -> Self {
+        Audit {
+            action,
+            actor_id: None,
+            impersonator_id: None,
+            target_id: None,
+            ip: client_ip(req),
+            user_agent: user_agent(req),
+            metadata: json!({}),
+        }
+    }
+
+    /// The logged in user who acted, along with their impersonator
+    pub fn actor(mut self, user: &AuthUser) -> Self {
+        self.actor_id = Some(user.id.clone());
+        self.impersonator_id = user.impersonator.clone();
+        self
+    }
+
+    /// The user who acted, when they aren't logged in yet (e.g. login)
+    pub fn actor_id(mut self, actor_id: Uuid) -> Self {
+        self.actor_id = Some(actor_id.to_string());
+        self
+    }
+
+    /// What was acted on, usually a user's id
+    pub fn target<T: ToString>(mut self, target_id: T) -> Self {
+        self.target_id = Some(target_id.to_string());
+        self
+    }
+
+    /// Anything else worth knowing, as a JSON object
+    pub fn metadata(mut self, metadata: Value) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Store the event, logging rather than returning any failure
+    pub async fn record(self, pool: Data<PoolType>) {
+        let audit_event = self.into_event();
+        let action = audit_event.action.clone();
+        if let Err(error) = block(move || create(&pool, &audit_event)).await {
+            error!("Cannot record the audit event {}: {}", action, error);
+        }
+    }
+
+    fn into_event(self) -> AuditEvent {
+        AuditEvent {
+            id: Uuid::new_v4().to_string(),
+            actor_id: self.actor_id,
+            impersonator_id: self.impersonator_id,
+            action: self.action.as_str().into(),
+            target_id: self.target_id,
+            ip: self.ip,
+            user_agent: self.user_agent,
+            metadata: self.metadata.to_string(),
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use actix_web::{http::header::USER_AGENT, test};
+
+    #[test]
+    fn it_describes_an_event() {
+        let req = test::TestRequest::with_header(USER_AGENT, "curl/7.64.1").to_http_request();
+        let user = AuthUser {
+            id: Uuid::new_v4().to_string(),
+            email: "audit@nothing.org".into(),
+            roles: vec![],
+            permissions: vec![],
+            impersonator: Some(Uuid::new_v4().to_string()),
+        };
+        let audit_event = Audit::new(Action::UserDeleted, &req)
+            .actor(&user)
+            .target("a421a56e-8652-4da6-90ee-59dfebb9d1b4")
+            .metadata(json!({"email": "deleted@nothing.org"}))
+            .into_event();
+        assert_eq!(audit_event.action, "user.delete");
+        assert_eq!(audit_event.actor_id, Some(user.id));
+        assert_eq!(audit_event.impersonator_id, user.impersonator);
+        assert_eq!(audit_event.user_agent, Some("curl/7.64.1".into()));
+        assert_eq!(audit_event.metadata, r#"{"email":"deleted@nothing.org"}"#);
+    }
+}
@@ -1,55 +1,463 @@
 //! Handle JWTs, hash passwords, and Identity Service
 
+use crate::cache::{get, incr, set_ex, set_nx_ex, Cache};
 use crate::config::CONFIG;
 use crate::errors::ApiError;
-use actix_identity::{CookieIdentityPolicy, IdentityService};
+use crate::keys::KEYS;
+use actix_identity::{CookieIdentityPolicy, IdentityService, RequestIdentity};
+use actix_web::{
+    http::header::{HeaderMap, AUTHORIZATION},
+    web::Data,
+    HttpMessage, HttpRequest,
+};
+use argon2::{hash_encoded, verify_encoded, Config as HashConfig, Variant, Version};
 use argon2rs::argon2i_simple;
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::{decode, decode_header, encode, Header, Validation};
+use rand::Rng;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::max;
 use time::Duration as TimeDuration;
 use uuid::Uuid;
 
+/// Where JWTs are read from on incoming requests
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenSource {
+    Cookie,
+    Bearer,
+    Both,
+}
+
+impl TokenSource {
+    pub fn uses_cookie(&self) -> bool {
+        *self != TokenSource::Bearer
+    }
+
+    pub fn uses_bearer(&self) -> bool {
+        *self != TokenSource::Cookie
+    }
+}
+
+/// The claims of a JWT
+///
+/// Applications can add their own claims (e.g. a tenant) with with_claims.
+/// They're flattened alongside the framework's claims, so their names must
+/// not clash, and are read back with the Claims<T> extractor.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
-pub struct PrivateClaim {
+pub struct PrivateClaim<T = NoClaims> {
     pub user_id: Uuid,
     pub email: String,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+    pub jti: Uuid,
+    pub generation: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<Impersonator>,
     exp: i64,
+    #[serde(flatten)]
+    pub claims: T,
+}
+
+/// The admin behind an impersonation JWT, see handlers::impersonation
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Impersonator {
+    pub user_id: Uuid,
+    pub session_id: Option<Uuid>,
+    #[serde(default)]
+    pub generation: i64,
 }
 
+/// No application-defined claims, the default for PrivateClaim
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct NoClaims {}
+
 impl PrivateClaim {
     pub fn new(user_id: Uuid, email: String) -> Self {
         Self {
             user_id,
             email,
-            exp: (Utc::now() + Duration::hours(CONFIG.jwt_expiration)).timestamp(),
+            roles: vec![],
+            permissions: vec![],
+            jti: Uuid::new_v4(),
+            generation: 0,
+            session_id: None,
+            impersonator: None,
+            exp: (Utc::now() + Duration::minutes(CONFIG.jwt_expiration_minutes)).timestamp(),
+            claims: NoClaims {},
         }
     }
 }
 
-/// Create a json web token (JWT)
-pub fn create_jwt(private_claim: PrivateClaim) -> Result<String, ApiError> {
-    let encoding_key = EncodingKey::from_secret(&CONFIG.jwt_key.as_ref());
-    encode(&Header::default(), &private_claim, &encoding_key)
-        .map_err(|e| ApiError::CannotEncodeJwtToken(e.to_string()))
+impl<T> PrivateClaim<T> {
+    /// Embed the user's roles and permissions, see RequirePermission
+    pub fn with_grants(mut self, roles: Vec<String>, permissions: Vec<String>) -> Self {
+        self.roles = roles;
+        self.permissions = permissions;
+        self
+    }
+
+    /// Set the user's token generation, see revoke_all_jwts()
+    pub fn with_generation(mut self, generation: i64) -> Self {
+        self.generation = generation;
+        self
+    }
+
+    /// Set the session the token was issued for, see revoke_session()
+    pub fn with_session(mut self, session_id: Uuid) -> Self {
+        self.session_id = Some(session_id);
+        self
+    }
+
+    /// Issue the token to an admin acting as the user
+    /// Revoking the admin's session, or all of their JWTs, also revokes the token
+    pub fn with_impersonator(
+        mut self,
+        user_id: Uuid,
+        session_id: Option<Uuid>,
+        generation: i64,
+    ) -> Self {
+        self.impersonator = Some(Impersonator {
+            user_id,
+            session_id,
+            generation,
+        });
+        self
+    }
+
+    /// Expire the token sooner or later than JWT_EXPIRATION_MINUTES
+    pub fn with_expiration(mut self, minutes: i64) -> Self {
+        self.exp = (Utc::now() + Duration::minutes(minutes)).timestamp();
+        self
+    }
+
+    /// Embed application-defined claims
+    pub fn with_claims<U>(self, claims: U) -> PrivateClaim<U> {
+        PrivateClaim {
+            user_id: self.user_id,
+            email: self.email,
+            roles: self.roles,
+            permissions: self.permissions,
+            jti: self.jti,
+            generation: self.generation,
+            session_id: self.session_id,
+            impersonator: self.impersonator,
+            exp: self.exp,
+            claims,
+        }
+    }
+}
+
+/// Proves that a user passed the password step of a login with MFA enabled
+///
+/// The claim has none of the PrivateClaim fields, so it can't be used in place
+/// of a JWT, and vice versa.  Each token can only be exchanged once.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MfaPendingClaim {
+    pub mfa_user_id: Uuid,
+    jti: Uuid,
+    exp: i64,
 }
 
-/// Decode a json web token (JWT)
+/// Create a json web token (JWT), signed with the current key, see KEYS
+pub fn create_jwt<T: Serialize>(private_claim: PrivateClaim<T>) -> Result<String, ApiError> {
+    encode_claim(&private_claim)
+}
+
+/// Decode a json web token (JWT), verified with the key named in its kid header
 pub fn decode_jwt(token: &str) -> Result<PrivateClaim, ApiError> {
-    let decoding_key = DecodingKey::from_secret(&CONFIG.jwt_key.as_ref());
-    decode::<PrivateClaim>(token, &decoding_key, &Validation::default())
+    decode_claim(token)
+}
+
+/// Decode a JWT along with its application-defined claims
+pub fn decode_jwt_with_claims<T: DeserializeOwned>(
+    token: &str,
+) -> Result<PrivateClaim<T>, ApiError> {
+    decode_claim(token)
+}
+
+/// Create a short-lived token to exchange for a JWT once MFA is verified
+pub fn create_mfa_token(user_id: Uuid) -> Result<String, ApiError> {
+    encode_claim(&MfaPendingClaim {
+        mfa_user_id: user_id,
+        jti: Uuid::new_v4(),
+        exp: (Utc::now() + Duration::minutes(CONFIG.mfa_token_expiration)).timestamp(),
+    })
+}
+
+/// Decode an MFA pending token, returning the user's id
+pub fn decode_mfa_token(token: &str) -> Result<Uuid, ApiError> {
+    decode_claim::<MfaPendingClaim>(token).map(|claim| claim.mfa_user_id)
+}
+
+/// Decode an MFA pending token and use it up, adding it to the denylist
+/// until it expires
+///
+/// The token is used up atomically, so two requests can't both use it.
+pub async fn use_mfa_token(token: &str, cache: Cache) -> Result<MfaPendingClaim, ApiError> {
+    let claim = decode_claim::<MfaPendingClaim>(token)?;
+    let ttl = max(claim.exp - Utc::now().timestamp(), 1);
+    if !set_nx_ex(cache, &denylist_key(claim.jti), "1", ttl).await? {
+        return Err(ApiError::Unauthorized("Token has been used".into()));
+    }
+    Ok(claim)
+}
+
+fn encode_claim<T: Serialize>(claim: &T) -> Result<String, ApiError> {
+    let mut header = Header::new(KEYS.algorithm);
+    header.kid = KEYS.kid.clone();
+    encode(&header, claim, KEYS.encoding_key())
+        .map_err(|e| ApiError::CannotEncodeJwtToken(e.to_string()))
+}
+
+fn decode_claim<T: DeserializeOwned>(token: &str) -> Result<T, ApiError> {
+    let header = decode_header(token).map_err(|e| ApiError::CannotDecodeJwtToken(e.to_string()))?;
+    let decoding_key = KEYS
+        .decoding_key(header.kid.as_deref())
+        .ok_or_else(|| ApiError::CannotDecodeJwtToken("Unknown key".into()))?;
+    decode::<T>(token, decoding_key, &Validation::new(KEYS.algorithm))
         .map(|data| data.claims)
         .map_err(|e| ApiError::CannotDecodeJwtToken(e.to_string()))
 }
 
+/// Decode a JWT and ensure it hasn't been revoked
+///
+/// Revocation checks are skipped when the cache isn't available, which
+/// add_cache warns about at startup.
+pub async fn verify_jwt(token: &str, cache: Option<Cache>) -> Result<PrivateClaim, ApiError> {
+    verify_jwt_with_claims(token, cache).await
+}
+
+/// Verify a JWT along with its application-defined claims
+pub async fn verify_jwt_with_claims<T: DeserializeOwned>(
+    token: &str,
+    cache: Option<Cache>,
+) -> Result<PrivateClaim<T>, ApiError> {
+    let private_claim = decode_jwt_with_claims(token)?;
+    if let Some(cache) = cache {
+        if is_revoked(cache, &private_claim).await? {
+            return Err(ApiError::Unauthorized("Token has been revoked".into()));
+        }
+    }
+    Ok(private_claim)
+}
+
+/// Add a JWT to the denylist until it expires
+pub async fn revoke_jwt<T>(cache: Cache, private_claim: &PrivateClaim<T>) -> Result<(), ApiError> {
+    let ttl = private_claim.exp - Utc::now().timestamp();
+    if ttl > 0 {
+        set_ex(cache, &denylist_key(private_claim.jti), "1", ttl).await?;
+    }
+    Ok(())
+}
+
+/// Revoke every JWT issued for a session, including impersonations started from it
+/// JWTs can't be refreshed once their session is revoked, so the session
+/// only needs to be remembered until the last one expires
+pub async fn revoke_session(cache: Cache, session_id: Uuid) -> Result<(), ApiError> {
+    let minutes = max(
+        CONFIG.jwt_expiration_minutes,
+        CONFIG.impersonation_expiration,
+    );
+    set_ex(cache, &session_key(session_id), "1", minutes * 60).await?;
+    Ok(())
+}
+
+/// Revoke every JWT issued to a user by bumping their token generation
+pub async fn revoke_all_jwts(cache: Cache, user_id: Uuid) -> Result<i64, ApiError> {
+    incr(cache, &generation_key(user_id)).await
+}
+
+/// Get the user's current token generation
+/// JWTs issued with an older generation are considered revoked
+pub async fn get_generation(cache: Cache, user_id: Uuid) -> Result<i64, ApiError> {
+    let generation = get(cache, &generation_key(user_id)).await?;
+    Ok(generation.parse().unwrap_or(0))
+}
+
+/// Determine if a JWT is in the denylist, from a revoked session or from an
+/// older generation
+/// Impersonation JWTs are also checked against the admin's session and generation
+async fn is_revoked<T>(cache: Cache, private_claim: &PrivateClaim<T>) -> Result<bool, ApiError> {
+    let denied = get(cache.clone(), &denylist_key(private_claim.jti)).await?;
+    if !denied.is_empty() {
+        return Ok(true);
+    }
+    let impersonator_session_id = private_claim
+        .impersonator
+        .as_ref()
+        .and_then(|impersonator| impersonator.session_id);
+    for session_id in private_claim
+        .session_id
+        .iter()
+        .chain(&impersonator_session_id)
+    {
+        let revoked = get(cache.clone(), &session_key(*session_id)).await?;
+        if !revoked.is_empty() {
+            return Ok(true);
+        }
+    }
+    if let Some(impersonator) = &private_claim.impersonator {
+        let generation = get_generation(cache.clone(), impersonator.user_id).await?;
+        if impersonator.generation < generation {
+            return Ok(true);
+        }
+    }
+    let generation = get_generation(cache, private_claim.user_id).await?;
+    Ok(private_claim.generation < generation)
+}
+
+fn denylist_key(jti: Uuid) -> String {
+    format!("jwt:denylist:{}", jti)
+}
+
+fn session_key(session_id: Uuid) -> String {
+    format!("jwt:session:{}", session_id)
+}
+
+fn generation_key(user_id: Uuid) -> String {
+    format!("jwt:generation:{}", user_id)
+}
+
+/// Where a request's JWT is read from and returned to
+///
+/// An app can override auth_token_source with TokenSource data, e.g.
+/// App::new().data(TokenSource::Both)
+pub fn token_source(req: &HttpRequest) -> TokenSource {
+    req.app_data::<Data<TokenSource>>()
+        .map(|source| source.get_ref().clone())
+        .unwrap_or_else(|| CONFIG.auth_token_source.clone())
+}
+
+/// Extract a JWT from a request
+///
+/// Depending on token_source, the token is read from the identity
+/// cookie, an "Authorization: Bearer <jwt>" header, or both.
+/// When both are allowed, the header takes precedence.
+pub fn get_token(req: &HttpRequest) -> Option<String> {
+    let source = token_source(req);
+    let bearer = if source.uses_bearer() {
+        get_bearer_token(req.headers())
+    } else {
+        None
+    };
+    bearer.or_else(|| {
+        if source.uses_cookie() {
+            req.get_identity()
+        } else {
+            None
+        }
+    })
+}
+
+/// Whether get_token would use an "Authorization: Bearer <jwt>" header
+/// Browsers never attach these on their own, unlike the identity cookie.
+pub fn has_bearer_token(req: &HttpRequest) -> bool {
+    token_source(req).uses_bearer() && get_bearer_token(req.headers()).is_some()
+}
+
+/// Extract the token from an "Authorization: Bearer <jwt>" header
+fn get_bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let mut parts = value.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Some(token.trim().to_string())
+        }
+        _ => None,
+    }
+}
+
+/// The header service-to-service requests send their API key in
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Extract an API key from an "X-Api-Key: <key>" header
+pub fn get_api_key<T>(req: &T) -> Option<String>
+where
+    T: HttpMessage,
+{
+    let value = req.headers().get(API_KEY_HEADER)?.to_str().ok()?;
+    Some(value.trim().to_string())
+}
+
 /// Encrypt a password
 ///
-/// Uses the argon2i algorithm.
-/// auth_salt is environment-configured.
+/// Uses the argon2i algorithm with a random salt per password.
+/// The result is a PHC string, so the algorithm, cost parameters
+/// and salt are stored alongside the hash.
 pub fn hash(password: &str) -> String {
-    argon2i_simple(&password, &CONFIG.auth_salt)
-        .iter()
-        .map(|b| format!("{:02x}", b))
-        .collect()
+    let salt = rand::thread_rng().gen::<[u8; 16]>();
+    hash_encoded(password.as_bytes(), &salt, &hash_config())
+        .expect("Could not hash password, check the AUTH_HASH_* configuration")
+}
+
+/// Verify a password against a stored hash
+///
+/// Hashes created before per-user salts (hex encoded, global auth_salt)
+/// are still verified so existing users can log in and be rehashed.
+pub fn verify(password: &str, hashed: &str) -> bool {
+    if is_legacy_hash(hashed) {
+        return legacy_hash(password) == hashed;
+    }
+    verify_encoded(hashed, password.as_bytes()).unwrap_or(false)
+}
+
+/// Determine if a stored hash was created with outdated parameters
+///
+/// Compares the PHC prefix (algorithm, version and costs) against the
+/// current configuration.
+pub fn needs_rehash(hashed: &str) -> bool {
+    !hashed.starts_with(&hash_prefix(&hash_config()))
+}
+
+/// Argon2 settings for new hashes, environment-configured
+fn hash_config<'a>() -> HashConfig<'a> {
+    HashConfig {
+        variant: Variant::Argon2i,
+        version: Version::Version13,
+        mem_cost: CONFIG.auth_hash_memory_cost,
+        time_cost: CONFIG.auth_hash_time_cost,
+        lanes: CONFIG.auth_hash_lanes,
+        ..HashConfig::default()
+    }
+}
+
+/// The PHC prefix for a given configuration, e.g. $argon2i$v=19$m=4096,t=3,p=1$
+fn hash_prefix(config: &HashConfig) -> String {
+    format!(
+        "${}$v={}$m={},t={},p={}$",
+        config.variant, config.version, config.mem_cost, config.time_cost, config.lanes
+    )
+}
+
+/// Legacy hashes are hex strings without the PHC $ delimiters
+fn is_legacy_hash(hashed: &str) -> bool {
+    !hashed.starts_with('$')
+}
+
+/// Legacy hashing using the global auth_salt
+fn legacy_hash(password: &str) -> String {
+    to_hex(&argon2i_simple(&password, &CONFIG.auth_salt))
+}
+
+/// Generate a random, url-safe token (e.g. refresh tokens)
+pub fn generate_token() -> String {
+    to_hex(&rand::thread_rng().gen::<[u8; 32]>())
+}
+
+/// Hash a high-entropy token for storage and lookup
+///
+/// Unlike passwords, random tokens don't need a slow, salted hash.
+pub fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// Hex encode a byte slice
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 /// Gets the identidy service for injection into an Actix app
@@ -65,6 +473,8 @@ pub fn get_identity_service() -> IdentityService<CookieIdentityPolicy> {
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::tests::helpers::tests::get_data_cache;
+    use actix_web::test::TestRequest;
     static EMAIL: &str = "test@test.com";
 
     #[test]
@@ -75,11 +485,122 @@ pub mod tests {
     }
 
     #[test]
-    fn it_matches_2_hashed_passwords() {
+    fn it_salts_each_hashed_password() {
         let password = "password";
         let hashed = hash(password);
         let hashed_again = hash(password);
-        assert_eq!(hashed, hashed_again);
+        assert_ne!(hashed, hashed_again);
+    }
+
+    #[test]
+    fn it_verifies_a_password() {
+        let hashed = hash("password");
+        assert!(verify("password", &hashed));
+        assert!(!verify("wrong-password", &hashed));
+    }
+
+    #[test]
+    fn it_verifies_a_legacy_password() {
+        let hashed = legacy_hash("password");
+        assert!(verify("password", &hashed));
+        assert!(!verify("wrong-password", &hashed));
+    }
+
+    #[test]
+    fn it_detects_outdated_hashes() {
+        assert!(!needs_rehash(&hash("password")));
+        assert!(needs_rehash(&legacy_hash("password")));
+        assert!(needs_rehash("$argon2i$v=19$m=8,t=1,p=1$c2FsdHNhbHQ$aGFzaA"));
+    }
+
+    #[test]
+    fn it_gets_a_bearer_token() {
+        let request = TestRequest::get()
+            .header(AUTHORIZATION, "Bearer abc.def.ghi")
+            .to_http_request();
+        assert_eq!(
+            get_bearer_token(request.headers()),
+            Some("abc.def.ghi".into())
+        );
+    }
+
+    #[test]
+    fn it_ignores_other_authorization_schemes() {
+        let request = TestRequest::get()
+            .header(AUTHORIZATION, "Basic dXNlcjpwYXNz")
+            .to_http_request();
+        assert_eq!(get_bearer_token(request.headers()), None);
+    }
+
+    #[test]
+    fn it_uses_the_apps_token_source() {
+        let request = TestRequest::get()
+            .header(AUTHORIZATION, "Bearer abc.def.ghi")
+            .data(TokenSource::Cookie)
+            .to_http_request();
+        assert_eq!(token_source(&request), TokenSource::Cookie);
+        assert_eq!(get_token(&request), None);
+        assert!(!has_bearer_token(&request));
+
+        let request = TestRequest::get()
+            .header(AUTHORIZATION, "Bearer abc.def.ghi")
+            .data(TokenSource::Bearer)
+            .to_http_request();
+        assert_eq!(get_token(&request), Some("abc.def.ghi".into()));
+    }
+
+    #[test]
+    fn it_gets_an_api_key() {
+        let request = TestRequest::get()
+            .header(API_KEY_HEADER, "rak_0123456789abcdef")
+            .to_http_request();
+        assert_eq!(get_api_key(&request), Some("rak_0123456789abcdef".into()));
+        assert_eq!(get_api_key(&TestRequest::get().to_http_request()), None);
+    }
+
+    #[test]
+    fn it_generates_unique_tokens() {
+        let token = generate_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, generate_token());
+    }
+
+    #[test]
+    fn it_hashes_a_token() {
+        let token = generate_token();
+        assert_ne!(hash_token(&token), token);
+        assert_eq!(hash_token(&token), hash_token(&token));
+    }
+
+    #[test]
+    fn it_creates_a_unique_jti() {
+        let private_claim = PrivateClaim::new(Uuid::new_v4(), EMAIL.into());
+        let private_claim_again = PrivateClaim::new(Uuid::new_v4(), EMAIL.into());
+        assert_ne!(private_claim.jti, private_claim_again.jti);
+    }
+
+    #[actix_rt::test]
+    async fn it_revokes_a_jwt() {
+        let cache = get_data_cache();
+        let private_claim = PrivateClaim::new(Uuid::new_v4(), EMAIL.into());
+        let jwt = create_jwt(private_claim.clone()).unwrap();
+        assert!(verify_jwt(&jwt, Some(cache.clone())).await.is_ok());
+        revoke_jwt(cache.clone(), &private_claim).await.unwrap();
+        assert!(verify_jwt(&jwt, Some(cache)).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_revokes_all_jwts_for_a_user() {
+        let cache = get_data_cache();
+        let user_id = Uuid::new_v4();
+        let private_claim = PrivateClaim::new(user_id, EMAIL.into());
+        let jwt = create_jwt(private_claim).unwrap();
+        let generation = revoke_all_jwts(cache.clone(), user_id).await.unwrap();
+        assert!(verify_jwt(&jwt, Some(cache.clone())).await.is_err());
+
+        let private_claim = PrivateClaim::new(user_id, EMAIL.into()).with_generation(generation);
+        let jwt = create_jwt(private_claim).unwrap();
+        assert!(verify_jwt(&jwt, Some(cache)).await.is_ok());
     }
 
     #[test]
@@ -89,6 +610,34 @@ pub mod tests {
         assert!(jwt.is_ok());
     }
 
+    #[test]
+    fn it_decodes_an_mfa_token() {
+        let user_id = Uuid::new_v4();
+        let mfa_token = create_mfa_token(user_id).unwrap();
+        assert_eq!(decode_mfa_token(&mfa_token).unwrap(), user_id);
+    }
+
+    #[actix_rt::test]
+    async fn it_uses_up_an_mfa_token() {
+        let cache = get_data_cache();
+        let user_id = Uuid::new_v4();
+        let mfa_token = create_mfa_token(user_id).unwrap();
+        let claim = use_mfa_token(&mfa_token, cache.clone()).await.unwrap();
+        assert_eq!(claim.mfa_user_id, user_id);
+        assert_eq!(
+            use_mfa_token(&mfa_token, cache).await.unwrap_err(),
+            ApiError::Unauthorized("Token has been used".into())
+        );
+    }
+
+    #[test]
+    fn it_doesnt_decode_an_mfa_token_as_a_jwt() {
+        let mfa_token = create_mfa_token(Uuid::new_v4()).unwrap();
+        assert!(decode_jwt(&mfa_token).is_err());
+        let jwt = create_jwt(PrivateClaim::new(Uuid::new_v4(), EMAIL.into())).unwrap();
+        assert!(decode_mfa_token(&jwt).is_err());
+    }
+
     #[test]
     fn it_decodes_a_jwt() {
         let private_claim = PrivateClaim::new(Uuid::new_v4(), EMAIL.into());
@@ -96,4 +645,62 @@ pub mod tests {
         let decoded = decode_jwt(&jwt).unwrap();
         assert_eq!(private_claim, decoded);
     }
+
+    #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+    pub struct TenantClaims {
+        pub tenant: String,
+    }
+
+    #[test]
+    fn it_decodes_a_jwt_with_custom_claims() {
+        let private_claim =
+            PrivateClaim::new(Uuid::new_v4(), EMAIL.into()).with_claims(TenantClaims {
+                tenant: "acme".into(),
+            });
+        let jwt = create_jwt(private_claim.clone()).unwrap();
+        let decoded = decode_jwt_with_claims::<TenantClaims>(&jwt).unwrap();
+        assert_eq!(private_claim, decoded);
+
+        // Tokens can still be decoded without knowing the custom claims
+        assert_eq!(decode_jwt(&jwt).unwrap().user_id, private_claim.user_id);
+    }
+
+    #[actix_rt::test]
+    async fn it_revokes_a_session() {
+        let cache = get_data_cache();
+        let session_id = Uuid::new_v4();
+        let private_claim =
+            PrivateClaim::new(Uuid::new_v4(), EMAIL.into()).with_session(session_id);
+        let jwt = create_jwt(private_claim).unwrap();
+        assert!(verify_jwt(&jwt, Some(cache.clone())).await.is_ok());
+        revoke_session(cache.clone(), session_id).await.unwrap();
+        assert!(verify_jwt(&jwt, Some(cache)).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_revokes_an_impersonation_with_the_admins_session() {
+        let cache = get_data_cache();
+        let session_id = Uuid::new_v4();
+        let private_claim = PrivateClaim::new(Uuid::new_v4(), EMAIL.into()).with_impersonator(
+            Uuid::new_v4(),
+            Some(session_id),
+            0,
+        );
+        let jwt = create_jwt(private_claim).unwrap();
+        assert!(verify_jwt(&jwt, Some(cache.clone())).await.is_ok());
+        revoke_session(cache.clone(), session_id).await.unwrap();
+        assert!(verify_jwt(&jwt, Some(cache)).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_revokes_an_impersonation_with_all_of_the_admins_jwts() {
+        let cache = get_data_cache();
+        let admin_id = Uuid::new_v4();
+        let private_claim =
+            PrivateClaim::new(Uuid::new_v4(), EMAIL.into()).with_impersonator(admin_id, None, 0);
+        let jwt = create_jwt(private_claim).unwrap();
+        assert!(verify_jwt(&jwt, Some(cache.clone())).await.is_ok());
+        revoke_all_jwts(cache.clone(), admin_id).await.unwrap();
+        assert!(verify_jwt(&jwt, Some(cache)).await.is_err());
+    }
 }
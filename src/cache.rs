@@ -5,6 +5,7 @@ use crate::errors::ApiError;
 use actix::prelude::*;
 use actix_redis::{Command, RedisActor};
 use actix_web::web::{Data, ServiceConfig};
+use log::warn;
 use redis_async::resp::{FromResp, RespValue};
 
 pub type Cache = Data<Addr<RedisActor>>;
@@ -15,12 +16,62 @@ pub async fn get<'a>(redis: Cache, key: &'a str) -> Result<String, ApiError> {
     send(redis, command).await
 }
 
+/// Retrieve an entry in redis and delete it, so only one caller gets it
+/// Requires Redis 6.2 or later
+pub async fn get_del<'a>(redis: Cache, key: &'a str) -> Result<String, ApiError> {
+    let command = resp_array!["GETDEL", key];
+    send(redis, command).await
+}
+
 /// Insert or update an entry in redis
 pub async fn set<'a>(redis: Cache, key: &'a str, value: &'a str) -> Result<String, ApiError> {
     let command = resp_array!["SET", key, value];
     send(redis, command).await
 }
 
+/// Insert or update an entry in redis that expires after ttl seconds
+pub async fn set_ex<'a>(
+    redis: Cache,
+    key: &'a str,
+    value: &'a str,
+    ttl: i64,
+) -> Result<String, ApiError> {
+    let command = resp_array!["SET", key, value, "EX", ttl.to_string()];
+    send(redis, command).await
+}
+
+/// Insert an entry in redis that expires after ttl seconds, unless it exists
+/// Returns whether it was inserted
+pub async fn set_nx_ex<'a>(
+    redis: Cache,
+    key: &'a str,
+    value: &'a str,
+    ttl: i64,
+) -> Result<bool, ApiError> {
+    let command = resp_array!["SET", key, value, "EX", ttl.to_string(), "NX"];
+    let response: String = send(redis, command).await?;
+    Ok(!response.is_empty())
+}
+
+/// Increment a numeric entry in redis, returning the new value
+pub async fn incr<'a>(redis: Cache, key: &'a str) -> Result<i64, ApiError> {
+    let command = resp_array!["INCR", key];
+    send(redis, command).await
+}
+
+/// Set an existing entry in redis to expire after ttl seconds
+pub async fn expire<'a>(redis: Cache, key: &'a str, ttl: i64) -> Result<i64, ApiError> {
+    let command = resp_array!["EXPIRE", key, ttl.to_string()];
+    send(redis, command).await
+}
+
+/// Get the number of seconds until an entry in redis expires
+/// Negative if the entry doesn't exist or never expires
+pub async fn ttl<'a>(redis: Cache, key: &'a str) -> Result<i64, ApiError> {
+    let command = resp_array!["TTL", key];
+    send(redis, command).await
+}
+
 /// Delete an entry in redis
 pub async fn delete<'a>(redis: Cache, key: &'a str) -> Result<String, ApiError> {
     let command = resp_array!["DEL", key];
@@ -28,12 +79,15 @@ pub async fn delete<'a>(redis: Cache, key: &'a str) -> Result<String, ApiError>
 }
 
 /// Send a command to the redis actor
-async fn send<'a>(redis: Cache, command: RespValue) -> Result<String, ApiError> {
+async fn send<'a, T>(redis: Cache, command: RespValue) -> Result<T, ApiError>
+where
+    T: FromResp + Default,
+{
     let error_message = format!("Could not send {:?} command to Redis", command);
     let error = ApiError::CacheError(error_message.into());
     let response = redis.send(Command(command)).await.map_err(|_| error)?;
     match response {
-        Ok(message) => Ok::<String, _>(FromResp::from_resp(message).unwrap_or("".into())),
+        Ok(message) => Ok(FromResp::from_resp(message).unwrap_or_default()),
         Err(message) => Err(ApiError::CacheError(format!("{:?}", message))),
     }
 }
@@ -44,6 +98,8 @@ pub fn add_cache(cfg: &mut ServiceConfig) {
         // Start a new supervisor with redis actor
         let cache = RedisActor::start(&CONFIG.redis_url);
         cfg.data(cache);
+    } else {
+        warn!("REDIS_URL isn't set, so revoked JWTs are accepted until they expire");
     }
 }
 
@@ -64,6 +120,53 @@ mod tests {
         assert_eq!(value, "123");
     }
 
+    #[actix_rt::test]
+    async fn it_sets_an_expiring_entry_in_application_cache() {
+        let cache = get_cache();
+        set_ex(cache.clone(), "testing-expiring", "123", 60)
+            .await
+            .unwrap();
+        let value = get(cache, "testing-expiring").await.unwrap();
+        assert_eq!(value, "123");
+    }
+
+    #[actix_rt::test]
+    async fn it_only_sets_a_missing_entry_in_application_cache() {
+        let cache = get_cache();
+        let key = format!("testing-nx-{}", uuid::Uuid::new_v4());
+        assert!(set_nx_ex(cache.clone(), &key, "123", 60).await.unwrap());
+        assert!(!set_nx_ex(cache.clone(), &key, "456", 60).await.unwrap());
+        assert_eq!(get(cache, &key).await.unwrap(), "123");
+    }
+
+    #[actix_rt::test]
+    async fn it_reads_and_removes_an_entry_in_application_cache() {
+        let cache = get_cache();
+        set(cache.clone(), "testing-get-del", "123").await.unwrap();
+        let value = get_del(cache.clone(), "testing-get-del").await.unwrap();
+        assert_eq!(value, "123");
+        let value = get_del(cache, "testing-get-del").await.unwrap();
+        assert_eq!(value, "");
+    }
+
+    #[actix_rt::test]
+    async fn it_increments_an_entry_in_application_cache() {
+        let cache = get_cache();
+        let value = incr(cache.clone(), "testing-incr").await.unwrap();
+        let incremented = incr(cache, "testing-incr").await.unwrap();
+        assert_eq!(incremented, value + 1);
+    }
+
+    #[actix_rt::test]
+    async fn it_expires_an_entry_in_application_cache() {
+        let cache = get_cache();
+        set(cache.clone(), "testing-expire", "123").await.unwrap();
+        assert_eq!(ttl(cache.clone(), "testing-expire").await.unwrap(), -1);
+        expire(cache.clone(), "testing-expire", 60).await.unwrap();
+        let remaining = ttl(cache, "testing-expire").await.unwrap();
+        assert!(remaining > 0 && remaining <= 60);
+    }
+
     #[actix_rt::test]
     async fn it_removes_an_entry_in_application_cache() {
         let cache = get_cache();
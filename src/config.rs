@@ -8,18 +8,57 @@
 //! This file throws the Config struct into a CONFIG lazy_static to avoid
 //! multiple processing.
 
+use crate::auth::TokenSource;
 use crate::database::DatabaseConnection;
+use crate::mailer::MailerKind;
 use dotenv::dotenv;
+use jsonwebtoken::Algorithm;
 use serde::Deserialize;
 
 #[derive(Clone, Deserialize, Debug)]
 pub struct Config {
+    pub auth_hash_lanes: u32,
+    pub auth_hash_memory_cost: u32,
+    pub auth_hash_time_cost: u32,
     pub auth_salt: String,
+    pub auth_token_source: TokenSource,
+    pub cursor_key: String,
     pub database: DatabaseConnection,
     pub database_url: String,
-    pub jwt_expiration: i64,
+    pub email_verification_expiration: i64,
+    pub email_verification_required: bool,
+    pub email_verification_url: String,
+    pub impersonation_expiration: i64,
+    pub jwt_algorithm: Algorithm,
+    pub jwt_expiration_minutes: i64,
     pub jwt_key: String,
+    pub jwt_kid: Option<String>,
+    pub jwt_private_key: Option<String>,
+    #[serde(default)]
+    pub jwt_public_keys: Vec<String>,
+    pub login_backoff_base: i64,
+    pub login_backoff_threshold: i64,
+    pub login_ip_lockout_threshold: i64,
+    pub login_lockout_duration: i64,
+    pub login_lockout_threshold: i64,
+    pub mailer: MailerKind,
+    pub mailer_file: String,
+    pub mailer_from: String,
+    pub max_per_page: i64,
+    pub mfa_issuer: String,
+    #[serde(default)]
+    pub mfa_required_roles: Vec<String>,
+    pub mfa_token_expiration: i64,
+    #[serde(default)]
+    pub oidc_providers: Vec<String>,
+    pub oidc_redirect_url: String,
+    pub password_breached_list: Option<String>,
+    pub password_min_character_classes: usize,
+    pub password_min_length: usize,
+    pub password_reset_expiration: i64,
+    pub password_reset_url: String,
     pub redis_url: String,
+    pub refresh_token_expiration: i64,
     pub rust_backtrace: u8,
     pub rust_log: String,
     pub server: String,
@@ -27,6 +66,9 @@ pub struct Config {
     pub session_name: String,
     pub session_secure: bool,
     pub session_timeout: i64,
+    pub smtp_host: Option<String>,
+    pub smtp_password: Option<String>,
+    pub smtp_username: Option<String>,
 }
 
 // Throw the Config struct into a CONFIG lazy_static to avoid multiple processing
@@ -38,6 +38,16 @@ pub type PoolType = PostgresPool;
 #[cfg(feature = "sqlite")]
 pub type PoolType = SqlitePool;
 
+/// The Diesel backend of PoolType, for naming boxed queries
+#[cfg(any(feature = "cockroach", feature = "postgres"))]
+pub type Backend = diesel::pg::Pg;
+
+#[cfg(feature = "mysql")]
+pub type Backend = diesel::mysql::Mysql;
+
+#[cfg(feature = "sqlite")]
+pub type Backend = diesel::sqlite::Sqlite;
+
 #[derive(Clone)]
 pub enum InferPool {
     Cockroach(CockroachPool),
@@ -2,7 +2,7 @@
 
 use actix_web::{
     error::{BlockingError, ResponseError},
-    http::StatusCode,
+    http::{header::RETRY_AFTER, StatusCode},
     HttpResponse,
 };
 use derive_more::Display;
@@ -20,10 +20,15 @@ pub enum ApiError {
     CacheError(String),
     CannotDecodeJwtToken(String),
     CannotEncodeJwtToken(String),
+    EmailNotVerified(String),
+    Forbidden(String),
     InternalServerError(String),
     NotFound(String),
     ParseError(String),
     PoolError(String),
+    /// The message, and the number of seconds to wait before retrying
+    #[display(fmt = "{}", _0)]
+    TooManyRequests(String, i64),
     #[display(fmt = "")]
     ValidationError(Vec<String>),
     Unauthorized(String),
@@ -42,9 +47,18 @@ impl ResponseError for ApiError {
             ApiError::BadRequest(error) => {
                 HttpResponse::BadRequest().json::<ErrorResponse>(error.into())
             }
+            ApiError::EmailNotVerified(error) => {
+                HttpResponse::Forbidden().json::<ErrorResponse>(error.into())
+            }
+            ApiError::Forbidden(error) => {
+                HttpResponse::Forbidden().json::<ErrorResponse>(error.into())
+            }
             ApiError::NotFound(message) => {
                 HttpResponse::NotFound().json::<ErrorResponse>(message.into())
             }
+            ApiError::TooManyRequests(error, retry_after) => HttpResponse::TooManyRequests()
+                .header(RETRY_AFTER, retry_after.to_string())
+                .json::<ErrorResponse>(error.into()),
             ApiError::ValidationError(errors) => {
                 HttpResponse::UnprocessableEntity().json::<ErrorResponse>(errors.to_vec().into())
             }
@@ -1,32 +1,159 @@
 //! Actix handler extractors
 
-use crate::auth::{decode_jwt, PrivateClaim};
+use crate::auth::{get_token, verify_jwt, verify_jwt_with_claims, PrivateClaim};
+use crate::cache::Cache;
+use crate::errors::ApiError;
 use crate::models::user::AuthUser;
-use actix_identity::RequestIdentity;
-use actix_web::{
-    dev::Payload,
-    web::{HttpRequest, HttpResponse},
-    Error, FromRequest,
-};
-use futures::future::{err, ok, Ready};
+use actix_web::{dev::Payload, web::HttpRequest, Error, FromRequest};
+use futures::future::{ok, FutureExt, LocalBoxFuture};
+use serde::de::DeserializeOwned;
+use std::ops::Deref;
 
 /// Extractor for pulling the identity out of a request.
 ///
 /// Simply add "user: AuthUser" to a handler to invoke this.
+/// Add "user: Option<AuthUser>" instead on routes where logging in is optional.
+///
+/// Requests that passed through the AUTH middleware use the user it stored,
+/// including the principal of an API key, instead of decoding the JWT again.
 impl FromRequest for AuthUser {
     type Error = Error;
     type Config = ();
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
     fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
-        let identity = RequestIdentity::get_identity(req);
-        if let Some(identity) = identity {
-            let private_claim: PrivateClaim = decode_jwt(&identity).unwrap();
-            return ok(AuthUser {
-                id: private_claim.user_id.to_string(),
-                email: private_claim.email,
-            });
+        if let Some(user) = req.extensions().get::<AuthUser>() {
+            return ok(user.clone()).boxed_local();
+        }
+
+        let identity = get_token(req);
+        let cache = req.app_data::<Cache>().cloned();
+
+        async move {
+            let identity =
+                identity.ok_or_else(|| ApiError::Unauthorized("Not logged in".into()))?;
+            let private_claim = verify_jwt(&identity, cache).await.map_err(unauthorized)?;
+            Ok::<_, Error>(private_claim.into())
+        }
+        .boxed_local()
+    }
+}
+
+/// Extractor for the claims of the request's JWT, including any
+/// application-defined claims, see PrivateClaim::with_claims.
+///
+/// Add "claims: Claims<MyClaims>" to a handler to invoke this.
+///
+/// API keys don't have claims, so requests authenticated with one are
+/// rejected.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Claims<T>(pub PrivateClaim<T>);
+
+impl<T> Claims<T> {
+    pub fn into_inner(self) -> PrivateClaim<T> {
+        self.0
+    }
+}
+
+impl<T> Deref for Claims<T> {
+    type Target = PrivateClaim<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + 'static> FromRequest for Claims<T> {
+    type Error = Error;
+    type Config = ();
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let identity = get_token(req);
+        let cache = req.app_data::<Cache>().cloned();
+
+        async move {
+            let identity =
+                identity.ok_or_else(|| ApiError::Unauthorized("Not logged in".into()))?;
+            let private_claim = verify_jwt_with_claims::<T>(&identity, cache)
+                .await
+                .map_err(unauthorized)?;
+            Ok::<_, Error>(Claims(private_claim))
+        }
+        .boxed_local()
+    }
+}
+
+/// Invalid and expired tokens are a 401, not a server error
+fn unauthorized(error: ApiError) -> ApiError {
+    match error {
+        ApiError::CannotDecodeJwtToken(_) => ApiError::Unauthorized("Invalid token".into()),
+        error => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::create_jwt;
+    use crate::auth::tests::TenantClaims;
+    use actix_web::{http::header::AUTHORIZATION, http::StatusCode, test};
+    use uuid::Uuid;
+
+    async fn extract<T: FromRequest>(token: Option<&str>) -> Result<T, Error> {
+        let mut request = test::TestRequest::default();
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
         }
-        err(HttpResponse::Unauthorized().into())
+        let (request, mut payload) = request.to_http_parts();
+        T::from_request(&request, &mut payload)
+            .await
+            .map_err(Into::into)
+    }
+
+    fn get_jwt() -> String {
+        let private_claim = PrivateClaim::new(Uuid::new_v4(), "extractor@nothing.org".into())
+            .with_claims(TenantClaims {
+                tenant: "acme".into(),
+            });
+        create_jwt(private_claim).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn it_extracts_an_auth_user() {
+        let user = extract::<AuthUser>(Some(&get_jwt())).await.unwrap();
+        assert_eq!(user.email, "extractor@nothing.org");
+    }
+
+    #[actix_rt::test]
+    async fn it_rejects_an_invalid_token_with_a_401() {
+        let error = extract::<AuthUser>(Some("not-a-jwt")).await.unwrap_err();
+        assert_eq!(
+            error.as_response_error().error_response().status(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_extracts_an_optional_auth_user() {
+        let user = extract::<Option<AuthUser>>(None).await.unwrap();
+        assert!(user.is_none());
+
+        let user = extract::<Option<AuthUser>>(Some(&get_jwt())).await.unwrap();
+        assert!(user.is_some());
+    }
+
+    #[actix_rt::test]
+    async fn it_extracts_custom_claims() {
+        let claims = extract::<Claims<TenantClaims>>(Some(&get_jwt()))
+            .await
+            .unwrap();
+        assert_eq!(claims.claims.tenant, "acme");
+    }
+
+    #[test]
+    fn it_maps_decoding_errors_to_unauthorized() {
+        let error = unauthorized(ApiError::CannotDecodeJwtToken("InvalidToken".into()));
+        assert_eq!(error, ApiError::Unauthorized("Invalid token".into()));
     }
 }
@@ -0,0 +1,271 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::{respond_json, respond_ok};
+use crate::models::api_key::{create, delete, find, find_all, update, ApiKey};
+use crate::models::user::AuthUser;
+use crate::validate::validate;
+use actix_web::web::{block, Data, HttpResponse, Json, Path};
+use chrono::{NaiveDateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct ApiKeyRequest {
+    #[validate(length(
+        min = 1,
+        max = 100,
+        message = "name is required and must be at most 100 characters"
+    ))]
+    pub name: String,
+
+    #[serde(default)]
+    pub scopes: Vec<String>,
+
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ApiKeyResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ApiKeysResponse(pub Vec<ApiKeyResponse>);
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct CreateApiKeyResponse {
+    pub key: String,
+
+    #[serde(flatten)]
+    pub api_key: ApiKeyResponse,
+}
+
+/// Get all of the logged in user's API keys
+pub async fn get_api_keys(
+    user: AuthUser,
+    pool: Data<PoolType>,
+) -> Result<Json<ApiKeysResponse>, ApiError> {
+    let user_id = Uuid::parse_str(&user.id)?;
+    let api_keys = block(move || find_all(&pool, user_id)).await?;
+    respond_json(api_keys.into())
+}
+
+/// Get one of the logged in user's API keys
+pub async fn get_api_key(
+    user: AuthUser,
+    api_key_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<Json<ApiKeyResponse>, ApiError> {
+    let user_id = Uuid::parse_str(&user.id)?;
+    let api_key = block(move || find(&pool, user_id, *api_key_id)).await?;
+    respond_json(api_key.into())
+}
+
+/// Create an API key for the logged in user
+/// The key is only returned this once
+pub async fn create_api_key(
+    user: AuthUser,
+    pool: Data<PoolType>,
+    params: Json<ApiKeyRequest>,
+) -> Result<Json<CreateApiKeyResponse>, ApiError> {
+    validate(&params)?;
+    check_grants(&user, &params)?;
+
+    let user_id = Uuid::parse_str(&user.id)?;
+    let (api_key, key) = block(move || {
+        create(
+            &pool,
+            user_id,
+            &params.name,
+            &params.scopes,
+            params.expires_at,
+        )
+    })
+    .await?;
+    respond_json(CreateApiKeyResponse {
+        key,
+        api_key: api_key.into(),
+    })
+}
+
+/// Rename one of the logged in user's API keys, or change its scopes or expiry
+pub async fn update_api_key(
+    user: AuthUser,
+    api_key_id: Path<Uuid>,
+    pool: Data<PoolType>,
+    params: Json<ApiKeyRequest>,
+) -> Result<Json<ApiKeyResponse>, ApiError> {
+    validate(&params)?;
+    check_grants(&user, &params)?;
+
+    let user_id = Uuid::parse_str(&user.id)?;
+    let api_key = block(move || {
+        update(
+            &pool,
+            user_id,
+            *api_key_id,
+            &params.name,
+            &params.scopes,
+            params.expires_at,
+        )
+    })
+    .await?;
+    respond_json(api_key.into())
+}
+
+/// Delete one of the logged in user's API keys
+pub async fn delete_api_key(
+    user: AuthUser,
+    api_key_id: Path<Uuid>,
+    pool: Data<PoolType>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = Uuid::parse_str(&user.id)?;
+    block(move || delete(&pool, user_id, *api_key_id)).await?;
+    respond_ok()
+}
+
+/// Users can only grant their own permissions to a key, and only until a
+/// time in the future
+///
+/// Admins impersonating a user can't grant anything, so they can't leave a
+/// key behind that outlives the impersonation.
+fn check_grants(user: &AuthUser, params: &ApiKeyRequest) -> Result<(), ApiError> {
+    if user.impersonator.is_some() {
+        return Err(ApiError::Forbidden(
+            "Cannot manage API keys while impersonating".into(),
+        ));
+    }
+
+    if let Some(scope) = params
+        .scopes
+        .iter()
+        .find(|scope| !user.has_permission(scope))
+    {
+        return Err(ApiError::Forbidden(format!("Cannot grant scope {}", scope)));
+    }
+
+    if let Some(expires_at) = params.expires_at {
+        if expires_at <= Utc::now().naive_utc() {
+            return Err(ApiError::BadRequest(
+                "expires_at must be in the future".into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+impl From<ApiKey> for ApiKeyResponse {
+    fn from(api_key: ApiKey) -> Self {
+        ApiKeyResponse {
+            id: Uuid::parse_str(&api_key.id).unwrap(),
+            scopes: api_key.scopes(),
+            name: api_key.name,
+            prefix: api_key.prefix,
+            expires_at: api_key.expires_at,
+            last_used_at: api_key.last_used_at,
+            created_at: api_key.created_at,
+        }
+    }
+}
+
+impl From<Vec<ApiKey>> for ApiKeysResponse {
+    fn from(api_keys: Vec<ApiKey>) -> Self {
+        ApiKeysResponse(api_keys.into_iter().map(|api_key| api_key.into()).collect())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::{get_auth_user, get_data_pool};
+    use chrono::Duration;
+
+    fn api_key_request(scopes: Vec<String>) -> ApiKeyRequest {
+        ApiKeyRequest {
+            name: "batch".into(),
+            scopes,
+            expires_at: None,
+        }
+    }
+
+    #[actix_rt::test]
+    async fn it_creates_an_api_key() {
+        let user = get_auth_user("api-key", vec![], vec!["user:delete".into()]);
+        let params = api_key_request(vec!["user:delete".into()]);
+        let response = create_api_key(user.clone(), get_data_pool(), Json(params))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.key.starts_with(&response.api_key.prefix));
+        assert_eq!(response.api_key.scopes, vec!["user:delete".to_string()]);
+
+        let api_keys = get_api_keys(user, get_data_pool())
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(api_keys.0.len(), 1);
+        assert_eq!(api_keys.0[0].id, response.api_key.id);
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_create_an_api_key_with_an_ungranted_scope() {
+        let user = get_auth_user("api-key", vec![], vec![]);
+        let params = api_key_request(vec!["user:delete".into()]);
+        let response = create_api_key(user, get_data_pool(), Json(params)).await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::Forbidden("Cannot grant scope user:delete".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_create_an_expired_api_key() {
+        let user = get_auth_user("api-key", vec![], vec![]);
+        let mut params = api_key_request(vec![]);
+        params.expires_at = Some((Utc::now() - Duration::days(1)).naive_utc());
+        let response = create_api_key(user, get_data_pool(), Json(params)).await;
+        assert!(response.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_updates_an_api_key() {
+        let user = get_auth_user("api-key", vec![], vec![]);
+        let created = create_api_key(user.clone(), get_data_pool(), Json(api_key_request(vec![])))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut params = api_key_request(vec![]);
+        params.name = "nightly".into();
+        let response = update_api_key(
+            user,
+            created.api_key.id.into(),
+            get_data_pool(),
+            Json(params),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.into_inner().name, "nightly");
+    }
+
+    #[actix_rt::test]
+    async fn it_deletes_an_api_key() {
+        let user = get_auth_user("api-key", vec![], vec![]);
+        let created = create_api_key(user.clone(), get_data_pool(), Json(api_key_request(vec![])))
+            .await
+            .unwrap()
+            .into_inner();
+        let id = created.api_key.id;
+        delete_api_key(user.clone(), id.into(), get_data_pool())
+            .await
+            .unwrap();
+        let response = get_api_key(user, id.into(), get_data_pool()).await;
+        assert!(response.is_err());
+    }
+}
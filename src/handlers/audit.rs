@@ -0,0 +1,113 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::get_base;
+use crate::models::audit_event::{get_all, AuditEvent, AuditEventFilter};
+use crate::pagination::{validate_pagination, PaginationRequest, PaginationResponse};
+use actix_web::web::{block, Data, HttpRequest, Query};
+use chrono::NaiveDateTime;
+use rayon::prelude::*;
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct AuditEventResponse {
+    pub id: Uuid,
+    pub actor_id: Option<String>,
+    pub impersonator_id: Option<String>,
+    pub action: String,
+    pub target_id: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub metadata: Value,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct AuditEventsResponse(pub Vec<AuditEventResponse>);
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditEventsRequest {
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+    pub actor_id: Option<String>,
+    pub action: Option<String>,
+    pub target_id: Option<String>,
+}
+
+/// Get the audit events, most recent first
+/// Filter by actor_id, action and/or target_id
+pub async fn get_audit_events(
+    req: HttpRequest,
+    params: Query<AuditEventsRequest>,
+    pool: Data<PoolType>,
+) -> Result<PaginationResponse<AuditEventsResponse>, ApiError> {
+    let base = get_base(req);
+    let params = params.into_inner();
+    validate_pagination(params.page, params.per_page)?;
+    let pagination = PaginationRequest {
+        page: params.page,
+        per_page: params.per_page,
+    };
+    let filter = AuditEventFilter {
+        actor_id: params.actor_id,
+        action: params.action,
+        target_id: params.target_id,
+    };
+    let audit_events = block(move || get_all(&pool, &filter, pagination, base)).await?;
+    Ok(audit_events)
+}
+
+impl From<AuditEvent> for AuditEventResponse {
+    fn from(audit_event: AuditEvent) -> Self {
+        AuditEventResponse {
+            id: Uuid::parse_str(&audit_event.id).unwrap(),
+            actor_id: audit_event.actor_id,
+            impersonator_id: audit_event.impersonator_id,
+            action: audit_event.action,
+            target_id: audit_event.target_id,
+            ip: audit_event.ip,
+            user_agent: audit_event.user_agent,
+            metadata: serde_json::from_str(&audit_event.metadata).unwrap_or(Value::Null),
+            created_at: audit_event.created_at,
+        }
+    }
+}
+
+impl From<Vec<AuditEvent>> for AuditEventsResponse {
+    fn from(audit_events: Vec<AuditEvent>) -> Self {
+        AuditEventsResponse(
+            audit_events
+                .into_par_iter()
+                .map(|audit_event| audit_event.into())
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::models::audit_event::tests::create_audit_event;
+    use crate::tests::helpers::tests::{get_data_pool, mock_get_request};
+
+    #[actix_rt::test]
+    async fn it_gets_audit_events_for_a_target() {
+        let target = Uuid::new_v4().to_string();
+        let audit_event = create_audit_event("user.delete", &target);
+        let params = Query(AuditEventsRequest {
+            target_id: Some(target),
+            ..Default::default()
+        });
+        let response = get_audit_events(
+            mock_get_request("/api/v1/audit-events"),
+            params,
+            get_data_pool(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.pagination.total, 1);
+        assert_eq!(response.data.0[0].id.to_string(), audit_event.id);
+        assert_eq!(response.data.0[0].metadata, serde_json::json!({}));
+    }
+}
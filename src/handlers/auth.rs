@@ -1,13 +1,31 @@
-use crate::auth::{create_jwt, hash, PrivateClaim};
+use crate::audit::{Action, Audit};
+use crate::auth::{
+    create_jwt, create_mfa_token, decode_jwt, get_generation, get_token, revoke_all_jwts,
+    revoke_jwt, revoke_session, token_source, PrivateClaim,
+};
+use crate::cache::Cache;
+use crate::config::CONFIG;
 use crate::database::PoolType;
 use crate::errors::ApiError;
 use crate::handlers::user::UserResponse;
-use crate::helpers::{respond_json, respond_ok};
-use crate::models::user::find_by_auth;
+use crate::helpers::{client_ip, respond_json, respond_ok, user_agent};
+use crate::models::mfa::is_enabled as is_mfa_enabled;
+use crate::models::refresh_token::{
+    create as create_refresh_token, rotate as rotate_refresh_token, Rotation,
+};
+use crate::models::role::find_login_grants;
+use crate::models::session::{
+    create as create_session, revoke as revoke_user_session, revoke_all as revoke_all_sessions,
+    touch as touch_session,
+};
+use crate::models::user::{find, find_by_auth, AuthUser};
+use crate::throttle;
 use crate::validate::validate;
 use actix_identity::Identity;
-use actix_web::web::{block, Data, HttpResponse, Json};
+use actix_web::web::{block, Data, HttpRequest, HttpResponse, Json};
 use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
 use validator::Validate;
 
 #[derive(Clone, Debug, Deserialize, Serialize, Validate)]
@@ -22,64 +40,366 @@ pub struct LoginRequest {
     pub password: String,
 }
 
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct RefreshRequest {
+    #[validate(length(min = 1, message = "refresh_token is required"))]
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct LoginResponse {
+    #[serde(flatten)]
+    pub user: UserResponse,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    pub refresh_token: String,
+}
+
+/// Returned by login instead of a JWT when the user has MFA enabled
+/// Exchange the mfa_token and a code for a JWT at /auth/mfa/verify
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct MfaRequiredResponse {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum LoginStep {
+    Complete(LoginResponse),
+    MfaRequired(MfaRequiredResponse),
+}
+
 /// Login a user
 /// Create and remember their JWT
+///
+/// Cookie clients have the JWT remembered in the identity cookie.
+/// Bearer clients receive the JWT in the response body.
+///
+/// Users with MFA enabled receive an mfa_token instead, see handlers::mfa.
+///
+/// Repeated failures for an email or IP address are throttled, see throttle.
 pub async fn login(
     id: Identity,
+    req: HttpRequest,
     pool: Data<PoolType>,
+    cache: Option<Cache>,
     params: Json<LoginRequest>,
-) -> Result<Json<UserResponse>, ApiError> {
+) -> Result<Json<LoginStep>, ApiError> {
     validate(&params)?;
 
-    // Validate that the email + hashed password matches
-    let hashed = hash(&params.password);
-    let user = block(move || find_by_auth(&pool, &params.email, &hashed)).await?;
+    let email = params.email.clone();
+    let ip = client_ip(&req);
+    if let Some(cache) = cache.clone() {
+        let checked = throttle::check(cache, &email, ip.as_deref()).await;
+        if let Err(ApiError::TooManyRequests(..)) = checked {
+            login_failed(&req, pool.clone(), &email, "throttled").await;
+        }
+        checked?;
+    }
+
+    // Validate that the email + password matches
+    let block_pool = pool.clone();
+    let found = block(move || {
+        let user = find_by_auth(
+            &block_pool,
+            &params.email,
+            &params.password,
+            CONFIG.email_verification_required,
+        )?;
+        let mfa_enabled = is_mfa_enabled(&block_pool, user.id)?;
+        Ok::<_, ApiError>((user, mfa_enabled))
+    })
+    .await
+    .map_err(ApiError::from);
+
+    match &found {
+        Err(ApiError::Unauthorized(_)) => {
+            login_failed(&req, pool.clone(), &email, "invalid_credentials").await
+        }
+        Err(ApiError::EmailNotVerified(_)) => {
+            login_failed(&req, pool.clone(), &email, "email_not_verified").await
+        }
+        _ => {}
+    }
+    if let Some(cache) = cache.clone() {
+        match &found {
+            // Users with MFA enabled aren't logged in until their code is verified
+            Ok((_, true)) => {}
+            Ok(_) => throttle::record_success(cache, &email).await?,
+            Err(ApiError::Unauthorized(_)) => {
+                throttle::record_failure(cache, &email, ip.as_deref()).await?
+            }
+            Err(_) => {}
+        }
+    }
+    let (user, mfa_enabled) = found?;
+    respond_json(login_or_require_mfa(id, req, pool, cache, user, mfa_enabled).await?)
+}
+
+/// Record a failed login attempt, there's no actor as nobody logged in
+async fn login_failed(req: &HttpRequest, pool: Data<PoolType>, email: &str, reason: &str) {
+    Audit::new(Action::LoginFailed, req)
+        .metadata(json!({ "email": email, "reason": reason }))
+        .record(pool)
+        .await;
+}
+
+/// Log in a user whose credentials are verified
+/// Users with MFA enabled receive an mfa_token instead, see handlers::mfa.
+pub async fn login_or_require_mfa(
+    id: Identity,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    user: UserResponse,
+    mfa_enabled: bool,
+) -> Result<LoginStep, ApiError> {
+    if mfa_enabled {
+        return Ok(LoginStep::MfaRequired(MfaRequiredResponse {
+            mfa_required: true,
+            mfa_token: create_mfa_token(user.id)?,
+        }));
+    }
 
-    // Create a JWT
-    let private_claim = PrivateClaim::new(user.id, user.email.clone());
+    let login_response = complete_login(id, req, pool, cache, user).await?;
+    Ok(LoginStep::Complete(login_response))
+}
+
+/// Issue a JWT and a new refresh token to a user whose credentials are verified
+/// Each login starts a new session, see models::session.
+pub async fn complete_login(
+    id: Identity,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    user: UserResponse,
+) -> Result<LoginResponse, ApiError> {
+    let user_id = user.id;
+    let user_agent = user_agent(&req);
+    let ip = client_ip(&req);
+    let block_pool = pool.clone();
+    let (grants, session_id, refresh_token) = block(move || {
+        let grants = find_login_grants(&block_pool, user_id)?;
+        let session = create_session(&block_pool, user_id, user_agent.as_deref(), ip.as_deref())?;
+        let session_id = Uuid::parse_str(&session.id)?;
+        let refresh_token = create_refresh_token(&block_pool, user_id, session_id)?;
+        Ok::<_, ApiError>((grants, session_id, refresh_token))
+    })
+    .await?;
+
+    Audit::new(Action::Login, &req)
+        .actor_id(user_id)
+        .metadata(json!({ "session_id": session_id }))
+        .record(pool)
+        .await;
+
+    issue_tokens(id, &req, cache, user, grants, session_id, refresh_token).await
+}
+
+/// Exchange a refresh token for a new access token
+/// The refresh token is rotated, so the old one can no longer be used
+///
+/// Reusing a refresh token revokes its session, including the session's JWTs.
+pub async fn refresh(
+    id: Identity,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    params: Json<RefreshRequest>,
+) -> Result<Json<LoginResponse>, ApiError> {
+    validate(&params)?;
+
+    let block_pool = pool.clone();
+    let rotation = block(move || rotate_refresh_token(&block_pool, &params.refresh_token)).await?;
+    let (user_id, session_id, refresh_token) = match rotation {
+        Rotation::Rotated(user_id, session_id, refresh_token) => {
+            (user_id, session_id, refresh_token)
+        }
+        Rotation::Reused(session_id) => {
+            if let Some(cache) = cache {
+                revoke_session(cache, session_id).await?;
+            }
+            return Err(ApiError::Unauthorized("Invalid refresh token".into()));
+        }
+    };
+
+    let (user, grants) = block(move || {
+        touch_session(&pool, session_id)?;
+        let user = find(&pool, user_id)?;
+        let grants = find_login_grants(&pool, user_id)?;
+        Ok::<_, ApiError>((user, grants))
+    })
+    .await?;
+
+    respond_json(issue_tokens(id, &req, cache, user, grants, session_id, refresh_token).await?)
+}
+
+/// Create a JWT for the user, remembering it and/or handing it back to the client
+async fn issue_tokens(
+    id: Identity,
+    req: &HttpRequest,
+    cache: Option<Cache>,
+    user: UserResponse,
+    (roles, permissions): (Vec<String>, Vec<String>),
+    session_id: Uuid,
+    refresh_token: String,
+) -> Result<LoginResponse, ApiError> {
+    let generation = match cache {
+        Some(cache) => get_generation(cache, user.id).await?,
+        None => 0,
+    };
+    let private_claim = PrivateClaim::new(user.id, user.email.clone())
+        .with_grants(roles, permissions)
+        .with_generation(generation)
+        .with_session(session_id);
     let jwt = create_jwt(private_claim)?;
 
-    // Remember the token
-    id.remember(jwt);
-    respond_json(user.into())
+    Ok(LoginResponse {
+        user,
+        token: remember_jwt(req, id, jwt),
+        refresh_token,
+    })
+}
+
+/// Remember a JWT in the identity cookie and/or return it for the response body
+/// Depending on the request's token_source
+pub fn remember_jwt(req: &HttpRequest, id: Identity, jwt: String) -> Option<String> {
+    let source = token_source(req);
+    if source.uses_cookie() {
+        id.remember(jwt.clone());
+    }
+    if source.uses_bearer() {
+        Some(jwt)
+    } else {
+        None
+    }
 }
 
 /// Logout a user
-/// Forget their user_id
-pub async fn logout(id: Identity) -> Result<HttpResponse, ApiError> {
+/// Forget their user_id and revoke the JWT and session used for this request
+///
+/// The JWT is only revoked when there's a cache, otherwise it's accepted
+/// until it expires.  The session and its refresh tokens are always revoked.
+pub async fn logout(
+    id: Identity,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+) -> Result<HttpResponse, ApiError> {
+    id.forget();
+    let private_claim = get_token(&req).and_then(|token| decode_jwt(&token).ok());
+    if let Some(private_claim) = private_claim {
+        if let Some(session_id) = private_claim.session_id {
+            let user_id = private_claim.user_id;
+            let block_pool = pool.clone();
+            let revoked =
+                block(move || revoke_user_session(&block_pool, user_id, session_id)).await;
+            match revoked.map_err(ApiError::from) {
+                Ok(_) | Err(ApiError::NotFound(_)) => {}
+                Err(error) => return Err(error),
+            }
+        }
+        Audit::new(Action::Logout, &req)
+            .actor(&private_claim.clone().into())
+            .metadata(json!({ "session_id": private_claim.session_id }))
+            .record(pool)
+            .await;
+        if let Some(cache) = cache {
+            revoke_jwt(cache.clone(), &private_claim).await?;
+            if let Some(session_id) = private_claim.session_id {
+                revoke_session(cache, session_id).await?;
+            }
+        }
+    }
+    respond_ok()
+}
+
+/// Logout a user everywhere
+/// Revoke all of their sessions, JWTs and refresh tokens
+///
+/// Like logout, JWTs are only revoked when there's a cache.
+pub async fn logout_all(
+    id: Identity,
+    user: AuthUser,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+) -> Result<HttpResponse, ApiError> {
     id.forget();
+    let user_id = Uuid::parse_str(&user.id)?;
+    let block_pool = pool.clone();
+    block(move || revoke_all_sessions(&block_pool, user_id)).await?;
+    Audit::new(Action::LogoutAll, &req)
+        .actor(&user)
+        .record(pool)
+        .await;
+    if let Some(cache) = cache {
+        revoke_all_jwts(cache, user_id).await?;
+    }
     respond_ok()
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use crate::tests::helpers::tests::get_data_pool;
-    use actix_identity::Identity;
-    use actix_web::{test, FromRequest};
+    use crate::auth::{decode_mfa_token, verify_jwt};
+    use crate::models::audit_event::{get_all as get_audit_events, AuditEventFilter};
+    use crate::models::mfa::tests::enable_mfa;
+    use crate::models::session::find_all;
+    use crate::models::user::tests::create_user_with_email;
+    use crate::pagination::PaginationRequest;
+    use crate::tests::helpers::tests::{get_data_cache, get_data_pool, get_identity, get_pool};
+    use actix_web::{http::header::AUTHORIZATION, test};
 
-    async fn get_identity() -> Identity {
-        let (request, mut payload) =
-            test::TestRequest::with_header("content-type", "application/json").to_http_parts();
-        let identity = Option::<Identity>::from_request(&request, &mut payload)
-            .await
-            .unwrap()
-            .unwrap();
-        identity
+    async fn login_with(email: &str) -> Result<LoginStep, ApiError> {
+        login_with_password(email, "123456").await
     }
 
-    async fn login_user() -> Result<Json<UserResponse>, ApiError> {
+    async fn login_with_password(email: &str, password: &str) -> Result<LoginStep, ApiError> {
         let params = LoginRequest {
-            email: "satoshi@nakamotoinstitute.org".into(),
-            password: "123456".into(),
+            email: email.into(),
+            password: password.into(),
         };
         let identity = get_identity().await;
-        login(identity, get_data_pool(), Json(params)).await
+        let request = test::TestRequest::default().to_http_request();
+        login(
+            identity,
+            request,
+            get_data_pool(),
+            Some(get_data_cache()),
+            Json(params),
+        )
+        .await
+        .map(|response| response.into_inner())
+    }
+
+    pub async fn login_user() -> Result<LoginResponse, ApiError> {
+        match login_with_password("satoshi@nakamotoinstitute.org", "Bitcoin-2009").await? {
+            LoginStep::Complete(login_response) => Ok(login_response),
+            LoginStep::MfaRequired(_) => panic!("MFA is not enabled for this user"),
+        }
+    }
+
+    async fn refresh_user(refresh_token: String) -> Result<Json<LoginResponse>, ApiError> {
+        let params = RefreshRequest { refresh_token };
+        let identity = get_identity().await;
+        refresh(
+            identity,
+            test::TestRequest::default().to_http_request(),
+            get_data_pool(),
+            Some(get_data_cache()),
+            Json(params),
+        )
+        .await
     }
 
     async fn logout_user() -> Result<HttpResponse, ApiError> {
         let identity = get_identity().await;
-        logout(identity).await
+        let request = test::TestRequest::default().to_http_request();
+        logout(identity, request, get_data_pool(), Some(get_data_cache())).await
     }
 
     #[actix_rt::test]
@@ -88,10 +408,141 @@ pub mod tests {
         assert!(response.is_ok());
     }
 
+    #[actix_rt::test]
+    async fn it_audits_a_login() {
+        let response = login_user().await.unwrap();
+        let filter = AuditEventFilter {
+            actor_id: Some(response.user.id.to_string()),
+            action: Some("login".into()),
+            ..Default::default()
+        };
+        let params = PaginationRequest {
+            page: Some(1),
+            per_page: Some(10),
+        };
+        let audit_events = get_audit_events(&get_pool(), &filter, params, "".into()).unwrap();
+        assert!(audit_events.pagination.total > 0);
+    }
+
+    #[actix_rt::test]
+    async fn it_returns_a_token_for_bearer_clients() {
+        let response = login_user().await.unwrap();
+        assert_eq!(
+            response.token.is_some(),
+            CONFIG.auth_token_source.uses_bearer()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_requires_mfa_for_a_user_with_mfa_enabled() {
+        let email = format!("mfa-{}@nothing.org", Uuid::new_v4());
+        let user = create_user_with_email(&email).unwrap();
+        enable_mfa(user.id);
+        let response = login_with(&email).await.unwrap();
+        match response {
+            LoginStep::MfaRequired(mfa_required) => {
+                assert_eq!(decode_mfa_token(&mfa_required.mfa_token).unwrap(), user.id)
+            }
+            LoginStep::Complete(_) => panic!("Logged in without MFA"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn it_throttles_an_email_after_failed_logins() {
+        let email = format!("throttle-{}@nothing.org", Uuid::new_v4());
+        create_user_with_email(&email).unwrap();
+        for _ in 0..CONFIG.login_backoff_threshold {
+            let response = login_with_password(&email, "bad-password").await;
+            assert!(response.is_err());
+        }
+
+        // Even the correct password is rejected while throttled
+        match login_with(&email).await {
+            Err(ApiError::TooManyRequests(_, retry_after)) => assert!(retry_after > 0),
+            _ => panic!("expected the email to be throttled"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn it_refreshes_a_token() {
+        let login = login_user().await.unwrap();
+        let response = refresh_user(login.refresh_token.clone()).await.unwrap();
+        assert_eq!(response.user, login.user);
+        assert_ne!(response.refresh_token, login.refresh_token);
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_refresh_a_reused_token() {
+        let login = login_user().await.unwrap();
+        refresh_user(login.refresh_token.clone()).await.unwrap();
+        let response = refresh_user(login.refresh_token).await;
+        assert!(response.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_revokes_the_session_when_a_token_is_reused() {
+        let email = format!("reuse-{}@nothing.org", Uuid::new_v4());
+        let user = create_user_with_email(&email).unwrap();
+        let login = match login_with(&email).await.unwrap() {
+            LoginStep::Complete(login_response) => login_response,
+            LoginStep::MfaRequired(_) => panic!("MFA is not enabled for this user"),
+        };
+        let session_id = Uuid::parse_str(&find_all(&get_pool(), user.id).unwrap()[0].id).unwrap();
+        let private_claim = PrivateClaim::new(user.id, email).with_session(session_id);
+        let jwt = create_jwt(private_claim).unwrap();
+
+        refresh_user(login.refresh_token.clone()).await.unwrap();
+        assert!(refresh_user(login.refresh_token).await.is_err());
+        assert!(find_all(&get_pool(), user.id).unwrap().is_empty());
+        assert!(verify_jwt(&jwt, Some(get_data_cache())).await.is_err());
+    }
+
     #[actix_rt::test]
     async fn it_logs_a_user_out() {
         login_user().await.unwrap();
         let response = logout_user().await;
         assert!(response.is_ok());
     }
+
+    #[actix_rt::test]
+    async fn it_logs_a_user_out_without_a_cache() {
+        let user = create_user_with_email("nocache@nothing.org").unwrap();
+        let session = create_session(&get_pool(), user.id, None, None).unwrap();
+        let session_id = Uuid::parse_str(&session.id).unwrap();
+        let private_claim = PrivateClaim::new(user.id, user.email).with_session(session_id);
+        let token = create_jwt(private_claim).unwrap();
+        let request = test::TestRequest::default()
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .to_http_request();
+        let response = logout(get_identity().await, request, get_data_pool(), None).await;
+        assert!(response.is_ok());
+        assert!(find_all(&get_pool(), user.id).unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn it_logs_a_user_out_everywhere() {
+        let user_id = Uuid::new_v4();
+        let user = AuthUser {
+            id: user_id.to_string(),
+            email: "logout-all@nothing.org".into(),
+            roles: vec![],
+            permissions: vec![],
+            impersonator: None,
+        };
+        let cache = get_data_cache();
+        let private_claim = PrivateClaim::new(user_id, user.email.clone());
+        let jwt = create_jwt(private_claim).unwrap();
+        let identity = get_identity().await;
+        let request = test::TestRequest::default().to_http_request();
+        logout_all(
+            identity,
+            user,
+            request,
+            get_data_pool(),
+            Some(cache.clone()),
+        )
+        .await
+        .unwrap();
+        assert!(verify_jwt(&jwt, Some(cache)).await.is_err());
+    }
 }
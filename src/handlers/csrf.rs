@@ -0,0 +1,59 @@
+use crate::auth::generate_token;
+use crate::errors::ApiError;
+use crate::middleware::csrf::{csrf_cookie, CSRF_COOKIE};
+use actix_web::{
+    web::{HttpRequest, HttpResponse},
+    HttpMessage,
+};
+use serde::Serialize;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct CsrfTokenResponse {
+    pub csrf_token: String,
+}
+
+/// Get a CSRF token for cookie-authenticated requests, see middleware::csrf
+///
+/// The token is set in the csrf_token cookie and returned in the body, for
+/// SPAs to send back in the X-CSRF-Token header.  An existing token is kept.
+pub async fn get_csrf_token(req: HttpRequest) -> Result<HttpResponse, ApiError> {
+    let csrf_token = req
+        .cookie(CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|token| !token.is_empty())
+        .unwrap_or_else(generate_token);
+    Ok(HttpResponse::Ok()
+        .cookie(csrf_cookie(&csrf_token))
+        .json(CsrfTokenResponse { csrf_token }))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use actix_web::{cookie::Cookie, test, web, App};
+
+    async fn get_token(request: test::TestRequest) -> (String, String) {
+        let mut app =
+            test::init_service(App::new().route("/csrf", web::get().to(get_csrf_token))).await;
+        let response = test::call_service(&mut app, request.uri("/csrf").to_request()).await;
+        let cookie = response.response().cookies().next().unwrap();
+        let cookie = cookie.value().to_string();
+        let body: CsrfTokenResponse = test::read_body_json(response).await;
+        (cookie, body.csrf_token)
+    }
+
+    #[actix_rt::test]
+    async fn it_issues_a_csrf_token() {
+        let (cookie, token) = get_token(test::TestRequest::get()).await;
+        assert_eq!(cookie, token);
+        assert_eq!(token.len(), 64);
+    }
+
+    #[actix_rt::test]
+    async fn it_keeps_an_existing_csrf_token() {
+        let request = test::TestRequest::get().cookie(Cookie::new(CSRF_COOKIE, "existing"));
+        let (cookie, token) = get_token(request).await;
+        assert_eq!(cookie, "existing");
+        assert_eq!(token, "existing");
+    }
+}
@@ -0,0 +1,339 @@
+use crate::audit::{Action, Audit};
+use crate::auth::{
+    create_jwt, decode_jwt, get_generation, get_token, revoke_jwt, verify_jwt, PrivateClaim,
+};
+use crate::cache::Cache;
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::auth::remember_jwt;
+use crate::handlers::user::UserResponse;
+use crate::helpers::respond_json;
+use crate::models::role::find_login_grants;
+use crate::models::session::{is_active as is_active_session, touch as touch_session};
+use crate::models::user::{find, AuthUser};
+use actix_identity::Identity;
+use actix_web::web::{block, Data, HttpRequest, Json, Path};
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct ImpersonationResponse {
+    #[serde(flatten)]
+    pub user: UserResponse,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impersonator: Option<Uuid>,
+}
+
+/// Act as another user, to see the app exactly as they see it
+///
+/// Issues a JWT for the user that expires after IMPERSONATION_EXPIRATION
+/// minutes and can't be refreshed.  It carries the user's grants, not the
+/// admin's, along with an impersonator claim naming the admin.
+pub async fn impersonate_user(
+    id: Identity,
+    admin: AuthUser,
+    user_id: Path<Uuid>,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+) -> Result<Json<ImpersonationResponse>, ApiError> {
+    if admin.impersonator.is_some() {
+        return Err(ApiError::Forbidden(
+            "Cannot impersonate while impersonating".into(),
+        ));
+    }
+    let admin_id = Uuid::parse_str(&admin.id)?;
+    let user_id = *user_id;
+    if admin_id == user_id {
+        return Err(ApiError::BadRequest("Cannot impersonate yourself".into()));
+    }
+
+    // Ending the impersonation returns the admin to this session
+    let admin_session_id = get_token(&req)
+        .and_then(|token| decode_jwt(&token).ok())
+        .filter(|private_claim| private_claim.user_id == admin_id)
+        .and_then(|private_claim| private_claim.session_id);
+
+    let block_pool = pool.clone();
+    let (user, (roles, permissions)) = block(move || {
+        let user = find(&block_pool, user_id)?;
+        let grants = find_login_grants(&block_pool, user_id)?;
+        Ok::<_, ApiError>((user, grants))
+    })
+    .await?;
+    let (generation, admin_generation) = match cache {
+        Some(cache) => (
+            get_generation(cache.clone(), user_id).await?,
+            get_generation(cache, admin_id).await?,
+        ),
+        None => (0, 0),
+    };
+    let private_claim = PrivateClaim::new(user.id, user.email.clone())
+        .with_grants(roles, permissions)
+        .with_generation(generation)
+        .with_impersonator(admin_id, admin_session_id, admin_generation)
+        .with_expiration(CONFIG.impersonation_expiration);
+    let jwt = create_jwt(private_claim)?;
+
+    Audit::new(Action::ImpersonationStarted, &req)
+        .actor(&admin)
+        .target(user_id)
+        .record(pool)
+        .await;
+    respond_json(ImpersonationResponse {
+        user,
+        token: remember_jwt(&req, id, jwt),
+        impersonator: Some(admin_id),
+    })
+}
+
+/// Stop impersonating, returning the admin to the session they started from
+/// The impersonation JWT is revoked
+///
+/// Nothing is issued if the admin has since logged out of that session, or
+/// out of every session.
+pub async fn end_impersonation(
+    id: Identity,
+    user: AuthUser,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+) -> Result<Json<ImpersonationResponse>, ApiError> {
+    let not_impersonating = || ApiError::BadRequest("Not impersonating a user".into());
+    user.impersonator.as_ref().ok_or_else(not_impersonating)?;
+    let token = get_token(&req).ok_or_else(not_impersonating)?;
+    let private_claim = verify_jwt(&token, cache.clone()).await?;
+    let impersonator = private_claim
+        .impersonator
+        .clone()
+        .ok_or_else(not_impersonating)?;
+    if let Some(cache) = cache.clone() {
+        revoke_jwt(cache, &private_claim).await?;
+    }
+
+    let admin_id = impersonator.user_id;
+    let admin_session_id = impersonator.session_id;
+    let block_pool = pool.clone();
+    let (admin, (roles, permissions)) = block(move || {
+        if let Some(session_id) = admin_session_id {
+            if !is_active_session(&block_pool, admin_id, session_id)? {
+                return Err(ApiError::Unauthorized("Session has been revoked".into()));
+            }
+            touch_session(&block_pool, session_id)?;
+        }
+        let admin = find(&block_pool, admin_id)?;
+        let grants = find_login_grants(&block_pool, admin_id)?;
+        Ok::<_, ApiError>((admin, grants))
+    })
+    .await?;
+    let generation = match cache {
+        Some(cache) => get_generation(cache, admin_id).await?,
+        None => 0,
+    };
+    let mut private_claim = PrivateClaim::new(admin.id, admin.email.clone())
+        .with_grants(roles, permissions)
+        .with_generation(generation);
+    if let Some(session_id) = admin_session_id {
+        private_claim = private_claim.with_session(session_id);
+    }
+    let jwt = create_jwt(private_claim)?;
+
+    Audit::new(Action::ImpersonationEnded, &req)
+        .actor_id(admin_id)
+        .target(&user.id)
+        .record(pool)
+        .await;
+    respond_json(ImpersonationResponse {
+        user: admin,
+        token: remember_jwt(&req, id, jwt),
+        impersonator: None,
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::handlers::auth::logout_all;
+    use crate::models::session::{create as create_session, revoke as revoke_session};
+    use crate::tests::helpers::tests::{
+        get_auth_user, get_data_cache, get_data_pool, get_identity,
+    };
+    use actix_web::{http::header::AUTHORIZATION, test};
+
+    fn get_admin() -> AuthUser {
+        get_auth_user(
+            "impersonation",
+            vec!["admin".into()],
+            vec!["user:impersonate".into()],
+        )
+    }
+
+    fn get_user_id() -> Uuid {
+        Uuid::parse_str(&get_auth_user("impersonated", vec![], vec![]).id).unwrap()
+    }
+
+    fn bearer_request(token: &str) -> HttpRequest {
+        test::TestRequest::default()
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .to_http_request()
+    }
+
+    /// Start a session for the admin, returning its id and a JWT issued for it
+    fn login(admin: &AuthUser) -> (Uuid, String) {
+        let admin_id = Uuid::parse_str(&admin.id).unwrap();
+        let session = create_session(&get_data_pool(), admin_id, None, None).unwrap();
+        let session_id = Uuid::parse_str(&session.id).unwrap();
+        let private_claim =
+            PrivateClaim::new(admin_id, admin.email.clone()).with_session(session_id);
+        (session_id, create_jwt(private_claim).unwrap())
+    }
+
+    async fn impersonate(admin: AuthUser, user_id: Uuid) -> Result<String, ApiError> {
+        impersonate_with(
+            admin,
+            user_id,
+            test::TestRequest::default().to_http_request(),
+        )
+        .await
+    }
+
+    async fn impersonate_with(
+        admin: AuthUser,
+        user_id: Uuid,
+        req: HttpRequest,
+    ) -> Result<String, ApiError> {
+        let response = impersonate_user(
+            get_identity().await,
+            admin,
+            user_id.into(),
+            req,
+            get_data_pool(),
+            Some(get_data_cache()),
+        )
+        .await?;
+        Ok(response
+            .into_inner()
+            .token
+            .expect("impersonation is tested with bearer tokens"))
+    }
+
+    #[actix_rt::test]
+    async fn it_impersonates_a_user() {
+        let admin = get_admin();
+        let user = get_auth_user("impersonated", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        let token = impersonate(admin.clone(), user_id).await.unwrap();
+        let impersonated: AuthUser = decode_jwt(&token).unwrap().into();
+        assert_eq!(impersonated.id, user.id);
+        assert_eq!(impersonated.impersonator, Some(admin.id));
+        assert!(impersonated.permissions.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_impersonate_while_impersonating() {
+        let mut admin = get_admin();
+        admin.impersonator = Some(Uuid::new_v4().to_string());
+        let user_id = get_user_id();
+        let response = impersonate(admin, user_id).await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::Forbidden("Cannot impersonate while impersonating".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_ends_an_impersonation() {
+        let admin = get_admin();
+        let user_id = get_user_id();
+        let token = impersonate(admin.clone(), user_id).await.unwrap();
+        let response = end_impersonation(
+            get_identity().await,
+            decode_jwt(&token).unwrap().into(),
+            bearer_request(&token),
+            get_data_pool(),
+            Some(get_data_cache()),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+        assert_eq!(response.user.id.to_string(), admin.id);
+        assert_eq!(response.impersonator, None);
+        assert!(verify_jwt(&token, Some(get_data_cache())).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_end_without_impersonating() {
+        let response = end_impersonation(
+            get_identity().await,
+            get_admin(),
+            test::TestRequest::default().to_http_request(),
+            get_data_pool(),
+            Some(get_data_cache()),
+        )
+        .await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::BadRequest("Not impersonating a user".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_end_after_the_admin_logs_out_everywhere() {
+        let admin = get_admin();
+        let (_, admin_token) = login(&admin);
+        let user_id = get_user_id();
+        let token = impersonate_with(admin.clone(), user_id, bearer_request(&admin_token))
+            .await
+            .unwrap();
+        logout_all(
+            get_identity().await,
+            admin,
+            test::TestRequest::default().to_http_request(),
+            get_data_pool(),
+            Some(get_data_cache()),
+        )
+        .await
+        .unwrap();
+        let response = end_impersonation(
+            get_identity().await,
+            decode_jwt(&token).unwrap().into(),
+            bearer_request(&token),
+            get_data_pool(),
+            Some(get_data_cache()),
+        )
+        .await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::Unauthorized("Token has been revoked".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_end_into_a_revoked_session() {
+        let admin = get_admin();
+        let admin_id = Uuid::parse_str(&admin.id).unwrap();
+        let (session_id, admin_token) = login(&admin);
+        let user_id = get_user_id();
+        let token = impersonate_with(admin, user_id, bearer_request(&admin_token))
+            .await
+            .unwrap();
+        revoke_session(&get_data_pool(), admin_id, session_id).unwrap();
+        let response = end_impersonation(
+            get_identity().await,
+            decode_jwt(&token).unwrap().into(),
+            bearer_request(&token),
+            get_data_pool(),
+            Some(get_data_cache()),
+        )
+        .await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::Unauthorized("Session has been revoked".into())
+        );
+    }
+}
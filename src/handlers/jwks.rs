@@ -0,0 +1,21 @@
+use crate::errors::ApiError;
+use crate::helpers::respond_json;
+use crate::keys::{Jwks, KEYS};
+use actix_web::web::Json;
+
+/// Handler to get the public keys that verify JWTs
+/// Empty when JWTs are signed with a shared secret
+pub async fn get_jwks() -> Result<Json<Jwks>, ApiError> {
+    respond_json(KEYS.jwks.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_get_jwks() {
+        let response = get_jwks().await.unwrap();
+        assert_eq!(response.into_inner(), KEYS.jwks);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::audit::{Action, Audit};
+use crate::auth::revoke_all_jwts;
+use crate::cache::Cache;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::user::{UpdateUserRequest, UserResponse};
+use crate::handlers::verification::send_verification_email;
+use crate::helpers::{respond_json, respond_ok};
+use crate::mailer::MailerType;
+use crate::models::session::revoke_all as revoke_all_sessions;
+use crate::models::user::{delete, find, update, Actor, AuthUser, UpdateUser};
+use crate::validate::validate;
+use actix_identity::Identity;
+use actix_web::web::{block, Data, HttpRequest, HttpResponse, Json};
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+
+/// Only the fields sent are changed
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UpdateMeRequest {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub email: Option<String>,
+}
+
+/// Get the logged in user
+pub async fn get_me(user: AuthUser, pool: Data<PoolType>) -> Result<Json<UserResponse>, ApiError> {
+    let user_id = Uuid::parse_str(&user.id)?;
+    let user = block(move || find(&pool, user_id)).await?;
+    respond_json(user)
+}
+
+/// Update the logged in user's profile
+///
+/// The changes are merged into the current profile, which is then validated
+/// with the same rules as update_user.  A new email must be verified again.
+pub async fn update_me(
+    user: AuthUser,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    mailer: MailerType,
+    params: Json<UpdateMeRequest>,
+) -> Result<Json<UserResponse>, ApiError> {
+    let user_id = Uuid::parse_str(&user.id)?;
+    let block_pool = pool.clone();
+    let current = block(move || find(&block_pool, user_id)).await?;
+
+    let params = params.into_inner();
+    let email_changed = params
+        .email
+        .as_ref()
+        .map_or(false, |email| *email != current.email);
+    let merged = Json(UpdateUserRequest {
+        first_name: params.first_name.unwrap_or(current.first_name),
+        last_name: params.last_name.unwrap_or(current.last_name),
+        email: params.email.unwrap_or(current.email),
+    });
+    validate(&merged)?;
+
+    let update_user = UpdateUser {
+        id: user_id.to_string(),
+        first_name: merged.first_name.to_string(),
+        last_name: merged.last_name.to_string(),
+        email: merged.email.to_string(),
+        updated_by: Actor::from(&user).id(),
+    };
+    let block_pool = pool.clone();
+    let updated = block(move || {
+        let updated = update(&block_pool, &update_user)?;
+        if email_changed {
+            send_verification_email(&block_pool, &mailer, updated.id, &updated.email)?;
+        }
+        Ok::<_, ApiError>(updated)
+    })
+    .await?;
+    Audit::new(Action::UserUpdated, &req)
+        .actor(&user)
+        .target(updated.id)
+        .metadata(json!({ "email": updated.email }))
+        .record(pool)
+        .await;
+    respond_json(updated)
+}
+
+/// Delete the logged in user's account
+/// Logs them out everywhere first
+pub async fn delete_me(
+    id: Identity,
+    user: AuthUser,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+) -> Result<HttpResponse, ApiError> {
+    if user.impersonator.is_some() {
+        return Err(ApiError::Forbidden(
+            "Cannot delete an account while impersonating".into(),
+        ));
+    }
+
+    let user_id = Uuid::parse_str(&user.id)?;
+    let block_pool = pool.clone();
+    block(move || {
+        revoke_all_sessions(&block_pool, user_id)?;
+        delete(&block_pool, user_id)
+    })
+    .await?;
+    if let Some(cache) = cache {
+        revoke_all_jwts(cache, user_id).await?;
+    }
+    Audit::new(Action::UserDeleted, &req)
+        .actor(&user)
+        .target(user_id)
+        .metadata(json!({ "email": user.email }))
+        .record(pool)
+        .await;
+    id.forget();
+    respond_ok()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::mailer::{Mailer, MemoryMailer};
+    use crate::models::user::{find_by_auth, mark_email_verified};
+    use crate::tests::helpers::tests::{
+        get_auth_user, get_data_cache, get_data_mailer, get_data_pool, get_identity, get_pool,
+    };
+    use actix_web::test;
+    use std::sync::Arc;
+
+    async fn update_profile(
+        user: AuthUser,
+        params: UpdateMeRequest,
+    ) -> Result<UserResponse, ApiError> {
+        update_profile_with(user, params, get_data_mailer()).await
+    }
+
+    async fn update_profile_with(
+        user: AuthUser,
+        params: UpdateMeRequest,
+        mailer: MailerType,
+    ) -> Result<UserResponse, ApiError> {
+        let request = test::TestRequest::default().to_http_request();
+        update_me(user, request, get_data_pool(), mailer, Json(params))
+            .await
+            .map(|response| response.into_inner())
+    }
+
+    async fn delete_account(user: AuthUser) -> Result<HttpResponse, ApiError> {
+        delete_me(
+            get_identity().await,
+            user,
+            test::TestRequest::default().to_http_request(),
+            get_data_pool(),
+            Some(get_data_cache()),
+        )
+        .await
+    }
+
+    #[actix_rt::test]
+    async fn it_gets_me() {
+        let user = get_auth_user("me", vec![], vec![]);
+        let response = get_me(user.clone(), get_data_pool()).await.unwrap();
+        assert_eq!(response.into_inner().email, user.email);
+    }
+
+    #[actix_rt::test]
+    async fn it_updates_only_the_fields_sent() {
+        let user = get_auth_user("me", vec![], vec![]);
+        let params = UpdateMeRequest {
+            first_name: Some("Hal".into()),
+            ..Default::default()
+        };
+        let response = update_profile(user.clone(), params).await.unwrap();
+        assert_eq!(response.first_name, "Hal");
+        assert_eq!(response.email, user.email);
+    }
+
+    #[actix_rt::test]
+    async fn it_reverifies_a_changed_email() {
+        let user = get_auth_user("me", vec![], vec![]);
+        mark_email_verified(&get_pool(), Uuid::parse_str(&user.id).unwrap()).unwrap();
+        let email = format!("me-{}@nothing.org", Uuid::new_v4());
+        let params = UpdateMeRequest {
+            email: Some(email.clone()),
+            ..Default::default()
+        };
+        let mailer = Arc::new(MemoryMailer::default());
+        update_profile_with(user, params, Data::from(mailer.clone() as Arc<dyn Mailer>))
+            .await
+            .unwrap();
+        assert_eq!(
+            find_by_auth(&get_pool(), &email, "123456", true).unwrap_err(),
+            ApiError::EmailNotVerified("Email not verified".into())
+        );
+        assert_eq!(mailer.emails().len(), 1);
+        assert_eq!(mailer.emails()[0].to, email);
+    }
+
+    #[actix_rt::test]
+    async fn it_keeps_an_unchanged_email_verified() {
+        let user = get_auth_user("me", vec![], vec![]);
+        mark_email_verified(&get_pool(), Uuid::parse_str(&user.id).unwrap()).unwrap();
+        let params = UpdateMeRequest {
+            email: Some(user.email.clone()),
+            ..Default::default()
+        };
+        let mailer = Arc::new(MemoryMailer::default());
+        update_profile_with(
+            user.clone(),
+            params,
+            Data::from(mailer.clone() as Arc<dyn Mailer>),
+        )
+        .await
+        .unwrap();
+        assert!(find_by_auth(&get_pool(), &user.email, "123456", true).is_ok());
+        assert!(mailer.emails().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn it_revalidates_the_profile() {
+        let params = UpdateMeRequest {
+            email: Some("not-an-email".into()),
+            ..Default::default()
+        };
+        let response = update_profile(get_auth_user("me", vec![], vec![]), params).await;
+        assert!(matches!(response, Err(ApiError::ValidationError(_))));
+    }
+
+    #[actix_rt::test]
+    async fn it_deletes_me() {
+        let user = get_auth_user("me", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        delete_account(user).await.unwrap();
+        assert!(find(&get_pool(), user_id).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_delete_me_while_impersonating() {
+        let mut user = get_auth_user("me", vec![], vec![]);
+        user.impersonator = Some(Uuid::new_v4().to_string());
+        let response = delete_account(user).await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::Forbidden("Cannot delete an account while impersonating".into())
+        );
+    }
+}
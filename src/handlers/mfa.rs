@@ -0,0 +1,355 @@
+use crate::auth::{decode_mfa_token, use_mfa_token};
+use crate::cache::Cache;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::auth::{complete_login, LoginResponse};
+use crate::helpers::{client_ip, respond_json, respond_ok};
+use crate::models::mfa::{confirm, disable, enroll, verify};
+use crate::models::user::{find, AuthUser};
+use crate::throttle;
+use crate::totp::otpauth_uri;
+use crate::validate::validate;
+use actix_identity::Identity;
+use actix_web::web::{block, Data, HttpRequest, HttpResponse, Json};
+use serde::Serialize;
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct MfaCodeRequest {
+    #[validate(length(min = 1, message = "code is required"))]
+    pub code: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct VerifyMfaRequest {
+    #[validate(length(min = 1, message = "mfa_token is required"))]
+    pub mfa_token: String,
+
+    #[validate(length(min = 1, message = "code is required"))]
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct EnrollMfaResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Start MFA enrollment for the logged in user
+/// Returns a TOTP secret, and an otpauth:// URI to show as a QR code
+pub async fn enroll_mfa(
+    user: AuthUser,
+    pool: Data<PoolType>,
+) -> Result<Json<EnrollMfaResponse>, ApiError> {
+    let user_id = Uuid::parse_str(&user.id)?;
+    let secret = block(move || enroll(&pool, user_id)).await?;
+    respond_json(EnrollMfaResponse {
+        otpauth_uri: otpauth_uri(&secret, &user.email),
+        secret,
+    })
+}
+
+/// Enable MFA for the logged in user by confirming a code from their app
+/// Returns single-use recovery codes, which are only shown this once
+///
+/// Bad codes are throttled like failed logins, see throttled_code.
+pub async fn confirm_mfa(
+    user: AuthUser,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    params: Json<MfaCodeRequest>,
+) -> Result<Json<RecoveryCodesResponse>, ApiError> {
+    validate(&params)?;
+
+    let cache = require_cache(cache)?;
+    let user_id = Uuid::parse_str(&user.id)?;
+    let recovery_codes = throttled_code(cache, &req, &user.email, move || {
+        confirm(&pool, user_id, &params.code)
+    })
+    .await?;
+    respond_json(RecoveryCodesResponse { recovery_codes })
+}
+
+/// Disable MFA for the logged in user, with a code or recovery code
+///
+/// Bad codes are throttled like failed logins, see throttled_code.
+pub async fn disable_mfa(
+    user: AuthUser,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    params: Json<MfaCodeRequest>,
+) -> Result<HttpResponse, ApiError> {
+    validate(&params)?;
+
+    let cache = require_cache(cache)?;
+    let user_id = Uuid::parse_str(&user.id)?;
+    throttled_code(cache, &req, &user.email, move || {
+        verify(&pool, user_id, &params.code)?;
+        disable(&pool, user_id)
+    })
+    .await?;
+    respond_ok()
+}
+
+/// The second step of login for users with MFA enabled
+/// Exchange the mfa_token from login and a code or recovery code for a JWT
+///
+/// Each mfa_token is used up by the first code sent with it, right or wrong,
+/// so a bad code means logging in again.  Bad codes also count as failed
+/// logins for the user, so they're throttled and locked out the same way.
+pub async fn verify_mfa(
+    id: Identity,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    params: Json<VerifyMfaRequest>,
+) -> Result<Json<LoginResponse>, ApiError> {
+    validate(&params)?;
+
+    let cache = require_cache(cache)?;
+    let user_id = decode_mfa_token(&params.mfa_token)
+        .map_err(|_| ApiError::Unauthorized("Invalid MFA token".into()))?;
+    let block_pool = pool.clone();
+    let user = block(move || find(&block_pool, user_id)).await?;
+
+    throttle::check(cache.clone(), &user.email, client_ip(&req).as_deref()).await?;
+    use_mfa_token(&params.mfa_token, cache.clone())
+        .await
+        .map_err(|_| ApiError::Unauthorized("Invalid MFA token".into()))?;
+
+    let block_pool = pool.clone();
+    throttled_code(cache.clone(), &req, &user.email, move || {
+        verify(&block_pool, user_id, &params.code)
+    })
+    .await?;
+
+    respond_json(complete_login(id, req, pool, Some(cache), user).await?)
+}
+
+/// Codes are throttled in the cache, and used up mfa_tokens are denylisted there
+fn require_cache(cache: Option<Cache>) -> Result<Cache, ApiError> {
+    cache.ok_or_else(|| ApiError::CacheError("A cache is required to verify MFA codes".into()))
+}
+
+/// Check an MFA code, counting a bad one as a failed login for the user's
+/// email and the client's IP address, see throttle
+async fn throttled_code<F, T>(
+    cache: Cache,
+    req: &HttpRequest,
+    email: &str,
+    check: F,
+) -> Result<T, ApiError>
+where
+    F: FnOnce() -> Result<T, ApiError> + Send + 'static,
+    T: Send + 'static,
+{
+    let ip = client_ip(req);
+    throttle::check(cache.clone(), email, ip.as_deref()).await?;
+
+    let checked = block(check).await.map_err(ApiError::from);
+    match &checked {
+        Ok(_) => throttle::record_success(cache, email).await?,
+        Err(ApiError::Unauthorized(_)) => {
+            throttle::record_failure(cache, email, ip.as_deref()).await?
+        }
+        Err(_) => {}
+    }
+    checked
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::auth::create_mfa_token;
+    use crate::config::CONFIG;
+    use crate::models::mfa::tests::enable_mfa;
+    use crate::tests::helpers::tests::{
+        get_auth_user, get_data_cache, get_data_pool, get_identity,
+    };
+    use crate::totp::{code_at, current_step};
+    use actix_web::test;
+
+    async fn verify_code(mfa_token: String, code: String) -> Result<Json<LoginResponse>, ApiError> {
+        let params = VerifyMfaRequest { mfa_token, code };
+        verify_mfa(
+            get_identity().await,
+            test::TestRequest::default().to_http_request(),
+            get_data_pool(),
+            Some(get_data_cache()),
+            Json(params),
+        )
+        .await
+    }
+
+    async fn disable_with(user: AuthUser, code: String) -> Result<HttpResponse, ApiError> {
+        disable_mfa(
+            user,
+            test::TestRequest::default().to_http_request(),
+            get_data_pool(),
+            Some(get_data_cache()),
+            Json(MfaCodeRequest { code }),
+        )
+        .await
+    }
+
+    #[actix_rt::test]
+    async fn it_enrolls_and_confirms_mfa() {
+        let user = get_auth_user("mfa", vec![], vec![]);
+        let enrolled = enroll_mfa(user.clone(), get_data_pool())
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(enrolled.otpauth_uri.contains(&enrolled.secret));
+
+        let params = MfaCodeRequest {
+            code: code_at(&enrolled.secret, current_step()).unwrap(),
+        };
+        let response = confirm_mfa(
+            user,
+            test::TestRequest::default().to_http_request(),
+            get_data_pool(),
+            Some(get_data_cache()),
+            Json(params),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.into_inner().recovery_codes.len(), 10);
+    }
+
+    #[actix_rt::test]
+    async fn it_verifies_mfa_to_complete_a_login() {
+        let user = get_auth_user("mfa", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        let (secret, _) = enable_mfa(user_id);
+        let mfa_token = create_mfa_token(user_id).unwrap();
+        let code = code_at(&secret, current_step()).unwrap();
+        let response = verify_code(mfa_token, code).await.unwrap();
+        assert_eq!(response.user.id, user_id);
+    }
+
+    #[actix_rt::test]
+    async fn it_verifies_mfa_with_a_recovery_code() {
+        let user = get_auth_user("mfa", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        let (_, recovery_codes) = enable_mfa(user_id);
+        let mfa_token = create_mfa_token(user_id).unwrap();
+        let response = verify_code(mfa_token, recovery_codes[0].clone()).await;
+        assert!(response.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_verify_mfa_with_an_invalid_code() {
+        let user = get_auth_user("mfa", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        enable_mfa(user_id);
+        let mfa_token = create_mfa_token(user_id).unwrap();
+        let response = verify_code(mfa_token, "not-a-code".into()).await;
+        assert!(response.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_verify_an_mfa_token_twice() {
+        let user = get_auth_user("mfa", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        let (_, recovery_codes) = enable_mfa(user_id);
+        let mfa_token = create_mfa_token(user_id).unwrap();
+        verify_code(mfa_token.clone(), recovery_codes[0].clone())
+            .await
+            .unwrap();
+        let response = verify_code(mfa_token, recovery_codes[1].clone()).await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::Unauthorized("Invalid MFA token".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_uses_up_an_mfa_token_with_an_invalid_code() {
+        let user = get_auth_user("mfa", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        let (_, recovery_codes) = enable_mfa(user_id);
+        let mfa_token = create_mfa_token(user_id).unwrap();
+        verify_code(mfa_token.clone(), "not-a-code".into())
+            .await
+            .unwrap_err();
+        let response = verify_code(mfa_token, recovery_codes[0].clone()).await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::Unauthorized("Invalid MFA token".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_locks_out_after_too_many_invalid_codes() {
+        let user = get_auth_user("mfa", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        let (_, recovery_codes) = enable_mfa(user_id);
+        for _ in 0..CONFIG.login_lockout_threshold {
+            let mfa_token = create_mfa_token(user_id).unwrap();
+            let response = verify_code(mfa_token, "not-a-code".into()).await;
+            assert!(response.is_err());
+        }
+        let mfa_token = create_mfa_token(user_id).unwrap();
+        let response = verify_code(mfa_token, recovery_codes[0].clone()).await;
+        assert!(matches!(
+            response.unwrap_err(),
+            ApiError::TooManyRequests(..)
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_verify_mfa_without_a_cache() {
+        let user = get_auth_user("mfa", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        let (_, recovery_codes) = enable_mfa(user_id);
+        let params = VerifyMfaRequest {
+            mfa_token: create_mfa_token(user_id).unwrap(),
+            code: recovery_codes[0].clone(),
+        };
+        let response = verify_mfa(
+            get_identity().await,
+            test::TestRequest::default().to_http_request(),
+            get_data_pool(),
+            None,
+            Json(params),
+        )
+        .await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::CacheError("A cache is required to verify MFA codes".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_disables_mfa() {
+        let user = get_auth_user("mfa", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        let (_, recovery_codes) = enable_mfa(user_id);
+        let response = disable_with(user, recovery_codes[0].clone()).await;
+        assert!(response.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn it_throttles_invalid_codes_when_disabling_mfa() {
+        let user = get_auth_user("mfa", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        let (_, recovery_codes) = enable_mfa(user_id);
+        for _ in 0..CONFIG.login_lockout_threshold {
+            let response = disable_with(user.clone(), "not-a-code".into()).await;
+            assert!(response.is_err());
+        }
+        let response = disable_with(user, recovery_codes[0].clone()).await;
+        assert!(matches!(
+            response.unwrap_err(),
+            ApiError::TooManyRequests(..)
+        ));
+    }
+}
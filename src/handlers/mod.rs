@@ -1,6 +1,17 @@
 //! Actix route handlers
 
+pub mod api_key;
+pub mod audit;
 pub mod auth;
+pub mod csrf;
 pub mod file;
 pub mod health;
+pub mod impersonation;
+pub mod jwks;
+pub mod me;
+pub mod mfa;
+pub mod oidc;
+pub mod password;
+pub mod session;
 pub mod user;
+pub mod verification;
@@ -0,0 +1,140 @@
+use crate::cache::Cache;
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::auth::{login_or_require_mfa, LoginStep};
+use crate::helpers::respond_json;
+use crate::models::mfa::is_enabled as is_mfa_enabled;
+use crate::models::user_identity::find_or_create_user;
+use crate::oidc::{begin, finish, state_cookie, OIDC_STATE_COOKIE};
+use actix_identity::Identity;
+use actix_web::{
+    http::header::LOCATION,
+    web::{block, Data, HttpRequest, HttpResponse, Json, Path, Query},
+    HttpMessage,
+};
+use serde::Serialize;
+
+/// The query string an OIDC provider redirects back with
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CallbackRequest {
+    pub code: Option<String>,
+    pub state: String,
+    pub error: Option<String>,
+}
+
+/// Start signing in with an OIDC provider
+/// Redirects the user to the provider, remembering the login's state in a cookie
+pub async fn oidc_login(
+    provider: Path<String>,
+    cache: Option<Cache>,
+) -> Result<HttpResponse, ApiError> {
+    let cache = cache
+        .ok_or_else(|| ApiError::CacheError("A cache is required to sign in with OIDC".into()))?;
+    let (url, state) = begin(cache, &provider).await?;
+    Ok(HttpResponse::Found()
+        .header(LOCATION, url)
+        .cookie(state_cookie(&state))
+        .finish())
+}
+
+/// Finish signing in with an OIDC provider, where it redirects back to
+///
+/// The provider's identity is linked to a user, see models::user_identity,
+/// and the user is logged in just as with a password.
+pub async fn oidc_callback(
+    id: Identity,
+    req: HttpRequest,
+    provider: Path<String>,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    params: Query<CallbackRequest>,
+) -> Result<Json<LoginStep>, ApiError> {
+    let cache = cache
+        .ok_or_else(|| ApiError::CacheError("A cache is required to sign in with OIDC".into()))?;
+    if let Some(error) = &params.error {
+        return Err(ApiError::Unauthorized(format!(
+            "OIDC login failed: {}",
+            error
+        )));
+    }
+    let code = params
+        .code
+        .as_ref()
+        .ok_or_else(|| ApiError::BadRequest("code is required".into()))?;
+
+    let cookie_state = req.cookie(OIDC_STATE_COOKIE);
+    let cookie_state = cookie_state.as_ref().map(|cookie| cookie.value());
+    let claims = finish(cache.clone(), &provider, code, &params.state, cookie_state).await?;
+    let block_pool = pool.clone();
+    let (user, mfa_enabled) = block(move || {
+        let user = find_or_create_user(
+            &block_pool,
+            &provider,
+            &claims,
+            CONFIG.email_verification_required,
+        )?;
+        let mfa_enabled = is_mfa_enabled(&block_pool, user.id)?;
+        Ok::<_, ApiError>((user, mfa_enabled))
+    })
+    .await?;
+
+    respond_json(login_or_require_mfa(id, req, pool, Some(cache), user, mfa_enabled).await?)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::{get_data_cache, get_data_pool, get_identity};
+    use actix_web::test;
+
+    #[actix_rt::test]
+    async fn it_doesnt_sign_in_with_an_unknown_provider() {
+        let response = oidc_login(Path::from("unknown".to_string()), Some(get_data_cache())).await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::NotFound("OIDC provider unknown not found".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_finish_signing_in_with_an_unknown_state() {
+        let params = CallbackRequest {
+            code: Some("code".into()),
+            state: "unknown".into(),
+            error: None,
+        };
+        let response = oidc_callback(
+            get_identity().await,
+            test::TestRequest::default().to_http_request(),
+            Path::from("unknown".to_string()),
+            get_data_pool(),
+            Some(get_data_cache()),
+            Query(params),
+        )
+        .await;
+        assert!(response.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_reports_an_error_from_the_provider() {
+        let params = CallbackRequest {
+            code: None,
+            state: "unknown".into(),
+            error: Some("access_denied".into()),
+        };
+        let response = oidc_callback(
+            get_identity().await,
+            test::TestRequest::default().to_http_request(),
+            Path::from("mock".to_string()),
+            get_data_pool(),
+            Some(get_data_cache()),
+            Query(params),
+        )
+        .await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::Unauthorized("OIDC login failed: access_denied".into())
+        );
+    }
+}
@@ -0,0 +1,390 @@
+use crate::audit::{Action, Audit};
+use crate::auth::{revoke_all_jwts, verify};
+use crate::cache::Cache;
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::{client_ip, respond_ok};
+use crate::mailer::{Email, MailerType};
+use crate::models::password_reset::{
+    create as create_password_reset, find_user as find_password_reset_user, redeem,
+};
+use crate::models::session::revoke_all as revoke_all_sessions;
+use crate::models::user::{
+    find, find_by_email, find_with_password, update_password, Actor, AuthUser,
+};
+use crate::throttle;
+use crate::validate::{validate, validate_password};
+use actix_identity::Identity;
+use actix_web::web::{block, Data, HttpRequest, HttpResponse, Json, Path};
+use log::error;
+use serde::Serialize;
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct ForgotPasswordRequest {
+    #[validate(email(message = "email must be a valid email"))]
+    pub email: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct ResetPasswordRequest {
+    #[validate(length(min = 1, message = "token is required"))]
+    pub token: String,
+
+    /// Checked against the password policy, see validate_password
+    #[validate(length(min = 1, message = "password is required"))]
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct ChangePasswordRequest {
+    #[validate(length(min = 1, message = "current_password is required"))]
+    pub current_password: String,
+
+    /// Checked against the password policy, see validate_password
+    #[validate(length(min = 1, message = "password is required"))]
+    pub password: String,
+}
+
+/// Email a password reset link to a user
+///
+/// Always responds with a 200, so the response doesn't reveal whether the
+/// email belongs to a user.  The lookup and email are sent off the request
+/// path, so neither does the response time.  Failures are logged instead.
+pub async fn forgot_password(
+    pool: Data<PoolType>,
+    mailer: MailerType,
+    params: Json<ForgotPasswordRequest>,
+) -> Result<HttpResponse, ApiError> {
+    validate(&params)?;
+
+    actix_rt::spawn(async move {
+        block(move || send_password_reset(&pool, &mailer, &params.email))
+            .await
+            .unwrap_or_else(|e| error!("Cannot send a password reset email: {}", e));
+    });
+
+    respond_ok()
+}
+
+/// Create a password reset token and email it, if the email belongs to a user
+pub fn send_password_reset(
+    pool: &PoolType,
+    mailer: &MailerType,
+    email: &str,
+) -> Result<(), ApiError> {
+    let user = match find_by_email(pool, email) {
+        Ok(user) => user,
+        Err(_) => return Ok(()),
+    };
+    let token = create_password_reset(pool, user.id)?;
+    mailer.send(password_reset_email(user.email, &token))
+}
+
+/// Reset a user's password with a token from a password reset email
+/// Logs the user out everywhere
+///
+/// The token is only used up once the new password passes the policy.
+pub async fn reset_password(
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    params: Json<ResetPasswordRequest>,
+) -> Result<HttpResponse, ApiError> {
+    validate(&params)?;
+
+    let block_pool = pool.clone();
+    let user_id = block(move || {
+        let user = find(
+            &block_pool,
+            find_password_reset_user(&block_pool, &params.token)?,
+        )?;
+        validate_password(
+            &params.password,
+            &[&user.email, &user.first_name, &user.last_name],
+        )?;
+        let user_id = redeem(&block_pool, &params.token)?;
+        update_password(&block_pool, user_id, &params.password, &user_id.into())?;
+        revoke_all_sessions(&block_pool, user_id)?;
+        Ok::<_, ApiError>(user_id)
+    })
+    .await?;
+    Audit::new(Action::PasswordReset, &req)
+        .actor_id(user_id)
+        .target(user_id)
+        .record(pool)
+        .await;
+
+    if let Some(cache) = cache {
+        revoke_all_jwts(cache, user_id).await?;
+    }
+
+    respond_ok()
+}
+
+/// Change the logged in user's password
+/// The current password is verified, and the user is logged out everywhere
+///
+/// Wrong current passwords are throttled like failed logins, see throttle.
+pub async fn change_password(
+    id: Identity,
+    user: AuthUser,
+    user_id: Path<Uuid>,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+    params: Json<ChangePasswordRequest>,
+) -> Result<HttpResponse, ApiError> {
+    validate(&params)?;
+    if user.id != user_id.to_string() {
+        return Err(ApiError::Forbidden(
+            "Cannot change another user's password".into(),
+        ));
+    }
+    if user.impersonator.is_some() {
+        return Err(ApiError::Forbidden(
+            "Cannot change a password while impersonating".into(),
+        ));
+    }
+
+    let user_id = *user_id;
+    let actor = Actor::from(&user);
+    let ip = client_ip(&req);
+    if let Some(cache) = cache.clone() {
+        throttle::check(cache, &user.email, ip.as_deref()).await?;
+    }
+
+    // Check the current password by id, emails aren't unique
+    let block_pool = pool.clone();
+    let current_password = params.current_password.clone();
+    let (found, verified) = block(move || {
+        let found = find_with_password(&block_pool, user_id)?;
+        let verified = verify(&current_password, &found.password);
+        Ok::<_, ApiError>((found, verified))
+    })
+    .await?;
+    if let Some(cache) = cache.clone() {
+        if verified {
+            throttle::record_success(cache, &found.email).await?;
+        } else {
+            throttle::record_failure(cache, &found.email, ip.as_deref()).await?;
+        }
+    }
+    if !verified {
+        return Err(ApiError::ValidationError(vec![
+            "current_password is incorrect".into(),
+        ]));
+    }
+
+    let block_pool = pool.clone();
+    block(move || {
+        validate_password(
+            &params.password,
+            &[&found.email, &found.first_name, &found.last_name],
+        )?;
+        update_password(&block_pool, user_id, &params.password, &actor)?;
+        revoke_all_sessions(&block_pool, user_id)
+    })
+    .await?;
+    Audit::new(Action::PasswordChanged, &req)
+        .actor(&user)
+        .target(user_id)
+        .record(pool)
+        .await;
+
+    if let Some(cache) = cache {
+        revoke_all_jwts(cache, user_id).await?;
+    }
+    id.forget();
+    respond_ok()
+}
+
+fn password_reset_email(to: String, token: &str) -> Email {
+    Email {
+        to,
+        subject: "Reset your password".into(),
+        body: format!(
+            "Use this link to reset your password within {} minutes:\n\n{}?token={}",
+            CONFIG.password_reset_expiration, CONFIG.password_reset_url, token
+        ),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::mailer::{Mailer, MemoryMailer};
+    use crate::models::user::find_by_auth;
+    use crate::models::user::tests::create_user_with_email;
+    use crate::tests::helpers::tests::{
+        get_auth_user, get_data_cache, get_data_pool, get_identity, get_pool,
+    };
+    use actix_web::test;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    async fn forgot(mailer: Arc<MemoryMailer>, email: &str) -> Result<HttpResponse, ApiError> {
+        let params = ForgotPasswordRequest {
+            email: email.into(),
+        };
+        forgot_password(
+            get_data_pool(),
+            Data::from(mailer as Arc<dyn Mailer>),
+            Json(params),
+        )
+        .await
+    }
+
+    async fn reset(token: &str, password: &str) -> Result<HttpResponse, ApiError> {
+        let params = ResetPasswordRequest {
+            token: token.into(),
+            password: password.into(),
+        };
+        reset_password(
+            test::TestRequest::default().to_http_request(),
+            get_data_pool(),
+            Some(get_data_cache()),
+            Json(params),
+        )
+        .await
+    }
+
+    /// Wait for the emails sent off the request path
+    async fn sent(mailer: &MemoryMailer) -> Vec<Email> {
+        for _ in 0..100 {
+            if !mailer.emails().is_empty() {
+                break;
+            }
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        }
+        mailer.emails()
+    }
+
+    /// Request a password reset for a new user, returning their email and token
+    async fn forgot_new_user() -> (String, String) {
+        let email = format!("reset-{}@nothing.org", Uuid::new_v4());
+        create_user_with_email(&email).unwrap();
+        let mailer = Arc::new(MemoryMailer::default());
+        forgot(mailer.clone(), &email).await.unwrap();
+        let body = sent(&mailer).await[0].body.clone();
+        let token = body.rsplit("token=").next().unwrap().to_string();
+        (email, token)
+    }
+
+    #[actix_rt::test]
+    async fn it_emails_a_password_reset_link() {
+        let email = format!("reset-{}@nothing.org", Uuid::new_v4());
+        create_user_with_email(&email).unwrap();
+        let mailer = Arc::new(MemoryMailer::default());
+        let response = forgot(mailer.clone(), &email).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let emails = sent(&mailer).await;
+        assert_eq!(emails.len(), 1);
+        assert_eq!(emails[0].to, email);
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_reveal_an_unknown_email() {
+        let mailer = Arc::new(MemoryMailer::default());
+        let response = forgot(mailer.clone(), "nobody@nothing.org").await.unwrap();
+        assert_eq!(response.status(), 200);
+        let mailer_type = Data::from(mailer.clone() as Arc<dyn Mailer>);
+        send_password_reset(&get_pool(), &mailer_type, "nobody@nothing.org").unwrap();
+        assert!(mailer.emails().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn it_resets_a_password() {
+        let (email, token) = forgot_new_user().await;
+        let response = reset(&token, "Genesis-Block-1").await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(find_by_auth(&get_pool(), &email, "Genesis-Block-1", false).is_ok());
+        assert!(find_by_auth(&get_pool(), &email, "123456", false).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_reset_a_password_twice_with_the_same_token() {
+        let (_, token) = forgot_new_user().await;
+        reset(&token, "Genesis-Block-1").await.unwrap();
+        let response = reset(&token, "Genesis-Block-2").await;
+        assert!(response.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_keeps_the_token_when_the_new_password_is_too_weak() {
+        let (_, token) = forgot_new_user().await;
+        let response = reset(&token, "password").await;
+        assert!(matches!(response, Err(ApiError::ValidationError(_))));
+        assert!(reset(&token, "Genesis-Block-1").await.is_ok());
+    }
+
+    async fn change(
+        user: AuthUser,
+        user_id: Uuid,
+        current_password: &str,
+        password: &str,
+    ) -> Result<HttpResponse, ApiError> {
+        let params = ChangePasswordRequest {
+            current_password: current_password.into(),
+            password: password.into(),
+        };
+        change_password(
+            get_identity().await,
+            user,
+            user_id.into(),
+            test::TestRequest::default().to_http_request(),
+            get_data_pool(),
+            Some(get_data_cache()),
+            Json(params),
+        )
+        .await
+    }
+
+    #[actix_rt::test]
+    async fn it_changes_a_password() {
+        let user = get_auth_user("change", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        let email = user.email.clone();
+        let response = change(user, user_id, "123456", "Genesis-Block-1")
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(find_by_auth(&get_pool(), &email, "Genesis-Block-1", false).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_change_a_password_without_the_current_one() {
+        let user = get_auth_user("change", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        let response = change(user, user_id, "wrong-password", "Genesis-Block-1").await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::ValidationError(vec!["current_password is incorrect".into()])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_change_another_users_password() {
+        let user = get_auth_user("change", vec![], vec![]);
+        let response = change(user, Uuid::new_v4(), "123456", "Genesis-Block-1").await;
+        assert!(matches!(response, Err(ApiError::Forbidden(_))));
+    }
+
+    #[actix_rt::test]
+    async fn it_throttles_wrong_current_passwords() {
+        let user = get_auth_user("change", vec![], vec![]);
+        let user_id = Uuid::parse_str(&user.id).unwrap();
+        for _ in 0..CONFIG.login_backoff_threshold {
+            let response = change(user.clone(), user_id, "wrong-password", "Genesis-Block-1").await;
+            assert!(matches!(response, Err(ApiError::ValidationError(_))));
+        }
+
+        // Even the correct password is rejected while throttled
+        match change(user, user_id, "123456", "Genesis-Block-1").await {
+            Err(ApiError::TooManyRequests(_, retry_after)) => assert!(retry_after > 0),
+            _ => panic!("expected the email to be throttled"),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use crate::audit::{Action, Audit};
+use crate::auth::{decode_jwt, get_token, revoke_all_jwts, revoke_session};
+use crate::cache::Cache;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::{respond_json, respond_ok};
+use crate::models::session::{find_all, revoke, revoke_all, Session};
+use crate::models::user::AuthUser;
+use actix_web::web::{block, Data, HttpRequest, HttpResponse, Json, Path};
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::json;
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct SessionResponse {
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub current: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub struct SessionsResponse(pub Vec<SessionResponse>);
+
+/// Get all of the logged in user's active sessions
+/// The session of the JWT used for this request is flagged as current
+pub async fn get_sessions(
+    user: AuthUser,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+) -> Result<Json<SessionsResponse>, ApiError> {
+    let user_id = Uuid::parse_str(&user.id)?;
+    let current = get_token(&req)
+        .and_then(|token| decode_jwt(&token).ok())
+        .and_then(|private_claim| private_claim.session_id);
+    let sessions = block(move || find_all(&pool, user_id)).await?;
+    let sessions = sessions
+        .into_iter()
+        .map(|session| {
+            let mut response: SessionResponse = session.into();
+            response.current = Some(response.id) == current;
+            response
+        })
+        .collect();
+    respond_json(SessionsResponse(sessions))
+}
+
+/// Revoke one of the logged in user's sessions
+/// Its refresh tokens are revoked, as are its JWTs when there's a cache
+pub async fn delete_session(
+    user: AuthUser,
+    session_id: Path<Uuid>,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = Uuid::parse_str(&user.id)?;
+    let session_id = *session_id;
+    let block_pool = pool.clone();
+    block(move || revoke(&block_pool, user_id, session_id)).await?;
+    if let Some(cache) = cache {
+        revoke_session(cache, session_id).await?;
+    }
+    Audit::new(Action::SessionRevoked, &req)
+        .actor(&user)
+        .target(user_id)
+        .metadata(json!({ "session_id": session_id }))
+        .record(pool)
+        .await;
+    respond_ok()
+}
+
+/// Revoke all of the logged in user's sessions, including this one
+pub async fn delete_sessions(
+    user: AuthUser,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = Uuid::parse_str(&user.id)?;
+    let block_pool = pool.clone();
+    block(move || revoke_all(&block_pool, user_id)).await?;
+    if let Some(cache) = cache {
+        revoke_all_jwts(cache, user_id).await?;
+    }
+    Audit::new(Action::LogoutAll, &req)
+        .actor(&user)
+        .record(pool)
+        .await;
+    respond_ok()
+}
+
+impl From<Session> for SessionResponse {
+    fn from(session: Session) -> Self {
+        SessionResponse {
+            id: Uuid::parse_str(&session.id).unwrap(),
+            user_agent: session.user_agent,
+            ip: session.ip,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            current: false,
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::auth::verify_jwt;
+    use crate::handlers::auth::tests::login_user;
+    use crate::tests::helpers::tests::{get_data_cache, get_data_pool};
+    use actix_web::{http::header::AUTHORIZATION, test};
+
+    fn get_request(token: &str) -> HttpRequest {
+        test::TestRequest::default()
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .to_http_request()
+    }
+
+    fn get_auth_user(token: &str) -> AuthUser {
+        decode_jwt(token).unwrap().into()
+    }
+
+    async fn login() -> String {
+        let login = login_user().await.unwrap();
+        login.token.expect("sessions are tested with bearer tokens")
+    }
+
+    #[actix_rt::test]
+    async fn it_lists_sessions_with_the_current_one_flagged() {
+        let token = login().await;
+        let response = get_sessions(get_auth_user(&token), get_request(&token), get_data_pool())
+            .await
+            .unwrap()
+            .into_inner();
+        let current = decode_jwt(&token).unwrap().session_id.unwrap();
+        let session = response.0.iter().find(|session| session.id == current);
+        assert!(session.unwrap().current);
+    }
+
+    #[actix_rt::test]
+    async fn it_revokes_a_session() {
+        let token = login().await;
+        let session_id = decode_jwt(&token).unwrap().session_id.unwrap();
+        let cache = get_data_cache();
+        delete_session(
+            get_auth_user(&token),
+            session_id.into(),
+            get_request(&token),
+            get_data_pool(),
+            Some(cache.clone()),
+        )
+        .await
+        .unwrap();
+        assert!(verify_jwt(&token, Some(cache)).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_revoke_an_unknown_session() {
+        let token = login().await;
+        let session_id = Uuid::new_v4();
+        let response = delete_session(
+            get_auth_user(&token),
+            session_id.into(),
+            get_request(&token),
+            get_data_pool(),
+            Some(get_data_cache()),
+        )
+        .await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::NotFound(format!("Session {} not found", session_id))
+        );
+    }
+}
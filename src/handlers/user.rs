@@ -1,16 +1,26 @@
-use crate::config::CONFIG;
+use crate::audit::{Action, Audit};
+use crate::auth::revoke_all_jwts;
+use crate::cache::Cache;
 use crate::database::PoolType;
 use crate::errors::ApiError;
-use crate::helpers::{respond_json, respond_ok};
-use crate::models::user::{create, delete, find, get_all, update, NewUser, UpdateUser, User};
-use crate::pagination::{PaginationRequest, PaginationResponse};
-use crate::validate::validate;
+use crate::handlers::verification::send_verification_email;
+use crate::helpers::{get_base, respond_json, respond_ok};
+use crate::listing::ListRequest;
+use crate::mailer::MailerType;
+use crate::models::user::{
+    create, delete, find, get_all, get_all_by_cursor, update, Actor, AuthUser, NewUser, UpdateUser,
+    User, USER_LIST,
+};
+use crate::pagination::{CursorResponse, PaginationResponse};
+use crate::throttle;
+use crate::validate::{validate, validate_password};
 use actix_web::{
-    http::header::HeaderValue,
-    web::{block, Data, HttpRequest, HttpResponse, Json, Path, Query},
+    web::{block, Data, HttpRequest, HttpResponse, Json, Path},
+    Either,
 };
 use rayon::prelude::*;
 use serde::Serialize;
+use serde_json::json;
 use uuid::Uuid;
 use validator::Validate;
 
@@ -42,10 +52,8 @@ pub struct CreateUserRequest {
     #[validate(email(message = "email must be a valid email"))]
     pub email: String,
 
-    #[validate(length(
-        min = 6,
-        message = "password is required and must be at least 6 characters"
-    ))]
+    /// Checked against the password policy, see validate_password
+    #[validate(length(min = 1, message = "password is required"))]
     pub password: String,
 }
 
@@ -76,83 +84,151 @@ pub async fn get_user(
     respond_json(user)
 }
 
-fn get_base(req: HttpRequest) -> String {
-    let scheme = req.uri().scheme_str().unwrap_or("http");
-    let default_host = HeaderValue::from_static(&CONFIG.server);
-    let host = req
-        .head()
-        .headers()
-        .get("host")
-        .unwrap_or(&default_host)
-        .to_str()
-        .unwrap_or("");
-    let path = req.path();
-
-    format!("{}://{}{}", scheme, host, path)
-}
-
 /// Get all users
+/// Filter, search and sort with the query string, see listing
+/// Paginated by page, or by cursor when a cursor is sent
 pub async fn get_users(
     req: HttpRequest,
-    params: Query<PaginationRequest>,
     pool: Data<PoolType>,
-) -> Result<Json<PaginationResponse<UsersResponse>>, ApiError> {
-    let base = get_base(req);
-    let users = block(move || get_all(&pool, params.into_inner(), base)).await?;
-    respond_json(users)
+) -> Result<Either<PaginationResponse<UsersResponse>, CursorResponse<UsersResponse>>, ApiError> {
+    let params = ListRequest::from_query(req.query_string(), &USER_LIST)?;
+    let base = params.link_base(get_base(req));
+    if params.seek.is_some() {
+        let users = block(move || get_all_by_cursor(&pool, params, base)).await?;
+        return Ok(Either::B(users));
+    }
+    let users = block(move || get_all(&pool, params, base)).await?;
+    Ok(Either::A(users))
 }
 
 /// Create a user
+/// Anyone can sign up, the system is the actor when nobody is logged in
 pub async fn create_user(
+    user: Option<AuthUser>,
+    req: HttpRequest,
     pool: Data<PoolType>,
+    mailer: MailerType,
     params: Json<CreateUserRequest>,
 ) -> Result<Json<UserResponse>, ApiError> {
     validate(&params)?;
+    validate_password(
+        &params.password,
+        &[&params.email, &params.first_name, &params.last_name],
+    )?;
 
-    // temporarily use the new user's id for created_at/updated_at
-    // update when auth is added
-    let user_id = Uuid::new_v4();
+    let actor = user.as_ref().map(Actor::from).unwrap_or(Actor::System);
     let new_user: User = NewUser {
-        id: user_id.to_string(),
+        id: Uuid::new_v4().to_string(),
         first_name: params.first_name.to_string(),
         last_name: params.last_name.to_string(),
         email: params.email.to_string(),
         password: params.password.to_string(),
-        created_by: user_id.to_string(),
-        updated_by: user_id.to_string(),
+        created_by: actor.id(),
+        updated_by: actor.id(),
     }
     .into();
-    let user = block(move || create(&pool, &new_user)).await?;
-    respond_json(user.into())
+    let block_pool = pool.clone();
+    let created = block(move || {
+        let created = create(&block_pool, &new_user)?;
+        send_verification_email(&block_pool, &mailer, created.id, &created.email)?;
+        Ok::<_, ApiError>(created)
+    })
+    .await?;
+    let mut audit = Audit::new(Action::UserCreated, &req)
+        .target(created.id)
+        .metadata(json!({ "email": created.email }));
+    if let Some(user) = &user {
+        audit = audit.actor(user);
+    }
+    audit.record(pool).await;
+    respond_json(created.into())
 }
 
 /// Update a user
+/// Users can update themselves, anyone else requires the user:update permission
+/// A new email must be verified again, so a verification email is sent to it
 pub async fn update_user(
+    user: AuthUser,
     user_id: Path<Uuid>,
+    req: HttpRequest,
     pool: Data<PoolType>,
+    mailer: MailerType,
     params: Json<UpdateUserRequest>,
 ) -> Result<Json<UserResponse>, ApiError> {
     validate(&params)?;
+    if user.id != user_id.to_string() && !user.has_permission("user:update") {
+        return Err(ApiError::Forbidden("Cannot update another user".into()));
+    }
 
-    // temporarily use the user's id for updated_at
-    // update when auth is added
     let update_user = UpdateUser {
         id: user_id.to_string(),
         first_name: params.first_name.to_string(),
         last_name: params.last_name.to_string(),
         email: params.email.to_string(),
-        updated_by: user_id.to_string(),
+        updated_by: Actor::from(&user).id(),
     };
-    let user = block(move || update(&pool, &update_user)).await?;
-    respond_json(user.into())
+    let block_pool = pool.clone();
+    let updated = block(move || {
+        let current = find(&block_pool, Uuid::parse_str(&update_user.id)?)?;
+        let updated = update(&block_pool, &update_user)?;
+        if updated.email != current.email {
+            send_verification_email(&block_pool, &mailer, updated.id, &updated.email)?;
+        }
+        Ok::<_, ApiError>(updated)
+    })
+    .await?;
+    Audit::new(Action::UserUpdated, &req)
+        .actor(&user)
+        .target(updated.id)
+        .metadata(json!({ "email": updated.email }))
+        .record(pool)
+        .await;
+    respond_json(updated.into())
 }
 
 /// Delete a user
+/// Their JWTs are revoked too, when there's a cache
 pub async fn delete_user(
+    user: AuthUser,
     user_id: Path<Uuid>,
+    req: HttpRequest,
     pool: Data<PoolType>,
+    cache: Option<Cache>,
 ) -> Result<HttpResponse, ApiError> {
-    block(move || delete(&pool, *user_id)).await?;
+    let user_id = *user_id;
+    let block_pool = pool.clone();
+    block(move || delete(&block_pool, user_id)).await?;
+    if let Some(cache) = cache {
+        revoke_all_jwts(cache, user_id).await?;
+    }
+    Audit::new(Action::UserDeleted, &req)
+        .actor(&user)
+        .target(user_id)
+        .record(pool)
+        .await;
+    respond_ok()
+}
+
+/// Unlock a user's account after too many failed logins
+pub async fn unlock_user(
+    user: AuthUser,
+    user_id: Path<Uuid>,
+    req: HttpRequest,
+    pool: Data<PoolType>,
+    cache: Option<Cache>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = *user_id;
+    let block_pool = pool.clone();
+    let locked = block(move || find(&block_pool, user_id)).await?;
+    if let Some(cache) = cache {
+        throttle::unlock(cache, &locked.email).await?;
+    }
+    Audit::new(Action::UserUnlocked, &req)
+        .actor(&user)
+        .target(user_id)
+        .metadata(json!({ "email": locked.email }))
+        .record(pool)
+        .await;
     respond_ok()
 }
 
@@ -176,23 +252,43 @@ impl From<Vec<User>> for UsersResponse {
 #[cfg(test)]
 pub mod tests {
     use super::*;
-    use crate::models::user::tests::create_user as model_create_user;
+    use crate::auth::{create_jwt, verify_jwt, PrivateClaim};
+    use crate::config::CONFIG;
+    use crate::mailer::{Mailer, MemoryMailer};
+    use crate::models::user::tests::{create_user as model_create_user, create_user_with_email};
+    use crate::models::user::{find_by_auth, mark_email_verified, SYSTEM_ACTOR};
     use crate::tests::helpers::tests::{
-        get_data_pool, get_pagination_params, get_pool, get_query_pagination_params,
-        mock_get_request,
+        get_auth_user, get_data_cache, get_data_mailer, get_data_pool, get_pagination_params,
+        get_pool, mock_get_request,
     };
+    use actix_web::test;
+    use std::sync::Arc;
 
     pub fn get_all_users() -> PaginationResponse<UsersResponse> {
         let pool = get_pool();
         let params = get_pagination_params();
         let base = "http://fake/api/v1/user";
-        get_all(&pool, params, base.into()).unwrap()
+        get_all(&pool, params.into(), base.into()).unwrap()
     }
 
     pub fn get_first_users_id() -> Uuid {
         get_all_users().data.0[0].id
     }
 
+    fn get_admin() -> AuthUser {
+        AuthUser {
+            id: get_first_users_id().to_string(),
+            email: "satoshi@nakamotoinstitute.org".into(),
+            roles: vec!["admin".into()],
+            permissions: vec!["user:delete".into(), "user:unlock".into()],
+            impersonator: None,
+        }
+    }
+
+    fn get_request() -> HttpRequest {
+        test::TestRequest::default().to_http_request()
+    }
+
     #[actix_rt::test]
     async fn it_gets_a_user() {
         let first_user = &get_all_users().data.0[0];
@@ -211,14 +307,112 @@ pub mod tests {
         assert_eq!(response.unwrap_err(), expected_error);
     }
 
+    async fn list_users(uri: &str) -> PaginationResponse<UsersResponse> {
+        match get_users(mock_get_request(uri), get_data_pool())
+            .await
+            .unwrap()
+        {
+            Either::A(users) => users,
+            Either::B(_) => panic!("Expected users paginated by page"),
+        }
+    }
+
+    async fn list_users_by_cursor(uri: &str) -> CursorResponse<UsersResponse> {
+        match get_users(mock_get_request(uri), get_data_pool())
+            .await
+            .unwrap()
+        {
+            Either::A(_) => panic!("Expected users paginated by cursor"),
+            Either::B(users) => users,
+        }
+    }
+
+    /// The path and query of a pagination link
+    fn link_uri(link: &Option<String>) -> &str {
+        let link = link.as_ref().unwrap();
+        &link[link.find("/api/v1").unwrap()..]
+    }
+
     #[actix_rt::test]
     async fn it_gets_all_users() {
-        let request = mock_get_request("/api/v1/user");
-        let response = get_users(request, get_query_pagination_params(), get_data_pool()).await;
-        assert!(response.is_ok());
+        let response = list_users("/api/v1/user?page=1&per_page=10").await;
+        assert_eq!(response.data.0[0], get_all_users().data.0[0]);
+    }
+
+    #[actix_rt::test]
+    async fn it_keeps_the_filters_in_the_links() {
+        let response =
+            list_users("/api/v1/user?filter%5Blast_name%5D=Test&sort=email&page=2").await;
+        assert!(response
+            .links
+            .first
+            .ends_with("/api/v1/user?filter%5Blast_name%5D=Test&sort=email&page=1&per_page=10"));
+    }
+
+    #[actix_rt::test]
+    async fn it_links_an_empty_list_to_valid_pages() {
+        let uri = format!("/api/v1/user?q=nobody-{}", Uuid::new_v4());
+        let response = list_users(&uri).await;
+        assert!(response.data.0.is_empty());
+        assert_eq!(response.pagination.total, 0);
+        let last = list_users(link_uri(&Some(response.links.last))).await;
+        assert!(last.data.0.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn it_gets_users_by_cursor() {
+        let prefix = format!("cursor-{}", Uuid::new_v4());
+        for n in 1..=3 {
+            create_user_with_email(&format!("{}-{}@nothing.org", prefix, n)).unwrap();
+        }
+        let emails = |users: &UsersResponse| -> Vec<String> {
+            users.0.iter().map(|user| user.email.clone()).collect()
+        };
+
+        let uri = format!("/api/v1/user?q={}&sort=email&cursor=&per_page=2", prefix);
+        let first = list_users_by_cursor(&uri).await;
+        assert_eq!(first.pagination.total, None);
+        assert_eq!(first.links.prev, None);
         assert_eq!(
-            response.unwrap().into_inner().data.0[0],
-            get_all_users().data.0[0]
+            emails(&first.data),
+            vec![
+                format!("{}-1@nothing.org", prefix),
+                format!("{}-2@nothing.org", prefix)
+            ]
+        );
+
+        let second = list_users_by_cursor(link_uri(&first.links.next)).await;
+        assert_eq!(
+            emails(&second.data),
+            vec![format!("{}-3@nothing.org", prefix)]
+        );
+        assert_eq!(second.links.next, None);
+
+        let back = list_users_by_cursor(link_uri(&second.links.prev)).await;
+        assert_eq!(emails(&back.data), emails(&first.data));
+        assert_eq!(back.links.prev, None);
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_list_users_out_of_range() {
+        let request = mock_get_request("/api/v1/user?page=0&per_page=0");
+        let response = get_users(request, get_data_pool()).await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::ValidationError(vec![
+                "page must be at least 1".into(),
+                format!("per_page must be between 1 and {}", CONFIG.max_per_page),
+            ])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_list_users_by_an_unknown_field() {
+        let request = mock_get_request("/api/v1/user?sort=password");
+        let response = get_users(request, get_data_pool()).await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::ValidationError(vec!["sort by password is not supported".into()])
         );
     }
 
@@ -228,14 +422,62 @@ pub mod tests {
             first_name: "Satoshi".into(),
             last_name: "Nakamoto".into(),
             email: "satoshi@nakamotoinstitute.org".into(),
-            password: "123456".into(),
+            password: "Bitcoin-2009".into(),
         });
-        let response = create_user(get_data_pool(), Json(params.clone()))
-            .await
-            .unwrap();
+        let response = create_user(
+            Some(get_admin()),
+            get_request(),
+            get_data_pool(),
+            get_data_mailer(),
+            Json(params.clone()),
+        )
+        .await
+        .unwrap();
         assert_eq!(response.into_inner().first_name, params.first_name);
     }
 
+    /// Create a user, returning the stored row
+    async fn create_user_as(actor: Option<AuthUser>) -> User {
+        use crate::schema::users::dsl::{id, users};
+        use diesel::prelude::*;
+
+        let params = CreateUserRequest {
+            first_name: "Satoshi".into(),
+            last_name: "Nakamoto".into(),
+            email: format!("created-by-{}@nothing.org", Uuid::new_v4()),
+            password: "Bitcoin-2009".into(),
+        };
+        let response = create_user(
+            actor,
+            get_request(),
+            get_data_pool(),
+            get_data_mailer(),
+            Json(params),
+        )
+        .await
+        .unwrap();
+        let conn = get_pool().get().unwrap();
+        users
+            .filter(id.eq(response.into_inner().id.to_string()))
+            .first::<User>(&conn)
+            .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn it_records_who_created_a_user() {
+        let actor = get_admin();
+        let created = create_user_as(Some(actor.clone())).await;
+        assert_eq!(created.created_by, actor.id);
+        assert_eq!(created.updated_by, actor.id);
+    }
+
+    #[actix_rt::test]
+    async fn it_records_the_system_as_creating_a_signup() {
+        let created = create_user_as(None).await;
+        assert_eq!(created.created_by, SYSTEM_ACTOR);
+        assert_eq!(created.updated_by, SYSTEM_ACTOR);
+    }
+
     #[actix_rt::test]
     async fn it_updates_a_user() {
         let first_user = &get_all_users().data.0[0];
@@ -245,12 +487,85 @@ pub mod tests {
             last_name: first_user.last_name.clone(),
             email: first_user.email.clone(),
         });
-        let response = update_user(user_id, get_data_pool(), Json(params.clone()))
-            .await
-            .unwrap();
+        let response = update_user(
+            get_admin(),
+            user_id,
+            get_request(),
+            get_data_pool(),
+            get_data_mailer(),
+            Json(params.clone()),
+        )
+        .await
+        .unwrap();
         assert_eq!(response.into_inner().first_name, params.first_name);
     }
 
+    async fn update_another_user(permissions: Vec<String>) -> Result<UserResponse, ApiError> {
+        let other =
+            create_user_with_email(&format!("other-{}@nothing.org", Uuid::new_v4())).unwrap();
+        let user = get_auth_user("actor", vec![], permissions);
+        let params = UpdateUserRequest {
+            first_name: "Hal".into(),
+            last_name: other.last_name,
+            email: other.email,
+        };
+        update_user(
+            user,
+            other.id.into(),
+            get_request(),
+            get_data_pool(),
+            get_data_mailer(),
+            Json(params),
+        )
+        .await
+        .map(|response| response.into_inner())
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_update_another_user_without_permission() {
+        let response = update_another_user(vec![]).await;
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::Forbidden("Cannot update another user".into())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn it_updates_another_user_with_permission() {
+        let response = update_another_user(vec!["user:update".into()]).await;
+        assert_eq!(response.unwrap().first_name, "Hal");
+    }
+
+    #[actix_rt::test]
+    async fn it_reverifies_a_changed_email() {
+        let user =
+            create_user_with_email(&format!("changed-{}@nothing.org", Uuid::new_v4())).unwrap();
+        mark_email_verified(&get_pool(), user.id).unwrap();
+        let email = format!("changed-{}@nothing.org", Uuid::new_v4());
+        let mailer = Arc::new(MemoryMailer::default());
+        let actor = get_auth_user("actor", vec![], vec!["user:update".into()]);
+        update_user(
+            actor,
+            user.id.into(),
+            get_request(),
+            get_data_pool(),
+            Data::from(mailer.clone() as Arc<dyn Mailer>),
+            Json(UpdateUserRequest {
+                first_name: user.first_name,
+                last_name: user.last_name,
+                email: email.clone(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            find_by_auth(&get_pool(), &email, "123456", true).unwrap_err(),
+            ApiError::EmailNotVerified("Email not verified".into())
+        );
+        assert_eq!(mailer.emails().len(), 1);
+        assert_eq!(mailer.emails()[0].to, email);
+    }
+
     #[actix_rt::test]
     async fn it_deletes_a_user() {
         let created = model_create_user();
@@ -258,8 +573,56 @@ pub mod tests {
         let user_id_path: Path<Uuid> = user_id.into();
         let user = find(&get_pool(), user_id);
         assert!(user.is_ok());
-        delete_user(user_id_path, get_data_pool()).await.unwrap();
+        delete_user(
+            get_admin(),
+            user_id_path,
+            get_request(),
+            get_data_pool(),
+            Some(get_data_cache()),
+        )
+        .await
+        .unwrap();
         let user = find(&get_pool(), user_id);
         assert!(user.is_err());
     }
+
+    #[actix_rt::test]
+    async fn it_revokes_a_deleted_users_tokens() {
+        let user = model_create_user().unwrap();
+        let jwt = create_jwt(PrivateClaim::new(user.id, user.email)).unwrap();
+        delete_user(
+            get_admin(),
+            user.id.into(),
+            get_request(),
+            get_data_pool(),
+            Some(get_data_cache()),
+        )
+        .await
+        .unwrap();
+        assert!(verify_jwt(&jwt, Some(get_data_cache())).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn it_unlocks_a_user() {
+        let email = format!("unlock-{}@nothing.org", Uuid::new_v4());
+        let user = create_user_with_email(&email).unwrap();
+        let cache = get_data_cache();
+        for _ in 0..CONFIG.login_lockout_threshold {
+            throttle::record_failure(cache.clone(), &email, None)
+                .await
+                .unwrap();
+        }
+        assert!(throttle::check(cache.clone(), &email, None).await.is_err());
+
+        unlock_user(
+            get_admin(),
+            user.id.into(),
+            get_request(),
+            get_data_pool(),
+            Some(cache.clone()),
+        )
+        .await
+        .unwrap();
+        assert!(throttle::check(cache, &email, None).await.is_ok());
+    }
 }
@@ -0,0 +1,95 @@
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::helpers::respond_ok;
+use crate::mailer::{Email, MailerType};
+use crate::models::email_verification::{create as create_email_verification, redeem};
+use crate::models::user::mark_email_verified;
+use crate::validate::validate;
+use actix_web::web::{block, Data, HttpResponse, Query};
+use log::error;
+use serde::Serialize;
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Clone, Debug, Deserialize, Serialize, Validate)]
+pub struct VerifyEmailRequest {
+    #[validate(length(min = 1, message = "token is required"))]
+    pub token: String,
+}
+
+/// Verify a user's email with the token from their verification email
+pub async fn verify_email(
+    pool: Data<PoolType>,
+    params: Query<VerifyEmailRequest>,
+) -> Result<HttpResponse, ApiError> {
+    validate(&params)?;
+
+    block(move || {
+        let user_id = redeem(&pool, &params.token)?;
+        mark_email_verified(&pool, user_id)
+    })
+    .await?;
+
+    respond_ok()
+}
+
+/// The email sent to new users to verify their email
+pub fn verification_email(to: String, token: &str) -> Email {
+    Email {
+        to,
+        subject: "Verify your email".into(),
+        body: format!(
+            "Use this link to verify your email within {} minutes:\n\n{}?token={}",
+            CONFIG.email_verification_expiration, CONFIG.email_verification_url, token
+        ),
+    }
+}
+
+/// Email a user a link to verify their email
+/// The user is saved either way, so a failed email shouldn't fail the request
+pub fn send_verification_email(
+    pool: &PoolType,
+    mailer: &MailerType,
+    user_id: Uuid,
+    to: &str,
+) -> Result<(), ApiError> {
+    let token = create_email_verification(pool, user_id)?;
+    if let Err(error) = mailer.send(verification_email(to.into(), &token)) {
+        error!("Cannot send a verification email: {}", error);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::models::user::find_by_auth;
+    use crate::models::user::tests::create_user_with_email;
+    use crate::tests::helpers::tests::{get_data_pool, get_pool};
+
+    async fn verify(token: &str) -> Result<HttpResponse, ApiError> {
+        let params = VerifyEmailRequest {
+            token: token.into(),
+        };
+        verify_email(get_data_pool(), Query(params)).await
+    }
+
+    #[actix_rt::test]
+    async fn it_verifies_an_email() {
+        let email = format!("verify-{}@nothing.org", Uuid::new_v4());
+        let user = create_user_with_email(&email).unwrap();
+        let token = create_email_verification(&get_pool(), user.id).unwrap();
+        assert!(find_by_auth(&get_pool(), &email, "123456", true).is_err());
+
+        let response = verify(&token).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(find_by_auth(&get_pool(), &email, "123456", true).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_verify_an_email_with_an_unknown_token() {
+        let response = verify("not-a-token").await;
+        assert!(response.is_err());
+    }
+}
@@ -1,9 +1,11 @@
 //! Shared utilities
 
+use crate::config::CONFIG;
 use crate::errors::ApiError;
 use actix_web::{
     body::Body,
-    web::{HttpResponse, Json},
+    http::header::{HeaderValue, USER_AGENT},
+    web::{HttpRequest, HttpResponse, Json},
 };
 use serde::Serialize;
 
@@ -20,6 +22,38 @@ pub fn respond_ok() -> Result<HttpResponse, ApiError> {
     Ok(HttpResponse::Ok().body(Body::Empty))
 }
 
+/// The URL of the request, without its query string, for pagination links
+pub fn get_base(req: HttpRequest) -> String {
+    let scheme = req.uri().scheme_str().unwrap_or("http");
+    let default_host = HeaderValue::from_static(&CONFIG.server);
+    let host = req
+        .head()
+        .headers()
+        .get("host")
+        .unwrap_or(&default_host)
+        .to_str()
+        .unwrap_or("");
+    let path = req.path();
+
+    format!("{}://{}{}", scheme, host, path)
+}
+
+/// The address of the client making the request
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    req.peer_addr().map(|addr| addr.ip().to_string())
+}
+
+/// The longest user agent stored, longer ones are truncated
+pub const MAX_USER_AGENT_LENGTH: usize = 255;
+
+/// The User-Agent of the client making the request, truncated to fit a column
+pub fn user_agent(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|agent| agent.chars().take(MAX_USER_AGENT_LENGTH).collect())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -44,4 +78,18 @@ mod tests {
         let result = respond_ok();
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn it_gets_the_user_agent() {
+        let req =
+            actix_web::test::TestRequest::with_header(USER_AGENT, "curl/7.64.1").to_http_request();
+        assert_eq!(user_agent(&req), Some("curl/7.64.1".into()));
+    }
+
+    #[test]
+    fn it_truncates_a_long_user_agent() {
+        let req = actix_web::test::TestRequest::with_header(USER_AGENT, "a".repeat(1000))
+            .to_http_request();
+        assert_eq!(user_agent(&req).unwrap().len(), MAX_USER_AGENT_LENGTH);
+    }
 }
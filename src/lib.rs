@@ -8,6 +8,7 @@
 //! use actix_framework::cache::add_cache;
 //! use actix_framework::config::CONFIG;
 //! use actix_framework::database::add_pool;
+//! use actix_framework::mailer::add_mailer;
 //! use actix_framework::routes::routes;
 //! use actix_framework::state::new_state;
 //!
@@ -48,6 +49,10 @@
 //!             // In a handler, add "pool: Data<PoolType>" param for auto extraction
 //!             .configure(add_pool)
 //!
+//!             // Adds the Mailer for use in the Actix Data Extractor
+//!             // In a handler, add "mailer: MailerType" param for auto extraction
+//!             .configure(add_mailer)
+//!
 //!             // Pull in default framework defaults
 //!             // This can be removed if they're not needed
 //!             .configure(routes)
@@ -74,6 +79,7 @@ extern crate serde_derive;
 #[macro_use]
 extern crate validator_derive;
 
+pub mod audit;
 pub mod auth;
 pub mod cache;
 pub mod config;
@@ -82,12 +88,18 @@ pub mod errors;
 pub mod extractors;
 pub mod handlers;
 pub mod helpers;
+pub mod keys;
+pub mod listing;
+pub mod mailer;
 pub mod middleware;
 pub mod models;
+pub mod oidc;
 pub mod pagination;
 pub mod routes;
 pub mod schema;
 pub mod server;
 pub mod state;
 pub mod tests;
+pub mod throttle;
+pub mod totp;
 pub mod validate;
@@ -0,0 +1,737 @@
+//! Filtering, sorting and search for list endpoints
+//!
+//! ```ignore
+//! GET /api/v1/user?sort=-created_at,last_name&filter[email]=satoshi@nakamotoinstitute.org
+//!     &filter[created_at][gte]=2020-11-01&q=sat&page=1&per_page=10
+//! ```
+//!
+//! A model whitelists the fields it can be listed by in a ListSpec, and the
+//! handler parses the query string against it with ListRequest::from_query.
+//! The model then applies the filters, search and sort to a boxed Diesel
+//! query, matching each whitelisted field to its column:
+//!
+//! ```ignore
+//! for filter in &params.filters {
+//!     query = match filter.field.as_str() {
+//!         "email" => crate::filter_by!(query, email, filter.operator, filter.value.text()),
+//!         "created_at" => {
+//!             crate::filter_by!(query, created_at, filter.operator, filter.value.datetime())
+//!         }
+//!         _ => query,
+//!     };
+//! }
+//! ```
+//!
+//! Sending cursor= switches the list to cursor pagination, sorted by a single
+//! field and seeked past the cursor's row with seek_by!, see pagination.
+
+use crate::errors::ApiError;
+use crate::pagination::{pagination_errors, Cursor, PaginationRequest};
+use actix_web::web::Query;
+use chrono::{NaiveDate, NaiveDateTime};
+use std::fmt;
+
+/// The longest search term accepted
+const MAX_SEARCH_LENGTH: usize = 100;
+
+/// The type a filter's or cursor's value is parsed as
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldType {
+    Text,
+    /// A datetime (2020-11-01T09:30:00) or a date (2020-11-01) at midnight
+    DateTime,
+}
+
+/// The fields of a model that can be sorted, filtered and searched
+#[derive(Clone, Copy, Debug)]
+pub struct ListSpec {
+    /// Typed, so a cursor's value parses as the column's type
+    pub sortable: &'static [(&'static str, FieldType)],
+    pub filterable: &'static [(&'static str, FieldType)],
+    /// Whether the model supports a q= search
+    pub searchable: bool,
+    /// The field cursor pagination sorts by when no sort is given
+    pub default_sort: &'static str,
+}
+
+impl ListSpec {
+    fn sort_type(&self, field: &str) -> Option<FieldType> {
+        field_type(self.sortable, field)
+    }
+
+    fn filter_type(&self, field: &str) -> Option<FieldType> {
+        field_type(self.filterable, field)
+    }
+}
+
+fn field_type(fields: &[(&str, FieldType)], field: &str) -> Option<FieldType> {
+    fields
+        .iter()
+        .find(|(name, _)| *name == field)
+        .map(|(_, field_type)| *field_type)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Asc,
+    Desc,
+}
+
+/// A field to sort by, descending when prefixed with a -
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sort {
+    pub field: String,
+    pub direction: Direction,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl Operator {
+    fn parse(operator: &str) -> Option<Self> {
+        match operator {
+            "eq" => Some(Operator::Eq),
+            "ne" => Some(Operator::Ne),
+            "gt" => Some(Operator::Gt),
+            "gte" => Some(Operator::Gte),
+            "lt" => Some(Operator::Lt),
+            "lte" => Some(Operator::Lte),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FilterValue {
+    Text(String),
+    DateTime(NaiveDateTime),
+}
+
+impl FilterValue {
+    /// The value of a FieldType::Text filter
+    pub fn text(&self) -> String {
+        match self {
+            FilterValue::Text(text) => text.clone(),
+            FilterValue::DateTime(_) => self.to_string(),
+        }
+    }
+
+    /// The value of a FieldType::DateTime filter or cursor
+    /// Only a field declared as Text in the ListSpec can hold a Text value
+    pub fn datetime(&self) -> NaiveDateTime {
+        match self {
+            FilterValue::DateTime(datetime) => *datetime,
+            FilterValue::Text(_) => panic!("The filter is not a FieldType::DateTime"),
+        }
+    }
+}
+
+/// Datetimes are written so they parse back, e.g. in cursors
+impl fmt::Display for FilterValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterValue::Text(text) => write!(f, "{}", text),
+            FilterValue::DateTime(datetime) => {
+                write!(f, "{}", datetime.format("%Y-%m-%dT%H:%M:%S%.f"))
+            }
+        }
+    }
+}
+
+/// filter[field]=value, or filter[field][operator]=value
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    pub field: String,
+    pub operator: Operator,
+    pub value: FilterValue,
+}
+
+/// Where a page of a cursor-paginated list starts
+#[derive(Clone, Debug, PartialEq)]
+pub struct Seek {
+    pub sort: Sort,
+    /// Page backwards, towards the start of the list
+    pub before: bool,
+    /// The sort value and id of the row the page starts after, None for the
+    /// first page
+    pub after: Option<(FilterValue, String)>,
+}
+
+impl Seek {
+    /// Whether the seeked query runs in ascending order
+    pub fn ascending(&self) -> bool {
+        (self.sort.direction == Direction::Asc) != self.before
+    }
+}
+
+/// The first page of a list, sorted by the model's default_sort
+impl From<&ListSpec> for Seek {
+    fn from(spec: &ListSpec) -> Self {
+        Seek {
+            sort: Sort {
+                field: spec.default_sort.into(),
+                direction: Direction::Asc,
+            },
+            before: false,
+            after: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ListRequest {
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+    pub sort: Vec<Sort>,
+    pub filters: Vec<Filter>,
+    pub search: Option<String>,
+    /// Paginate by cursor rather than by page
+    pub seek: Option<Seek>,
+    /// Count the total when paginating by cursor
+    pub total: bool,
+
+    /// The query string without page, per_page and cursor, kept for
+    /// pagination links
+    query: String,
+}
+
+impl ListRequest {
+    /// Parse and validate a query string against a model's ListSpec
+    /// Unknown parameters are ignored, unknown fields are rejected
+    pub fn from_query(query: &str, spec: &ListSpec) -> Result<Self, ApiError> {
+        let pairs = Query::<Vec<(String, String)>>::from_query(query)
+            .map_err(|error| ApiError::BadRequest(error.to_string()))?
+            .into_inner();
+        let mut request = ListRequest {
+            query: link_query(query),
+            ..Default::default()
+        };
+        let mut errors = vec![];
+        let mut cursor = None;
+
+        for (key, value) in pairs {
+            match key.as_str() {
+                "page" => request.page = parse_number(&key, &value, &mut errors),
+                "per_page" => request.per_page = parse_number(&key, &value, &mut errors),
+                "sort" => request.sort = parse_sort(&value, spec, &mut errors),
+                "q" => request.search = parse_search(&value, spec, &mut errors),
+                "cursor" => cursor = Some(value),
+                "total" => request.total = parse_bool(&key, &value, &mut errors),
+                _ if key.starts_with("filter[") => {
+                    if let Some(filter) = parse_filter(&key, &value, spec, &mut errors) {
+                        request.filters.push(filter);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        errors.extend(pagination_errors(request.page, request.per_page));
+
+        // Resolved last, as it depends on the sort
+        if let Some(cursor) = cursor {
+            request.seek = parse_cursor(&cursor, &request.sort, spec, &mut errors);
+        }
+
+        if errors.is_empty() {
+            Ok(request)
+        } else {
+            Err(ApiError::ValidationError(errors))
+        }
+    }
+
+    /// The page and per_page, for Paginate
+    pub fn pagination(&self) -> PaginationRequest {
+        PaginationRequest {
+            page: self.page,
+            per_page: self.per_page,
+        }
+    }
+
+    /// The base URL for pagination links, keeping the filters, search and sort
+    pub fn link_base(&self, base: String) -> String {
+        if self.query.is_empty() {
+            base
+        } else {
+            format!("{}?{}", base, self.query)
+        }
+    }
+}
+
+/// A plain page/per_page request, with no filters, search or sort
+impl From<PaginationRequest> for ListRequest {
+    fn from(pagination: PaginationRequest) -> Self {
+        ListRequest {
+            page: pagination.page,
+            per_page: pagination.per_page,
+            ..Default::default()
+        }
+    }
+}
+
+/// A LIKE pattern matching values starting with the term
+/// The term's own wildcards are escaped
+pub fn prefix_pattern(term: &str) -> String {
+    let escaped = term
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("{}%", escaped)
+}
+
+/// The raw query string without page, per_page and cursor
+fn link_query(query: &str) -> String {
+    query
+        .split('&')
+        .filter(|pair| {
+            let key = pair.split('=').next().unwrap_or("");
+            !pair.is_empty() && !["page", "per_page", "cursor"].contains(&key)
+        })
+        .collect::<Vec<&str>>()
+        .join("&")
+}
+
+fn parse_number(key: &str, value: &str, errors: &mut Vec<String>) -> Option<i64> {
+    match value.parse() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            errors.push(format!("{} must be a number", key));
+            None
+        }
+    }
+}
+
+fn parse_bool(key: &str, value: &str, errors: &mut Vec<String>) -> bool {
+    match value {
+        "true" => true,
+        "false" => false,
+        _ => {
+            errors.push(format!("{} must be true or false", key));
+            false
+        }
+    }
+}
+
+fn parse_sort(value: &str, spec: &ListSpec, errors: &mut Vec<String>) -> Vec<Sort> {
+    let mut sort: Vec<Sort> = vec![];
+    for field in value
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+    {
+        let (field, direction) = if field.starts_with('-') {
+            (&field[1..], Direction::Desc)
+        } else {
+            (field.trim_start_matches('+'), Direction::Asc)
+        };
+        if spec.sort_type(field).is_none() {
+            errors.push(format!("sort by {} is not supported", field));
+        } else if !sort.iter().any(|sorted| sorted.field == field) {
+            sort.push(Sort {
+                field: field.into(),
+                direction,
+            });
+        }
+    }
+    sort
+}
+
+fn parse_search(value: &str, spec: &ListSpec, errors: &mut Vec<String>) -> Option<String> {
+    let search = value.trim();
+    if !spec.searchable {
+        errors.push("q is not supported".into());
+        None
+    } else if search.chars().count() > MAX_SEARCH_LENGTH {
+        errors.push(format!(
+            "q must be at most {} characters",
+            MAX_SEARCH_LENGTH
+        ));
+        None
+    } else if search.is_empty() {
+        None
+    } else {
+        Some(search.into())
+    }
+}
+
+fn parse_filter(
+    key: &str,
+    value: &str,
+    spec: &ListSpec,
+    errors: &mut Vec<String>,
+) -> Option<Filter> {
+    // "filter[created_at][gte]" => ("created_at", Some("gte"))
+    let inner = &key["filter[".len()..];
+    if !inner.ends_with(']') {
+        errors.push(format!("{} is not a valid filter", key));
+        return None;
+    }
+    let mut parts = inner[..inner.len() - 1].splitn(2, "][");
+    let field = parts.next().unwrap_or("");
+    let operator = match parts.next() {
+        None => Operator::Eq,
+        Some(operator) => match Operator::parse(operator) {
+            Some(operator) => operator,
+            None => {
+                errors.push(format!("{} is not a supported operator", operator));
+                return None;
+            }
+        },
+    };
+
+    let field_type = match spec.filter_type(field) {
+        Some(field_type) => field_type,
+        None => {
+            errors.push(format!("filter by {} is not supported", field));
+            return None;
+        }
+    };
+    let value = match parse_value(value, field_type) {
+        Some(value) => value,
+        None => {
+            errors.push(format!("filter[{}] must be a date or datetime", field));
+            return None;
+        }
+    };
+
+    Some(Filter {
+        field: field.into(),
+        operator,
+        value,
+    })
+}
+
+/// An empty cursor starts on the first page
+fn parse_cursor(
+    token: &str,
+    sort: &[Sort],
+    spec: &ListSpec,
+    errors: &mut Vec<String>,
+) -> Option<Seek> {
+    let sort = match sort {
+        [] => None,
+        [sort] => Some(sort),
+        _ => {
+            errors.push("sort must be a single field when paginating by cursor".into());
+            return None;
+        }
+    };
+    if token.is_empty() {
+        let mut seek = Seek::from(spec);
+        if let Some(sort) = sort {
+            seek.sort = sort.clone();
+        }
+        return Some(seek);
+    }
+
+    // Cursors are signed, but a field may have left the whitelist since
+    let cursor = Cursor::decode(token).and_then(|cursor| {
+        let value = parse_value(&cursor.value, spec.sort_type(&cursor.field)?)?;
+        Some((cursor, value))
+    });
+    let (cursor, value) = match cursor {
+        Some(cursor) => cursor,
+        None => {
+            errors.push("cursor is invalid".into());
+            return None;
+        }
+    };
+    let cursor_sort = Sort {
+        field: cursor.field,
+        direction: if cursor.descending {
+            Direction::Desc
+        } else {
+            Direction::Asc
+        },
+    };
+    if sort.map_or(false, |sort| *sort != cursor_sort) {
+        errors.push("cursor does not match the sort".into());
+        return None;
+    }
+
+    Some(Seek {
+        sort: cursor_sort,
+        before: cursor.before,
+        after: Some((value, cursor.id)),
+    })
+}
+
+fn parse_value(value: &str, field_type: FieldType) -> Option<FilterValue> {
+    match field_type {
+        FieldType::Text => Some(FilterValue::Text(value.into())),
+        FieldType::DateTime => parse_datetime(value).map(FilterValue::DateTime),
+    }
+}
+
+fn parse_datetime(value: &str) -> Option<NaiveDateTime> {
+    value.parse::<NaiveDateTime>().ok().or_else(|| {
+        value
+            .parse::<NaiveDate>()
+            .ok()
+            .map(|date| date.and_hms(0, 0, 0))
+    })
+}
+
+/// Apply a filter's operator to a column of a boxed query, see the module docs
+#[macro_export]
+macro_rules! filter_by {
+    ($query:expr, $column:expr, $operator:expr, $value:expr) => {{
+        use $crate::listing::Operator;
+
+        match $operator {
+            Operator::Eq => $query.filter($column.eq($value)),
+            Operator::Ne => $query.filter($column.ne($value)),
+            Operator::Gt => $query.filter($column.gt($value)),
+            Operator::Gte => $query.filter($column.ge($value)),
+            Operator::Lt => $query.filter($column.lt($value)),
+            Operator::Lte => $query.filter($column.le($value)),
+        }
+    }};
+}
+
+/// Order a boxed query by a column, after any previous ordering
+#[macro_export]
+macro_rules! sort_by {
+    ($query:expr, $column:expr, $direction:expr) => {{
+        use $crate::listing::Direction;
+
+        match $direction {
+            Direction::Asc => $query.then_order_by($column.asc()),
+            Direction::Desc => $query.then_order_by($column.desc()),
+        }
+    }};
+}
+
+/// Seek a boxed query past a cursor and order it by the column and id
+///
+/// ```ignore
+/// "created_at" => crate::seek_by!(query, created_at, id, &seek, datetime),
+/// ```
+///
+/// The last argument is the FilterValue method giving the column's type, and
+/// must match the field's FieldType in the ListSpec's sortable.
+/// Load per_page + 1 rows and hand them to pagination::paginate_by_cursor.
+#[macro_export]
+macro_rules! seek_by {
+    ($query:expr, $column:expr, $id:expr, $seek:expr, $value:ident) => {{
+        let seek: &$crate::listing::Seek = $seek;
+        let mut query = $query;
+        if let Some((value, after_id)) = &seek.after {
+            let (value, after_id) = (value.$value(), after_id.clone());
+            query = if seek.ascending() {
+                query.filter(
+                    $column
+                        .gt(value.clone())
+                        .or($column.eq(value).and($id.gt(after_id))),
+                )
+            } else {
+                query.filter(
+                    $column
+                        .lt(value.clone())
+                        .or($column.eq(value).and($id.lt(after_id))),
+                )
+            };
+        }
+        if seek.ascending() {
+            query.order(($column.asc(), $id.asc()))
+        } else {
+            query.order(($column.desc(), $id.desc()))
+        }
+    }};
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    const SPEC: ListSpec = ListSpec {
+        sortable: &[
+            ("last_name", FieldType::Text),
+            ("updated_at", FieldType::DateTime),
+            ("created_at", FieldType::DateTime),
+        ],
+        filterable: &[
+            ("email", FieldType::Text),
+            ("created_at", FieldType::DateTime),
+        ],
+        searchable: true,
+        default_sort: "created_at",
+    };
+
+    fn parse(query: &str) -> Result<ListRequest, ApiError> {
+        ListRequest::from_query(query, &SPEC)
+    }
+
+    #[test]
+    fn it_parses_a_list_request() {
+        let request = parse(
+            "page=2&per_page=5&sort=-created_at,last_name&filter%5Bemail%5D=a%40b.org\
+             &filter[created_at][gte]=2020-11-01&q=sat",
+        )
+        .unwrap();
+        assert_eq!(request.page, Some(2));
+        assert_eq!(request.per_page, Some(5));
+        assert_eq!(
+            request.sort,
+            vec![
+                Sort {
+                    field: "created_at".into(),
+                    direction: Direction::Desc
+                },
+                Sort {
+                    field: "last_name".into(),
+                    direction: Direction::Asc
+                },
+            ]
+        );
+        assert_eq!(
+            request.filters,
+            vec![
+                Filter {
+                    field: "email".into(),
+                    operator: Operator::Eq,
+                    value: FilterValue::Text("a@b.org".into()),
+                },
+                Filter {
+                    field: "created_at".into(),
+                    operator: Operator::Gte,
+                    value: FilterValue::DateTime(NaiveDate::from_ymd(2020, 11, 1).and_hms(0, 0, 0)),
+                },
+            ]
+        );
+        assert_eq!(request.search, Some("sat".into()));
+    }
+
+    #[test]
+    fn it_rejects_fields_that_arent_whitelisted() {
+        let response = parse("sort=password&filter[password]=123456&filter[email][like]=a");
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::ValidationError(vec![
+                "sort by password is not supported".into(),
+                "filter by password is not supported".into(),
+                "like is not a supported operator".into(),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_values() {
+        let response = parse("page=first&filter[created_at][lt]=yesterday");
+        assert_eq!(
+            response.unwrap_err(),
+            ApiError::ValidationError(vec![
+                "page must be a number".into(),
+                "filter[created_at] must be a date or datetime".into(),
+            ])
+        );
+    }
+
+    #[test]
+    fn it_keeps_the_query_for_pagination_links() {
+        let request = parse("page=2&sort=last_name&per_page=5&q=sat&cursor=").unwrap();
+        assert_eq!(
+            request.link_base("http://fake/api/v1/user".into()),
+            "http://fake/api/v1/user?sort=last_name&q=sat"
+        );
+    }
+
+    fn cursor(field: &str, descending: bool, value: &str) -> String {
+        Cursor {
+            field: field.into(),
+            descending,
+            before: true,
+            value: value.into(),
+            id: "a421a56e-8652-4da6-90ee-59dfebb9d1b4".into(),
+        }
+        .encode()
+    }
+
+    #[test]
+    fn it_starts_a_cursor_list_on_the_default_sort() {
+        let request = parse("cursor=&total=true").unwrap();
+        assert_eq!(request.seek, Some(Seek::from(&SPEC)));
+        assert!(request.total);
+    }
+
+    #[test]
+    fn it_seeks_from_a_cursor() {
+        let query = format!(
+            "sort=-created_at&cursor={}",
+            cursor("created_at", true, "2020-11-01T09:30:00")
+        );
+        let seek = parse(&query).unwrap().seek.unwrap();
+        assert_eq!(
+            seek.after,
+            Some((
+                FilterValue::DateTime(NaiveDate::from_ymd(2020, 11, 1).and_hms(9, 30, 0)),
+                "a421a56e-8652-4da6-90ee-59dfebb9d1b4".into()
+            ))
+        );
+        assert!(seek.before);
+        assert!(seek.ascending());
+    }
+
+    #[test]
+    fn it_rejects_a_tampered_or_mismatched_cursor() {
+        let mut tampered = cursor("last_name", false, "Nakamoto");
+        tampered.insert(0, 'x');
+        let query = format!("cursor={}", tampered);
+        assert_eq!(
+            parse(&query).unwrap_err(),
+            ApiError::ValidationError(vec!["cursor is invalid".into()])
+        );
+
+        let query = format!(
+            "sort=created_at&cursor={}",
+            cursor("last_name", false, "Nakamoto")
+        );
+        assert_eq!(
+            parse(&query).unwrap_err(),
+            ApiError::ValidationError(vec!["cursor does not match the sort".into()])
+        );
+
+        assert_eq!(
+            parse("sort=last_name,created_at&cursor=").unwrap_err(),
+            ApiError::ValidationError(vec![
+                "sort must be a single field when paginating by cursor".into()
+            ])
+        );
+    }
+
+    #[test]
+    fn it_parses_a_cursor_as_its_sortable_type() {
+        let query = format!(
+            "sort=updated_at&cursor={}",
+            cursor("updated_at", false, "2020-11-01")
+        );
+        let seek = parse(&query).unwrap().seek.unwrap();
+        let (value, _) = seek.after.unwrap();
+        assert_eq!(
+            value.datetime(),
+            NaiveDate::from_ymd(2020, 11, 1).and_hms(0, 0, 0)
+        );
+
+        let query = format!(
+            "sort=updated_at&cursor={}",
+            cursor("updated_at", false, "Nakamoto")
+        );
+        assert_eq!(
+            parse(&query).unwrap_err(),
+            ApiError::ValidationError(vec!["cursor is invalid".into()])
+        );
+    }
+
+    #[test]
+    fn it_escapes_a_prefix_pattern() {
+        assert_eq!(prefix_pattern("sat"), "sat%");
+        assert_eq!(prefix_pattern("100%_"), "100\\%\\_%");
+    }
+}
@@ -0,0 +1,171 @@
+//! Send emails through a pluggable Mailer
+//!
+//! The MAILER env var selects the implementation:
+//!
+//! - smtp: send through an SMTP server over TLS
+//! - file: append emails to MAILER_FILE, for local development
+//! - memory: keep emails in memory, for tests
+
+use crate::config::CONFIG;
+use crate::errors::ApiError;
+use actix_web::web::{Data, ServiceConfig};
+use lettre::smtp::authentication::Credentials;
+use lettre::{SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+pub type MailerType = Data<dyn Mailer>;
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MailerKind {
+    File,
+    Memory,
+    Smtp,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+pub trait Mailer: Send + Sync {
+    /// Send a plain text email
+    fn send(&self, email: Email) -> Result<(), ApiError>;
+}
+
+/// Send emails through an SMTP server over TLS
+pub struct SmtpMailer {
+    pub from: String,
+    pub host: String,
+    pub credentials: Option<(String, String)>,
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: Email) -> Result<(), ApiError> {
+        let message = EmailBuilder::new()
+            .to(email.to)
+            .from(self.from.clone())
+            .subject(email.subject)
+            .text(email.body)
+            .build()
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+
+        let mut client = SmtpClient::new_simple(&self.host)
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+        if let Some((username, password)) = &self.credentials {
+            client = client.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        client
+            .transport()
+            .send(message.into())
+            .map(|_| ())
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))
+    }
+}
+
+/// Append emails to a file
+pub struct FileMailer {
+    pub path: String,
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: Email) -> Result<(), ApiError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+        writeln!(
+            file,
+            "To: {}\nSubject: {}\n\n{}\n",
+            email.to, email.subject, email.body
+        )
+        .map_err(|e| ApiError::InternalServerError(e.to_string()))
+    }
+}
+
+/// Keep emails in memory
+#[derive(Default)]
+pub struct MemoryMailer {
+    emails: Mutex<Vec<Email>>,
+}
+
+impl MemoryMailer {
+    /// The emails sent so far, oldest first
+    pub fn emails(&self) -> Vec<Email> {
+        self.emails.lock().unwrap().clone()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, email: Email) -> Result<(), ApiError> {
+        self.emails.lock().unwrap().push(email);
+        Ok(())
+    }
+}
+
+/// Create the configured mailer
+pub fn get_mailer() -> Arc<dyn Mailer> {
+    match CONFIG.mailer {
+        MailerKind::File => Arc::new(FileMailer {
+            path: CONFIG.mailer_file.clone(),
+        }),
+        MailerKind::Memory => Arc::new(MemoryMailer::default()),
+        MailerKind::Smtp => Arc::new(SmtpMailer {
+            from: CONFIG.mailer_from.clone(),
+            host: CONFIG
+                .smtp_host
+                .clone()
+                .expect("SMTP_HOST is required for the smtp mailer"),
+            credentials: CONFIG
+                .smtp_username
+                .clone()
+                .zip(CONFIG.smtp_password.clone()),
+        }),
+    }
+}
+
+/// Add the mailer to actix data
+pub fn add_mailer(cfg: &mut ServiceConfig) {
+    cfg.app_data(Data::from(get_mailer()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_to_string, remove_file};
+
+    fn email() -> Email {
+        Email {
+            to: "mailer@nothing.org".into(),
+            subject: "Hello".into(),
+            body: "Hello, world".into(),
+        }
+    }
+
+    #[test]
+    fn it_keeps_emails_in_memory() {
+        let mailer = MemoryMailer::default();
+        mailer.send(email()).unwrap();
+        assert_eq!(mailer.emails(), vec![email()]);
+    }
+
+    #[test]
+    fn it_appends_emails_to_a_file() {
+        let path = std::env::temp_dir().join("it_appends_emails_to_a_file.log");
+        let path = path.to_str().unwrap().to_string();
+        let _ = remove_file(&path);
+        let mailer = FileMailer { path: path.clone() };
+        mailer.send(email()).unwrap();
+        mailer.send(email()).unwrap();
+        let contents = read_to_string(&path).unwrap();
+        assert_eq!(contents.matches("Subject: Hello").count(), 2);
+        remove_file(&path).unwrap();
+    }
+}
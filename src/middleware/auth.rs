@@ -1,20 +1,176 @@
-use crate::auth::{decode_jwt, PrivateClaim};
-use crate::errors::ApiError;
-use actix_identity::RequestIdentity;
+use crate::audit::{Action, Audit};
+use crate::auth::{get_api_key, get_token, verify_jwt};
+use crate::cache::Cache;
+use crate::database::PoolType;
+use crate::models::api_key::authenticate;
+use crate::models::user::AuthUser;
 use actix_service::{Service, Transform};
 use actix_web::{
     dev::{ServiceRequest, ServiceResponse},
-    Error, HttpResponse,
+    http::Method,
+    web::{block, Data},
+    Error, HttpMessage, HttpResponse,
 };
-use futures::{Future, future::{ok, Ready}};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use regex::Regex;
+use serde_json::json;
+use std::cell::RefCell;
 use std::pin::Pin;
+use std::rc::Rc;
 use std::task::{Context, Poll};
 
-pub struct Auth;
+/// Whether a request needs to be authenticated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    /// Authentication is skipped
+    Public,
+    /// The identity is attached when present, but not required
+    Optional,
+    /// Requests without a valid JWT or API key are rejected (the default)
+    Required,
+}
+
+/// A request path, matched exactly, by a glob or by a regular expression
+#[derive(Clone, Debug)]
+pub enum PathPattern {
+    Exact(String),
+    Regex(Regex),
+}
+
+impl PathPattern {
+    /// A glob, where `*` matches within a path segment and `**` matches
+    /// across segments, e.g. "/api/v1/auth/oidc/*/login"
+    pub fn glob(glob: &str) -> Self {
+        let mut regex = String::from("^");
+        for (i, part) in glob.split("**").enumerate() {
+            if i > 0 {
+                regex.push_str(".*");
+            }
+            let part = part
+                .split('*')
+                .map(regex::escape)
+                .collect::<Vec<String>>()
+                .join("[^/]*");
+            regex.push_str(&part);
+        }
+        regex.push('$');
+        PathPattern::Regex(Regex::new(&regex).expect("A glob is always a valid regex"))
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            PathPattern::Exact(exact) => exact == path,
+            PathPattern::Regex(regex) => regex.is_match(path),
+        }
+    }
+}
+
+/// Paths containing a `*` are globs, otherwise they're matched exactly
+impl From<&str> for PathPattern {
+    fn from(path: &str) -> Self {
+        if path.contains('*') {
+            PathPattern::glob(path)
+        } else {
+            PathPattern::Exact(path.into())
+        }
+    }
+}
+
+/// Regular expressions match anywhere in the path unless anchored with ^ and $
+impl From<Regex> for PathPattern {
+    fn from(regex: Regex) -> Self {
+        PathPattern::Regex(regex)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    access: Access,
+    methods: Vec<Method>,
+    pattern: PathPattern,
+}
+
+impl Rule {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        (self.methods.is_empty() || self.methods.contains(method)) && self.pattern.matches(path)
+    }
+}
+
+/// Require a valid JWT or API key, except for the routes allowed through
+///
+/// ```ignore
+/// web::scope("/api/v1").wrap(
+///     Auth::new()
+///         .public_for(&[Method::POST], "/api/v1/auth/login")
+///         .public("/api/v1/auth/oidc/*/callback")
+///         .optional(Regex::new(r"^/api/v1/posts/\d+$").unwrap()),
+/// )
+/// ```
+///
+/// The first matching rule applies.  The user is stored in the request for
+/// the AuthUser extractor whenever the request is authenticated.
+#[derive(Clone, Debug, Default)]
+pub struct Auth {
+    rules: Vec<Rule>,
+}
+
+impl Auth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow any request matching the pattern through without authentication
+    pub fn public<P: Into<PathPattern>>(self, pattern: P) -> Self {
+        self.rule(Access::Public, &[], pattern)
+    }
+
+    /// Allow requests with one of the methods matching the pattern through
+    /// without authentication
+    pub fn public_for<P: Into<PathPattern>>(self, methods: &[Method], pattern: P) -> Self {
+        self.rule(Access::Public, methods, pattern)
+    }
+
+    /// Authenticate any request matching the pattern if it has a JWT or API
+    /// key, but allow it through either way
+    pub fn optional<P: Into<PathPattern>>(self, pattern: P) -> Self {
+        self.rule(Access::Optional, &[], pattern)
+    }
+
+    /// Authenticate requests with one of the methods matching the pattern if
+    /// they have a JWT or API key, but allow them through either way
+    pub fn optional_for<P: Into<PathPattern>>(self, methods: &[Method], pattern: P) -> Self {
+        self.rule(Access::Optional, methods, pattern)
+    }
+
+    fn rule<P: Into<PathPattern>>(
+        mut self,
+        access: Access,
+        methods: &[Method],
+        pattern: P,
+    ) -> Self {
+        self.rules.push(Rule {
+            access,
+            methods: methods.to_vec(),
+            pattern: pattern.into(),
+        });
+        self
+    }
+
+    /// The access required for a request
+    pub fn access(&self, method: &Method, path: &str) -> Access {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(method, path))
+            .map_or(Access::Required, |rule| rule.access)
+    }
+}
 
 impl<S, B> Transform<S> for Auth
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
 {
     type Request = ServiceRequest;
@@ -25,16 +181,20 @@ where
     type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
     fn new_transform(&self, service: S) -> Self::Future {
-        ok(AuthMiddleware { service })
+        ok(AuthMiddleware {
+            auth: Rc::new(self.clone()),
+            service: Rc::new(RefCell::new(service)),
+        })
     }
 }
 pub struct AuthMiddleware<S> {
-    service: S,
+    auth: Rc<Auth>,
+    service: Rc<RefCell<S>>,
 }
 
 impl<S, B> Service for AuthMiddleware<S>
 where
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
     S::Future: 'static,
 {
     type Request = ServiceRequest;
@@ -42,27 +202,200 @@ where
     type Error = Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
 
-    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {        
-        self.service.poll_ready(cx)
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
     }
 
     fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        let identity = RequestIdentity::get_identity(&req).unwrap_or("".into());
-        let private_claim: Result<PrivateClaim, ApiError> = decode_jwt(&identity);
-        let is_logged_in = private_claim.is_ok();
-        let unauthorized = !is_logged_in && req.path() != "/api/v1/auth/login";
-
-        if unauthorized {
-            return Box::pin(async move {    
-                Ok(req.into_response(HttpResponse::Unauthorized().finish().into_body()))
-            })
-        }
-
-        let fut = self.service.call(req);
+        let access = self.auth.access(req.method(), req.path());
+        let identity = get_token(req.request()).unwrap_or("".into());
+        let api_key = get_api_key(&req);
+        let cache = req.app_data::<Cache>().cloned();
+        let pool = req.app_data::<Data<PoolType>>().cloned();
+        let audit_pool = pool.clone();
+        let service = self.service.clone();
 
         Box::pin(async move {
+            let mut impersonated = None;
+            if access != Access::Public {
+                // Revoked tokens are checked against the cache, so verify asynchronously
+                let jwt_user = verify_jwt(&identity, cache).await.ok().map(AuthUser::from);
+
+                // Fall back to an X-Api-Key header
+                let user = match (jwt_user, api_key, pool) {
+                    (Some(user), _, _) => Some(user),
+                    (None, Some(api_key), Some(pool)) => {
+                        block(move || authenticate(&pool, &api_key)).await.ok()
+                    }
+                    _ => None,
+                };
+
+                // Store the user for the AuthUser extractor
+                match user {
+                    Some(user) => {
+                        if user.impersonator.is_some() && !req.method().is_safe() {
+                            impersonated = Some(user.clone());
+                        }
+                        req.extensions_mut().insert(user);
+                    }
+                    None if access == Access::Required => {
+                        let response = HttpResponse::Unauthorized().finish().into_body();
+                        return Ok(req.into_response(response));
+                    }
+                    None => {}
+                }
+            }
+
+            let method = req.method().clone();
+            let path = req.path().to_string();
+            let fut = service.borrow_mut().call(req);
             let res = fut.await?;
+
+            // Record every write made while impersonating a user
+            if let (Some(user), Some(pool)) = (impersonated, audit_pool) {
+                Audit::new(Action::ImpersonatedWrite, res.request())
+                    .actor(&user)
+                    .metadata(json!({
+                        "method": method.as_str(),
+                        "path": path,
+                        "status": res.status().as_u16(),
+                    }))
+                    .record(pool)
+                    .await;
+            }
             Ok(res)
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{create_jwt, PrivateClaim};
+    use crate::models::audit_event::{get_all as get_audit_events, AuditEventFilter};
+    use crate::pagination::PaginationRequest;
+    use crate::tests::helpers::tests::get_pool;
+    use actix_web::{http::header::AUTHORIZATION, http::StatusCode, test, web, App, HttpRequest};
+    use uuid::Uuid;
+
+    fn get_auth() -> Auth {
+        Auth::new()
+            .public_for(&[Method::POST], "/login")
+            .public("/oidc/*/login")
+            .optional(Regex::new(r"^/posts/\d+$").unwrap())
+    }
+
+    async fn call(method: Method, uri: &str, logged_in: bool) -> (StatusCode, String) {
+        let mut app = test::init_service(App::new().wrap(get_auth()).default_service(
+            web::route().to(|req: HttpRequest| async move {
+                let email = req
+                    .extensions()
+                    .get::<AuthUser>()
+                    .map(|user| user.email.clone())
+                    .unwrap_or_default();
+                HttpResponse::Ok().body(email)
+            }),
+        ))
+        .await;
+        let mut request = test::TestRequest::with_uri(uri).method(method);
+        if logged_in {
+            let private_claim = PrivateClaim::new(Uuid::new_v4(), "auth@nothing.org".into());
+            let jwt = create_jwt(private_claim).unwrap();
+            request = request.header(AUTHORIZATION, format!("Bearer {}", jwt));
+        }
+        let response = test::call_service(&mut app, request.to_request()).await;
+        let status = response.status();
+        let body = test::read_body(response).await;
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn it_matches_paths() {
+        assert!(PathPattern::from("/login").matches("/login"));
+        assert!(!PathPattern::from("/login").matches("/login/other"));
+        assert!(PathPattern::from("/oidc/*/login").matches("/oidc/google/login"));
+        assert!(!PathPattern::from("/oidc/*/login").matches("/oidc/google/other/login"));
+        assert!(PathPattern::from("/static/**").matches("/static/css/app.css"));
+        assert!(!PathPattern::from("/static.*").matches("/static-files"));
+    }
+
+    #[test]
+    fn it_finds_the_access_for_a_request() {
+        let auth = get_auth();
+        assert_eq!(auth.access(&Method::POST, "/login"), Access::Public);
+        assert_eq!(auth.access(&Method::GET, "/login"), Access::Required);
+        assert_eq!(auth.access(&Method::GET, "/posts/1"), Access::Optional);
+        assert_eq!(auth.access(&Method::GET, "/posts/new"), Access::Required);
+    }
+
+    #[actix_rt::test]
+    async fn it_requires_authentication_by_default() {
+        let (status, _) = call(Method::GET, "/user", false).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = call(Method::GET, "/user", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "auth@nothing.org");
+    }
+
+    #[actix_rt::test]
+    async fn it_allows_public_routes() {
+        let (status, _) = call(Method::POST, "/login", false).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _) = call(Method::GET, "/oidc/google/login", false).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn it_attaches_the_identity_on_optional_routes() {
+        let (status, body) = call(Method::GET, "/posts/1", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "");
+
+        let (status, body) = call(Method::GET, "/posts/1", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "auth@nothing.org");
+    }
+
+    #[actix_rt::test]
+    async fn it_audits_writes_made_while_impersonating() {
+        let mut app = test::init_service(
+            App::new()
+                .data(get_pool())
+                .wrap(get_auth())
+                .default_service(web::route().to(|| async { HttpResponse::Created().finish() })),
+        )
+        .await;
+        let user_id = Uuid::new_v4();
+        let admin_id = Uuid::new_v4();
+        let private_claim = PrivateClaim::new(user_id, "auth@nothing.org".into())
+            .with_impersonator(admin_id, None, 0);
+        let jwt = create_jwt(private_claim).unwrap();
+        for method in &[Method::GET, Method::PUT] {
+            let request = test::TestRequest::with_uri("/user")
+                .method(method.clone())
+                .header(AUTHORIZATION, format!("Bearer {}", jwt))
+                .to_request();
+            test::call_service(&mut app, request).await;
+        }
+
+        let filter = AuditEventFilter {
+            actor_id: Some(user_id.to_string()),
+            action: Some("impersonation.write".into()),
+            ..Default::default()
+        };
+        let params = PaginationRequest {
+            page: Some(1),
+            per_page: Some(10),
+        };
+        let audit_events = get_audit_events(&get_pool(), &filter, params, "".into()).unwrap();
+        assert_eq!(audit_events.pagination.total, 1);
+        let audit_event = &audit_events.data.0[0];
+        assert_eq!(audit_event.impersonator_id, Some(admin_id.to_string()));
+        assert_eq!(
+            audit_event.metadata,
+            json!({ "method": "PUT", "path": "/user", "status": 201 })
+        );
+    }
+}
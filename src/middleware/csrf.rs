@@ -0,0 +1,231 @@
+use crate::auth::has_bearer_token;
+use crate::config::CONFIG;
+use crate::middleware::auth::PathPattern;
+use actix_service::{Service, Transform};
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    dev::{ServiceRequest, ServiceResponse},
+    Error, HttpMessage, HttpResponse,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use time::Duration as TimeDuration;
+
+/// The cookie holding the CSRF token
+pub const CSRF_COOKIE: &str = "csrf_token";
+
+/// The header the CSRF token is sent back in
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Build the CSRF cookie
+///
+/// It's readable by scripts, so SPAs can copy it into the X-CSRF-Token
+/// header, and is never sent on cross-site requests.
+pub fn csrf_cookie(token: &str) -> Cookie<'static> {
+    Cookie::build(CSRF_COOKIE, token.to_string())
+        .path("/")
+        .secure(CONFIG.session_secure)
+        .same_site(SameSite::Strict)
+        .max_age(TimeDuration::minutes(CONFIG.session_timeout))
+        .finish()
+}
+
+/// Reject cookie-authenticated writes without a matching CSRF token
+///
+/// ```ignore
+/// web::scope("/api/v1").wrap(Csrf::new().exempt("/api/v1/webhooks/**"))
+/// ```
+///
+/// Uses the double-submit cookie pattern: any request other than `GET`,
+/// `HEAD`, `OPTIONS` or `TRACE` that carries the identity cookie must send
+/// the csrf_token cookie's value in the X-CSRF-Token header.  A cross-site
+/// form can make the browser attach the cookies, but can't read or set them.
+///
+/// Requests authenticated with a bearer token or an API key, rather than the
+/// identity cookie, are let through.
+#[derive(Clone, Debug, Default)]
+pub struct Csrf {
+    exempt: Vec<PathPattern>,
+}
+
+impl Csrf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow any request matching the pattern through without a CSRF token
+    pub fn exempt<P: Into<PathPattern>>(mut self, pattern: P) -> Self {
+        self.exempt.push(pattern.into());
+        self
+    }
+
+    /// Whether a request needs a CSRF token
+    fn is_required(&self, req: &ServiceRequest) -> bool {
+        !req.method().is_safe()
+            && req.cookie(&CONFIG.session_name).is_some()
+            && !has_bearer_token(req.request())
+            && !self
+                .exempt
+                .iter()
+                .any(|pattern| pattern.matches(req.path()))
+    }
+}
+
+/// The cookie and header must both be present and match
+fn is_valid(req: &ServiceRequest) -> bool {
+    let cookie = req.cookie(CSRF_COOKIE);
+    let header = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+    match (cookie, header) {
+        (Some(cookie), Some(header)) => {
+            !cookie.value().is_empty() && constant_time_eq(cookie.value(), header)
+        }
+        _ => false,
+    }
+}
+
+/// Compare without leaking the position of the first difference
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+impl<S, B> Transform<S> for Csrf
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfMiddleware {
+            csrf: Rc::new(self.clone()),
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+pub struct CsrfMiddleware<S> {
+    csrf: Rc<Csrf>,
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for CsrfMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let is_forged = self.csrf.is_required(&req) && !is_valid(&req);
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            if is_forged {
+                let response = HttpResponse::Forbidden().finish().into_body();
+                return Ok(req.into_response(response));
+            }
+
+            let fut = service.borrow_mut().call(req);
+            let res = fut.await?;
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::{header::AUTHORIZATION, Method, StatusCode},
+        test, web, App,
+    };
+
+    async fn call(request: test::TestRequest) -> StatusCode {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(Csrf::new().exempt("/webhooks/*"))
+                .default_service(web::route().to(|| async { HttpResponse::Ok().finish() })),
+        )
+        .await;
+        let response = test::call_service(&mut app, request.to_request()).await;
+        response.status()
+    }
+
+    fn post(uri: &str) -> test::TestRequest {
+        test::TestRequest::with_uri(uri)
+            .method(Method::POST)
+            .cookie(Cookie::new(CONFIG.session_name.clone(), "identity"))
+    }
+
+    #[actix_rt::test]
+    async fn it_rejects_a_cookie_authenticated_write_without_a_token() {
+        assert_eq!(call(post("/user")).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn it_rejects_a_mismatched_token() {
+        let request = post("/user")
+            .cookie(csrf_cookie("token"))
+            .header(CSRF_HEADER, "other");
+        assert_eq!(call(request).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn it_allows_a_matching_token() {
+        let request = post("/user")
+            .cookie(csrf_cookie("token"))
+            .header(CSRF_HEADER, "token");
+        assert_eq!(call(request).await, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn it_allows_safe_methods_and_exempt_paths() {
+        let request = test::TestRequest::get()
+            .uri("/user")
+            .cookie(Cookie::new(CONFIG.session_name.clone(), "identity"));
+        assert_eq!(call(request).await, StatusCode::OK);
+        assert_eq!(call(post("/webhooks/stripe")).await, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn it_allows_bearer_requests() {
+        let request = post("/user").header(AUTHORIZATION, "Bearer jwt");
+        let expected = if CONFIG.auth_token_source.uses_bearer() {
+            StatusCode::OK
+        } else {
+            StatusCode::FORBIDDEN
+        };
+        assert_eq!(call(request).await, expected);
+    }
+
+    #[test]
+    fn it_compares_tokens() {
+        assert!(constant_time_eq("token", "token"));
+        assert!(!constant_time_eq("token", "tokem"));
+        assert!(!constant_time_eq("token", "token2"));
+    }
+}
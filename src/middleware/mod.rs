@@ -1,3 +1,5 @@
 //! Actix middleware
 
 pub mod auth;
+pub mod csrf;
+pub mod permission;
@@ -0,0 +1,137 @@
+use crate::auth::{get_token, verify_jwt};
+use crate::cache::Cache;
+use crate::models::user::AuthUser;
+use actix_service::{Service, Transform};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    Error, HttpMessage, HttpResponse,
+};
+use futures::{
+    future::{ok, Ready},
+    Future,
+};
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// Guard a scope or resource, requiring the logged in user to have a permission
+///
+/// ```ignore
+/// web::resource("/{id}")
+///     .guard(guard::Delete())
+///     .wrap(RequirePermission("user:delete"))
+///     .route(web::delete().to(delete_user))
+/// ```
+///
+/// Permissions are embedded in the JWT at login, see PrivateClaim.
+/// Requests authenticated with an API key are limited to the key's scopes.
+pub struct RequirePermission(pub &'static str);
+
+impl<S, B> Transform<S> for RequirePermission
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequirePermissionMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequirePermissionMiddleware {
+            permission: self.0,
+            service: Rc::new(RefCell::new(service)),
+        })
+    }
+}
+pub struct RequirePermissionMiddleware<S> {
+    permission: &'static str,
+    service: Rc<RefCell<S>>,
+}
+
+impl<S, B> Service for RequirePermissionMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let identity = get_token(req.request()).unwrap_or("".into());
+        let cache = req.app_data::<Cache>().cloned();
+        let stored_user = req.extensions().get::<AuthUser>().cloned();
+        let permission = self.permission;
+        let service = self.service.clone();
+
+        Box::pin(async move {
+            let is_permitted = match stored_user {
+                Some(user) => user.has_permission(permission),
+                None => verify_jwt(&identity, cache)
+                    .await
+                    .map(|private_claim| private_claim.permissions.iter().any(|p| p == permission))
+                    .unwrap_or(false),
+            };
+
+            if !is_permitted {
+                return Ok(req.into_response(HttpResponse::Forbidden().finish().into_body()));
+            }
+
+            let fut = service.borrow_mut().call(req);
+            let res = fut.await?;
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{create_jwt, PrivateClaim};
+    use actix_web::{http::header::AUTHORIZATION, http::StatusCode, test, web, App};
+    use uuid::Uuid;
+
+    async fn call_with_permissions(permissions: Vec<String>) -> StatusCode {
+        let mut app = test::init_service(
+            App::new().service(
+                web::resource("/guarded")
+                    .wrap(RequirePermission("user:delete"))
+                    .to(|| async { HttpResponse::Ok().finish() }),
+            ),
+        )
+        .await;
+        let private_claim = PrivateClaim::new(Uuid::new_v4(), "guard@nothing.org".into())
+            .with_grants(vec![], permissions);
+        let jwt = create_jwt(private_claim).unwrap();
+        let response = test::call_service(
+            &mut app,
+            test::TestRequest::get()
+                .header(AUTHORIZATION, format!("Bearer {}", jwt))
+                .uri("/guarded")
+                .to_request(),
+        )
+        .await;
+        response.status()
+    }
+
+    #[actix_rt::test]
+    async fn it_allows_a_user_with_the_permission() {
+        let status = call_with_permissions(vec!["user:delete".into()]).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn it_forbids_a_user_without_the_permission() {
+        let status = call_with_permissions(vec![]).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,248 @@
+use crate::auth::{generate_token, hash_token};
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::role::find_login_grants;
+use crate::models::user::{find as find_user, AuthUser};
+use crate::schema::api_keys;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+/// Prepended to every key, so leaked keys are easy to recognize
+pub const KEY_PREFIX: &str = "rak_";
+
+/// The number of characters of a key stored in plain text to identify it
+const VISIBLE_CHARS: usize = 8;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+pub struct ApiKey {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub prefix: String,
+    pub key_hash: String,
+    pub scopes: String,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+impl ApiKey {
+    /// Scopes are stored as a comma separated list of permissions
+    pub fn scopes(&self) -> Vec<String> {
+        self.scopes
+            .split(',')
+            .filter(|scope| !scope.is_empty())
+            .map(String::from)
+            .collect()
+    }
+}
+
+/// Create an API key for a user
+/// Returns the key and the plain text secret, only the hash is stored
+pub fn create(
+    pool: &PoolType,
+    user: Uuid,
+    key_name: &str,
+    key_scopes: &[String],
+    key_expires_at: Option<NaiveDateTime>,
+) -> Result<(ApiKey, String), ApiError> {
+    use crate::schema::api_keys::dsl::api_keys;
+
+    let secret = format!("{}{}", KEY_PREFIX, generate_token());
+    let api_key = ApiKey {
+        id: Uuid::new_v4().to_string(),
+        user_id: user.to_string(),
+        name: key_name.into(),
+        prefix: secret[..KEY_PREFIX.len() + VISIBLE_CHARS].into(),
+        key_hash: hash_token(&secret),
+        scopes: key_scopes.join(","),
+        expires_at: key_expires_at,
+        last_used_at: None,
+        created_at: Utc::now().naive_utc(),
+    };
+
+    let conn = pool.get()?;
+    diesel::insert_into(api_keys)
+        .values(&api_key)
+        .execute(&conn)?;
+    Ok((api_key, secret))
+}
+
+/// Get all of a user's API keys
+pub fn find_all(pool: &PoolType, user: Uuid) -> Result<Vec<ApiKey>, ApiError> {
+    use crate::schema::api_keys::dsl::{api_keys, created_at, user_id};
+
+    let conn = pool.get()?;
+    let keys = api_keys
+        .filter(user_id.eq(user.to_string()))
+        .order(created_at.desc())
+        .load::<ApiKey>(&conn)?;
+    Ok(keys)
+}
+
+/// Find one of a user's API keys or issue a NOT_FOUND
+pub fn find(pool: &PoolType, user: Uuid, key_id: Uuid) -> Result<ApiKey, ApiError> {
+    use crate::schema::api_keys::dsl::{api_keys, id, user_id};
+
+    let not_found = format!("API key {} not found", key_id);
+    let conn = pool.get()?;
+    api_keys
+        .filter(id.eq(key_id.to_string()))
+        .filter(user_id.eq(user.to_string()))
+        .first::<ApiKey>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+/// Rename one of a user's API keys, or change its scopes or expiry
+pub fn update(
+    pool: &PoolType,
+    user: Uuid,
+    key_id: Uuid,
+    key_name: &str,
+    key_scopes: &[String],
+    key_expires_at: Option<NaiveDateTime>,
+) -> Result<ApiKey, ApiError> {
+    use crate::schema::api_keys::dsl::{api_keys, expires_at, id, name, scopes, user_id};
+
+    find(pool, user, key_id)?;
+
+    let conn = pool.get()?;
+    diesel::update(api_keys)
+        .filter(id.eq(key_id.to_string()))
+        .filter(user_id.eq(user.to_string()))
+        .set((
+            name.eq(key_name),
+            scopes.eq(key_scopes.join(",")),
+            expires_at.eq(key_expires_at),
+        ))
+        .execute(&conn)?;
+    find(pool, user, key_id)
+}
+
+/// Delete one of a user's API keys, revoking it immediately
+pub fn delete(pool: &PoolType, user: Uuid, key_id: Uuid) -> Result<(), ApiError> {
+    use crate::schema::api_keys::dsl::{api_keys, id, user_id};
+
+    let conn = pool.get()?;
+    let deleted = diesel::delete(api_keys)
+        .filter(id.eq(key_id.to_string()))
+        .filter(user_id.eq(user.to_string()))
+        .execute(&conn)?;
+
+    if deleted == 0 {
+        return Err(ApiError::NotFound(format!("API key {} not found", key_id)));
+    }
+    Ok(())
+}
+
+/// Resolve an API key to the principal it acts as
+///
+/// The principal is the key's owner, limited to the key's scopes.
+/// Scopes are checked against the owner's current permissions, so removing
+/// a role from the owner also removes it from their keys.
+/// Records when the key was last used.
+pub fn authenticate(pool: &PoolType, secret: &str) -> Result<AuthUser, ApiError> {
+    use crate::schema::api_keys::dsl::{api_keys, id, key_hash, last_used_at};
+
+    let unauthorized = || ApiError::Unauthorized("Invalid API key".into());
+    let now = Utc::now().naive_utc();
+    let conn = pool.get()?;
+    let api_key = api_keys
+        .filter(key_hash.eq(hash_token(secret)))
+        .first::<ApiKey>(&conn)
+        .map_err(|_| unauthorized())?;
+
+    if api_key
+        .expires_at
+        .map_or(false, |expires_at| expires_at < now)
+    {
+        return Err(unauthorized());
+    }
+
+    diesel::update(api_keys)
+        .filter(id.eq(api_key.id.clone()))
+        .set(last_used_at.eq(now))
+        .execute(&conn)?;
+
+    let owner = Uuid::parse_str(&api_key.user_id)?;
+    let user = find_user(pool, owner).map_err(|_| unauthorized())?;
+    let (_, permissions) = find_login_grants(pool, owner)?;
+    Ok(AuthUser {
+        id: user.id.to_string(),
+        email: user.email,
+        roles: vec![],
+        permissions: api_key
+            .scopes()
+            .into_iter()
+            .filter(|scope| permissions.contains(scope))
+            .collect(),
+        impersonator: None,
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::models::mfa::tests::enable_mfa;
+    use crate::models::role::assign;
+    use crate::models::user::tests::create_user;
+    use crate::tests::helpers::tests::get_pool;
+    use chrono::Duration;
+
+    #[test]
+    fn it_creates_a_prefixed_api_key() {
+        let user = create_user().unwrap();
+        let (api_key, secret) = create(&get_pool(), user.id, "batch", &[], None).unwrap();
+        assert!(secret.starts_with(KEY_PREFIX));
+        assert!(secret.starts_with(&api_key.prefix));
+        assert_ne!(api_key.key_hash, secret);
+    }
+
+    #[test]
+    fn it_authenticates_with_an_api_key() {
+        let user = create_user().unwrap();
+        let (api_key, secret) = create(&get_pool(), user.id, "batch", &[], None).unwrap();
+        let principal = authenticate(&get_pool(), &secret).unwrap();
+        assert_eq!(principal.id, user.id.to_string());
+
+        let api_key = find(&get_pool(), user.id, Uuid::parse_str(&api_key.id).unwrap()).unwrap();
+        assert!(api_key.last_used_at.is_some());
+    }
+
+    #[test]
+    fn it_limits_an_api_key_to_its_owners_permissions() {
+        let user = create_user().unwrap();
+        assign(&get_pool(), user.id, "admin").unwrap();
+        enable_mfa(user.id);
+        let scopes = vec!["user:delete".to_string(), "not:granted".to_string()];
+        let (_, secret) = create(&get_pool(), user.id, "batch", &scopes, None).unwrap();
+        let principal = authenticate(&get_pool(), &secret).unwrap();
+        assert_eq!(principal.permissions, vec!["user:delete".to_string()]);
+    }
+
+    #[test]
+    fn it_doesnt_authenticate_with_an_expired_api_key() {
+        let user = create_user().unwrap();
+        let expired = Some((Utc::now() - Duration::minutes(1)).naive_utc());
+        let (_, secret) = create(&get_pool(), user.id, "batch", &[], expired).unwrap();
+        assert!(authenticate(&get_pool(), &secret).is_err());
+    }
+
+    #[test]
+    fn it_doesnt_authenticate_with_a_deleted_api_key() {
+        let user = create_user().unwrap();
+        let (api_key, secret) = create(&get_pool(), user.id, "batch", &[], None).unwrap();
+        delete(&get_pool(), user.id, Uuid::parse_str(&api_key.id).unwrap()).unwrap();
+        assert!(authenticate(&get_pool(), &secret).is_err());
+    }
+
+    #[test]
+    fn it_doesnt_find_another_users_api_key() {
+        let user = create_user().unwrap();
+        let (api_key, _) = create(&get_pool(), user.id, "batch", &[], None).unwrap();
+        let api_key_id = Uuid::parse_str(&api_key.id).unwrap();
+        assert!(find(&get_pool(), Uuid::new_v4(), api_key_id).is_err());
+        assert!(delete(&get_pool(), Uuid::new_v4(), api_key_id).is_err());
+    }
+}
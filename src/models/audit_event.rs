@@ -0,0 +1,132 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::audit::AuditEventsResponse;
+use crate::pagination::{paginate, Paginate, PaginationRequest, PaginationResponse};
+use crate::schema::audit_events;
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+/// A security-relevant action, see audit
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+pub struct AuditEvent {
+    pub id: String,
+    pub actor_id: Option<String>,
+    pub impersonator_id: Option<String>,
+    pub action: String,
+    pub target_id: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub metadata: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Narrow down the audit events returned by get_all
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditEventFilter {
+    pub actor_id: Option<String>,
+    pub action: Option<String>,
+    pub target_id: Option<String>,
+}
+
+/// Record an audit event
+pub fn create(pool: &PoolType, audit_event: &AuditEvent) -> Result<(), ApiError> {
+    use crate::schema::audit_events::dsl::audit_events;
+
+    let conn = pool.get()?;
+    diesel::insert_into(audit_events)
+        .values(audit_event)
+        .execute(&conn)?;
+    Ok(())
+}
+
+/// Get the audit events matching a filter, most recent first
+/// Paginate the results
+pub fn get_all(
+    pool: &PoolType,
+    filter: &AuditEventFilter,
+    params: PaginationRequest,
+    base: String,
+) -> Result<PaginationResponse<AuditEventsResponse>, ApiError> {
+    use crate::schema::audit_events::dsl::{
+        action, actor_id, audit_events, created_at, id, target_id,
+    };
+
+    let mut query = audit_events.into_boxed();
+    if let Some(actor) = &filter.actor_id {
+        query = query.filter(actor_id.eq(actor));
+    }
+    if let Some(event_action) = &filter.action {
+        query = query.filter(action.eq(event_action));
+    }
+    if let Some(target) = &filter.target_id {
+        query = query.filter(target_id.eq(target));
+    }
+
+    let conn = pool.get()?;
+    let (events, pagination) = query
+        .order((created_at.desc(), id.desc()))
+        .paginate(params)
+        .load_with_total::<AuditEvent, _>(&conn)?;
+
+    paginate(pagination, events.into(), base)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::get_pool;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    pub fn create_audit_event(event_action: &str, target: &str) -> AuditEvent {
+        let audit_event = AuditEvent {
+            id: Uuid::new_v4().to_string(),
+            actor_id: Some(Uuid::new_v4().to_string()),
+            impersonator_id: None,
+            action: event_action.into(),
+            target_id: Some(target.into()),
+            ip: Some("127.0.0.1".into()),
+            user_agent: None,
+            metadata: "{}".into(),
+            created_at: Utc::now().naive_utc(),
+        };
+        create(&get_pool(), &audit_event).unwrap();
+        audit_event
+    }
+
+    fn get_params() -> PaginationRequest {
+        PaginationRequest {
+            page: Some(1),
+            per_page: Some(10),
+        }
+    }
+
+    #[test]
+    fn it_filters_audit_events() {
+        let target = Uuid::new_v4().to_string();
+        create_audit_event("user.update", &target);
+        let deleted = create_audit_event("user.delete", &target);
+        create_audit_event("user.delete", &Uuid::new_v4().to_string());
+
+        let filter = AuditEventFilter {
+            action: Some("user.delete".into()),
+            target_id: Some(target),
+            ..Default::default()
+        };
+        let response = get_all(&get_pool(), &filter, get_params(), "".into()).unwrap();
+        assert_eq!(response.pagination.total, 1);
+        assert_eq!(response.data.0[0].id.to_string(), deleted.id);
+    }
+
+    #[test]
+    fn it_paginates_audit_events() {
+        let response = get_all(
+            &get_pool(),
+            &AuditEventFilter::default(),
+            get_params(),
+            "".into(),
+        )
+        .unwrap();
+        assert!(response.data.0.len() <= 10);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::auth::{generate_token, hash_token};
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::schema::email_verifications;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+pub struct EmailVerification {
+    pub id: String,
+    pub user_id: String,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Create an email verification token for a user
+/// Returns the plain text token, only the hash is stored
+pub fn create(pool: &PoolType, user_id: Uuid) -> Result<String, ApiError> {
+    use crate::schema::email_verifications::dsl::email_verifications;
+
+    let token = generate_token();
+    let now = Utc::now().naive_utc();
+    let email_verification = EmailVerification {
+        id: Uuid::new_v4().to_string(),
+        user_id: user_id.to_string(),
+        token_hash: hash_token(&token),
+        expires_at: now + Duration::minutes(CONFIG.email_verification_expiration),
+        used_at: None,
+        created_at: now,
+    };
+
+    let conn = pool.get()?;
+    diesel::insert_into(email_verifications)
+        .values(&email_verification)
+        .execute(&conn)?;
+    Ok(token)
+}
+
+/// Use up an email verification token, returning the user's id
+///
+/// Each token can only be used once.  Using a token also uses up any other
+/// outstanding tokens for the user.
+pub fn redeem(pool: &PoolType, token: &str) -> Result<Uuid, ApiError> {
+    use crate::schema::email_verifications::dsl::{
+        email_verifications, id, token_hash, used_at, user_id,
+    };
+
+    let invalid = || ApiError::BadRequest("Invalid or expired email verification token".into());
+    let now = Utc::now().naive_utc();
+    let conn = pool.get()?;
+    let email_verification = email_verifications
+        .filter(token_hash.eq(hash_token(token)))
+        .first::<EmailVerification>(&conn)
+        .map_err(|_| invalid())?;
+
+    if email_verification.expires_at < now {
+        return Err(invalid());
+    }
+
+    // Only one request can claim the token, even if used concurrently
+    let redeemed = diesel::update(email_verifications)
+        .filter(id.eq(email_verification.id.clone()))
+        .filter(used_at.is_null())
+        .set(used_at.eq(now))
+        .execute(&conn)?;
+
+    if redeemed == 0 {
+        return Err(invalid());
+    }
+
+    diesel::update(email_verifications)
+        .filter(user_id.eq(email_verification.user_id.clone()))
+        .filter(used_at.is_null())
+        .set(used_at.eq(now))
+        .execute(&conn)?;
+
+    Ok(Uuid::parse_str(&email_verification.user_id)?)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::get_pool;
+
+    #[test]
+    fn it_redeems_a_email_verification_token() {
+        let user_id = Uuid::new_v4();
+        let token = create(&get_pool(), user_id).unwrap();
+        let redeemed_user_id = redeem(&get_pool(), &token).unwrap();
+        assert_eq!(redeemed_user_id, user_id);
+    }
+
+    #[test]
+    fn it_doesnt_redeem_a_email_verification_token_twice() {
+        let token = create(&get_pool(), Uuid::new_v4()).unwrap();
+        redeem(&get_pool(), &token).unwrap();
+        assert!(redeem(&get_pool(), &token).is_err());
+    }
+
+    #[test]
+    fn it_uses_up_other_email_verification_tokens_for_the_user() {
+        let user_id = Uuid::new_v4();
+        let token = create(&get_pool(), user_id).unwrap();
+        let other_token = create(&get_pool(), user_id).unwrap();
+        redeem(&get_pool(), &token).unwrap();
+        assert!(redeem(&get_pool(), &other_token).is_err());
+    }
+
+    #[test]
+    fn it_doesnt_redeem_an_unknown_email_verification_token() {
+        assert!(redeem(&get_pool(), "not-a-token").is_err());
+    }
+}
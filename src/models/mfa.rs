@@ -0,0 +1,279 @@
+use crate::auth::{generate_token, hash_token};
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::schema::{mfa_recovery_codes, user_mfa};
+use crate::totp;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+/// The number of recovery codes issued when MFA is confirmed
+const RECOVERY_CODES: usize = 10;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Insertable)]
+#[table_name = "user_mfa"]
+pub struct UserMfa {
+    pub user_id: String,
+    pub secret: String,
+    pub confirmed_at: Option<NaiveDateTime>,
+    pub last_used_step: Option<i64>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Insertable)]
+pub struct MfaRecoveryCode {
+    pub id: String,
+    pub user_id: String,
+    pub code_hash: String,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Start MFA enrollment for a user, returning a new TOTP secret
+///
+/// MFA isn't enabled until the secret is confirmed with a code, so enrolling
+/// again before confirming replaces the secret.
+pub fn enroll(pool: &PoolType, user: Uuid) -> Result<String, ApiError> {
+    use crate::schema::user_mfa::dsl::{user_id, user_mfa};
+
+    if is_enabled(pool, user)? {
+        return Err(ApiError::BadRequest("MFA is already enabled".into()));
+    }
+
+    let secret = totp::generate_secret();
+    let conn = pool.get()?;
+    diesel::delete(user_mfa)
+        .filter(user_id.eq(user.to_string()))
+        .execute(&conn)?;
+    diesel::insert_into(user_mfa)
+        .values(&UserMfa {
+            user_id: user.to_string(),
+            secret: secret.clone(),
+            confirmed_at: None,
+            last_used_step: None,
+            created_at: Utc::now().naive_utc(),
+        })
+        .execute(&conn)?;
+    Ok(secret)
+}
+
+/// Enable MFA by confirming the enrolled secret with a TOTP code
+/// Returns the plain text recovery codes, only their hashes are stored
+pub fn confirm(pool: &PoolType, user: Uuid, code: &str) -> Result<Vec<String>, ApiError> {
+    use crate::schema::user_mfa::dsl::{confirmed_at, last_used_step, user_id, user_mfa};
+
+    let not_enrolled = || ApiError::BadRequest("MFA enrollment not started".into());
+    let conn = pool.get()?;
+    let mfa = user_mfa
+        .filter(user_id.eq(user.to_string()))
+        .first::<UserMfa>(&conn)
+        .map_err(|_| not_enrolled())?;
+
+    if mfa.confirmed_at.is_some() {
+        return Err(ApiError::BadRequest("MFA is already enabled".into()));
+    }
+
+    let step = totp::verify(&mfa.secret, code, mfa.last_used_step).ok_or_else(invalid_code)?;
+    diesel::update(user_mfa)
+        .filter(user_id.eq(user.to_string()))
+        .set((
+            confirmed_at.eq(Utc::now().naive_utc()),
+            last_used_step.eq(step),
+        ))
+        .execute(&conn)?;
+
+    create_recovery_codes(pool, user)
+}
+
+/// Determine if a user has confirmed MFA
+pub fn is_enabled(pool: &PoolType, user: Uuid) -> Result<bool, ApiError> {
+    use crate::schema::user_mfa::dsl::{confirmed_at, user_id, user_mfa};
+
+    let conn = pool.get()?;
+    let enabled = user_mfa
+        .filter(user_id.eq(user.to_string()))
+        .filter(confirmed_at.is_not_null())
+        .select(user_id)
+        .first::<String>(&conn)
+        .optional()?;
+    Ok(enabled.is_some())
+}
+
+/// Check a TOTP code or an unused recovery code for a user
+/// Each code can only be used once
+pub fn verify(pool: &PoolType, user: Uuid, code: &str) -> Result<(), ApiError> {
+    use crate::schema::user_mfa::dsl::{confirmed_at, last_used_step, user_id, user_mfa};
+
+    let conn = pool.get()?;
+    let mfa = user_mfa
+        .filter(user_id.eq(user.to_string()))
+        .filter(confirmed_at.is_not_null())
+        .first::<UserMfa>(&conn)
+        .map_err(|_| invalid_code())?;
+
+    if let Some(step) = totp::verify(&mfa.secret, code, mfa.last_used_step) {
+        // Only one request can use the code, even if used concurrently
+        let used = diesel::update(user_mfa)
+            .filter(user_id.eq(user.to_string()))
+            .filter(last_used_step.is_null().or(last_used_step.lt(step)))
+            .set(last_used_step.eq(step))
+            .execute(&conn)?;
+
+        return if used == 1 {
+            Ok(())
+        } else {
+            Err(invalid_code())
+        };
+    }
+
+    use_recovery_code(pool, user, code)
+}
+
+/// Disable MFA for a user, removing their secret and recovery codes
+pub fn disable(pool: &PoolType, user: Uuid) -> Result<(), ApiError> {
+    use crate::schema::mfa_recovery_codes::dsl::{
+        mfa_recovery_codes, user_id as recovery_code_user_id,
+    };
+    use crate::schema::user_mfa::dsl::{user_id, user_mfa};
+
+    let conn = pool.get()?;
+    diesel::delete(user_mfa)
+        .filter(user_id.eq(user.to_string()))
+        .execute(&conn)?;
+    diesel::delete(mfa_recovery_codes)
+        .filter(recovery_code_user_id.eq(user.to_string()))
+        .execute(&conn)?;
+    Ok(())
+}
+
+/// Replace a user's recovery codes
+fn create_recovery_codes(pool: &PoolType, user: Uuid) -> Result<Vec<String>, ApiError> {
+    use crate::schema::mfa_recovery_codes::dsl::{mfa_recovery_codes, user_id};
+
+    let conn = pool.get()?;
+    diesel::delete(mfa_recovery_codes)
+        .filter(user_id.eq(user.to_string()))
+        .execute(&conn)?;
+
+    let now = Utc::now().naive_utc();
+    let codes = (0..RECOVERY_CODES)
+        .map(|_| format_recovery_code(&generate_token()))
+        .collect::<Vec<String>>();
+    let recovery_codes = codes
+        .iter()
+        .map(|code| MfaRecoveryCode {
+            id: Uuid::new_v4().to_string(),
+            user_id: user.to_string(),
+            code_hash: hash_recovery_code(code),
+            used_at: None,
+            created_at: now,
+        })
+        .collect::<Vec<MfaRecoveryCode>>();
+
+    diesel::insert_into(mfa_recovery_codes)
+        .values(&recovery_codes)
+        .execute(&conn)?;
+    Ok(codes)
+}
+
+fn use_recovery_code(pool: &PoolType, user: Uuid, code: &str) -> Result<(), ApiError> {
+    use crate::schema::mfa_recovery_codes::dsl::{code_hash, mfa_recovery_codes, used_at, user_id};
+
+    let conn = pool.get()?;
+    let used = diesel::update(mfa_recovery_codes)
+        .filter(user_id.eq(user.to_string()))
+        .filter(code_hash.eq(hash_recovery_code(code)))
+        .filter(used_at.is_null())
+        .set(used_at.eq(Utc::now().naive_utc()))
+        .execute(&conn)?;
+
+    if used == 0 {
+        return Err(invalid_code());
+    }
+    Ok(())
+}
+
+/// Format the first 16 characters of a token as xxxx-xxxx-xxxx-xxxx
+fn format_recovery_code(token: &str) -> String {
+    token[..16]
+        .as_bytes()
+        .chunks(4)
+        .map(|chunk| String::from_utf8_lossy(chunk).to_string())
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+/// Hash a recovery code, ignoring case and dashes
+fn hash_recovery_code(code: &str) -> String {
+    let normalized = code.replace('-', "").to_lowercase();
+    hash_token(&normalized)
+}
+
+fn invalid_code() -> ApiError {
+    ApiError::Unauthorized("Invalid MFA code".into())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::get_pool;
+
+    /// Enroll and confirm MFA for a user, returning the secret and recovery codes
+    pub fn enable_mfa(user: Uuid) -> (String, Vec<String>) {
+        let secret = enroll(&get_pool(), user).unwrap();
+        let code = totp::code_at(&secret, totp::current_step() - 1).unwrap();
+        let recovery_codes = confirm(&get_pool(), user, &code).unwrap();
+        (secret, recovery_codes)
+    }
+
+    #[test]
+    fn it_enables_mfa() {
+        let user = Uuid::new_v4();
+        assert!(!is_enabled(&get_pool(), user).unwrap());
+        let (_, recovery_codes) = enable_mfa(user);
+        assert!(is_enabled(&get_pool(), user).unwrap());
+        assert_eq!(recovery_codes.len(), RECOVERY_CODES);
+    }
+
+    #[test]
+    fn it_doesnt_confirm_mfa_with_an_invalid_code() {
+        let user = Uuid::new_v4();
+        enroll(&get_pool(), user).unwrap();
+        assert!(confirm(&get_pool(), user, "000000x").is_err());
+        assert!(!is_enabled(&get_pool(), user).unwrap());
+    }
+
+    #[test]
+    fn it_verifies_a_totp_code_once() {
+        let user = Uuid::new_v4();
+        let (secret, _) = enable_mfa(user);
+        let code = totp::code_at(&secret, totp::current_step()).unwrap();
+        assert!(verify(&get_pool(), user, &code).is_ok());
+        assert!(verify(&get_pool(), user, &code).is_err());
+    }
+
+    #[test]
+    fn it_verifies_a_recovery_code_once() {
+        let user = Uuid::new_v4();
+        let (_, recovery_codes) = enable_mfa(user);
+        let code = recovery_codes[0].to_uppercase();
+        assert!(verify(&get_pool(), user, &code).is_ok());
+        assert!(verify(&get_pool(), user, &code).is_err());
+        assert!(verify(&get_pool(), user, &recovery_codes[1]).is_ok());
+    }
+
+    #[test]
+    fn it_disables_mfa() {
+        let user = Uuid::new_v4();
+        let (_, recovery_codes) = enable_mfa(user);
+        disable(&get_pool(), user).unwrap();
+        assert!(!is_enabled(&get_pool(), user).unwrap());
+        assert!(verify(&get_pool(), user, &recovery_codes[0]).is_err());
+    }
+
+    #[test]
+    fn it_formats_a_recovery_code() {
+        let code = format_recovery_code("0123456789abcdef0123");
+        assert_eq!(code, "0123-4567-89ab-cdef");
+    }
+}
@@ -1,3 +1,12 @@
 //! Business logic and database access
 
+pub mod api_key;
+pub mod audit_event;
+pub mod email_verification;
+pub mod mfa;
+pub mod password_reset;
+pub mod refresh_token;
+pub mod role;
+pub mod session;
 pub mod user;
+pub mod user_identity;
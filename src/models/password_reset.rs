@@ -0,0 +1,143 @@
+use crate::auth::{generate_token, hash_token};
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::schema::password_resets;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+pub struct PasswordReset {
+    pub id: String,
+    pub user_id: String,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Create a password reset token for a user
+/// Returns the plain text token, only the hash is stored
+pub fn create(pool: &PoolType, user_id: Uuid) -> Result<String, ApiError> {
+    use crate::schema::password_resets::dsl::password_resets;
+
+    let token = generate_token();
+    let now = Utc::now().naive_utc();
+    let password_reset = PasswordReset {
+        id: Uuid::new_v4().to_string(),
+        user_id: user_id.to_string(),
+        token_hash: hash_token(&token),
+        expires_at: now + Duration::minutes(CONFIG.password_reset_expiration),
+        used_at: None,
+        created_at: now,
+    };
+
+    let conn = pool.get()?;
+    diesel::insert_into(password_resets)
+        .values(&password_reset)
+        .execute(&conn)?;
+    Ok(token)
+}
+
+/// Find the user a password reset token belongs to, without using it up
+/// Expired and used tokens are rejected
+pub fn find_user(pool: &PoolType, token: &str) -> Result<Uuid, ApiError> {
+    use crate::schema::password_resets::dsl::{password_resets, token_hash};
+
+    let invalid = || ApiError::BadRequest("Invalid or expired password reset token".into());
+    let conn = pool.get()?;
+    let password_reset = password_resets
+        .filter(token_hash.eq(hash_token(token)))
+        .first::<PasswordReset>(&conn)
+        .map_err(|_| invalid())?;
+
+    if password_reset.expires_at < Utc::now().naive_utc() || password_reset.used_at.is_some() {
+        return Err(invalid());
+    }
+
+    Ok(Uuid::parse_str(&password_reset.user_id)?)
+}
+
+/// Use up a password reset token, returning the user's id
+///
+/// Each token can only be used once.  Using a token also uses up any other
+/// outstanding tokens for the user.
+pub fn redeem(pool: &PoolType, token: &str) -> Result<Uuid, ApiError> {
+    use crate::schema::password_resets::dsl::{id, password_resets, token_hash, used_at, user_id};
+
+    let invalid = || ApiError::BadRequest("Invalid or expired password reset token".into());
+    let now = Utc::now().naive_utc();
+    let conn = pool.get()?;
+    let password_reset = password_resets
+        .filter(token_hash.eq(hash_token(token)))
+        .first::<PasswordReset>(&conn)
+        .map_err(|_| invalid())?;
+
+    if password_reset.expires_at < now {
+        return Err(invalid());
+    }
+
+    // Only one request can claim the token, even if used concurrently
+    let redeemed = diesel::update(password_resets)
+        .filter(id.eq(password_reset.id.clone()))
+        .filter(used_at.is_null())
+        .set(used_at.eq(now))
+        .execute(&conn)?;
+
+    if redeemed == 0 {
+        return Err(invalid());
+    }
+
+    diesel::update(password_resets)
+        .filter(user_id.eq(password_reset.user_id.clone()))
+        .filter(used_at.is_null())
+        .set(used_at.eq(now))
+        .execute(&conn)?;
+
+    Ok(Uuid::parse_str(&password_reset.user_id)?)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::get_pool;
+
+    #[test]
+    fn it_redeems_a_password_reset_token() {
+        let user_id = Uuid::new_v4();
+        let token = create(&get_pool(), user_id).unwrap();
+        let redeemed_user_id = redeem(&get_pool(), &token).unwrap();
+        assert_eq!(redeemed_user_id, user_id);
+    }
+
+    #[test]
+    fn it_finds_the_user_of_an_unused_password_reset_token() {
+        let user_id = Uuid::new_v4();
+        let token = create(&get_pool(), user_id).unwrap();
+        assert_eq!(find_user(&get_pool(), &token).unwrap(), user_id);
+        redeem(&get_pool(), &token).unwrap();
+        assert!(find_user(&get_pool(), &token).is_err());
+    }
+
+    #[test]
+    fn it_doesnt_redeem_a_password_reset_token_twice() {
+        let token = create(&get_pool(), Uuid::new_v4()).unwrap();
+        redeem(&get_pool(), &token).unwrap();
+        assert!(redeem(&get_pool(), &token).is_err());
+    }
+
+    #[test]
+    fn it_uses_up_other_password_reset_tokens_for_the_user() {
+        let user_id = Uuid::new_v4();
+        let token = create(&get_pool(), user_id).unwrap();
+        let other_token = create(&get_pool(), user_id).unwrap();
+        redeem(&get_pool(), &token).unwrap();
+        assert!(redeem(&get_pool(), &other_token).is_err());
+    }
+
+    #[test]
+    fn it_doesnt_redeem_an_unknown_password_reset_token() {
+        assert!(redeem(&get_pool(), "not-a-token").is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use crate::auth::{generate_token, hash_token};
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::session::revoke as revoke_session;
+use crate::schema::refresh_tokens;
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+pub struct RefreshToken {
+    pub id: String,
+    pub user_id: String,
+    pub family_id: String,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub rotated_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+}
+
+/// The outcome of exchanging a refresh token, see rotate
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rotation {
+    /// The user's id, the session's id and the new plain text token
+    Rotated(Uuid, Uuid, String),
+    /// The token was already used, so its session has been revoked
+    Reused(Uuid),
+}
+
+/// Create a refresh token for a user, starting a new token family
+/// The family is the user's session, see models::session.
+/// Returns the plain text token, only the hash is stored
+pub fn create(pool: &PoolType, user_id: Uuid, session_id: Uuid) -> Result<String, ApiError> {
+    insert(pool, &user_id.to_string(), &session_id.to_string())
+}
+
+/// Exchange a refresh token for a new one in the same family
+///
+/// Each refresh token can only be used once. If a token that was already
+/// rotated is presented again, it has likely been stolen, so the session
+/// and its entire family are revoked, logging out both the attacker and
+/// the user.  Its JWTs are left to the caller, see auth::revoke_session.
+pub fn rotate(pool: &PoolType, token: &str) -> Result<Rotation, ApiError> {
+    use crate::schema::refresh_tokens::dsl::{id, refresh_tokens, rotated_at, token_hash};
+
+    let unauthorized = || ApiError::Unauthorized("Invalid refresh token".into());
+    let now = Utc::now().naive_utc();
+    let conn = pool.get()?;
+    let refresh_token = refresh_tokens
+        .filter(token_hash.eq(hash_token(token)))
+        .first::<RefreshToken>(&conn)
+        .map_err(|_| unauthorized())?;
+
+    if refresh_token.revoked_at.is_some() || refresh_token.expires_at < now {
+        return Err(unauthorized());
+    }
+
+    // Only one request can claim the token, even if used concurrently
+    let rotated = diesel::update(refresh_tokens)
+        .filter(id.eq(refresh_token.id.clone()))
+        .filter(rotated_at.is_null())
+        .set(rotated_at.eq(now))
+        .execute(&conn)?;
+
+    let user_id = Uuid::parse_str(&refresh_token.user_id)?;
+    let session_id = Uuid::parse_str(&refresh_token.family_id)?;
+    if rotated == 0 {
+        match revoke_session(pool, user_id, session_id) {
+            Err(ApiError::NotFound(_)) => revoke_family(pool, &refresh_token.family_id)?,
+            revoked => revoked?,
+        }
+        return Ok(Rotation::Reused(session_id));
+    }
+
+    let new_token = insert(pool, &refresh_token.user_id, &refresh_token.family_id)?;
+    Ok(Rotation::Rotated(user_id, session_id, new_token))
+}
+
+/// Revoke every refresh token in a family
+pub fn revoke_family(pool: &PoolType, family: &str) -> Result<(), ApiError> {
+    use crate::schema::refresh_tokens::dsl::{family_id, refresh_tokens, revoked_at};
+
+    let conn = pool.get()?;
+    diesel::update(refresh_tokens)
+        .filter(family_id.eq(family.to_string()))
+        .filter(revoked_at.is_null())
+        .set(revoked_at.eq(Utc::now().naive_utc()))
+        .execute(&conn)?;
+    Ok(())
+}
+
+/// Revoke every refresh token issued to a user
+pub fn revoke_all(pool: &PoolType, user: Uuid) -> Result<(), ApiError> {
+    use crate::schema::refresh_tokens::dsl::{refresh_tokens, revoked_at, user_id};
+
+    let conn = pool.get()?;
+    diesel::update(refresh_tokens)
+        .filter(user_id.eq(user.to_string()))
+        .filter(revoked_at.is_null())
+        .set(revoked_at.eq(Utc::now().naive_utc()))
+        .execute(&conn)?;
+    Ok(())
+}
+
+/// Store the hash of a new refresh token
+fn insert(pool: &PoolType, user_id: &str, family_id: &str) -> Result<String, ApiError> {
+    use crate::schema::refresh_tokens::dsl::refresh_tokens;
+
+    let token = generate_token();
+    let now = Utc::now().naive_utc();
+    let refresh_token = RefreshToken {
+        id: Uuid::new_v4().to_string(),
+        user_id: user_id.into(),
+        family_id: family_id.into(),
+        token_hash: hash_token(&token),
+        expires_at: now + Duration::days(CONFIG.refresh_token_expiration),
+        rotated_at: None,
+        revoked_at: None,
+        created_at: now,
+    };
+
+    let conn = pool.get()?;
+    diesel::insert_into(refresh_tokens)
+        .values(&refresh_token)
+        .execute(&conn)?;
+    Ok(token)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::models::session;
+    use crate::tests::helpers::tests::get_pool;
+
+    #[test]
+    fn it_rotates_a_refresh_token() {
+        let user_id = Uuid::new_v4();
+        let token = create(&get_pool(), user_id, Uuid::new_v4()).unwrap();
+        match rotate(&get_pool(), &token).unwrap() {
+            Rotation::Rotated(rotated_user_id, _, rotated) => {
+                assert_eq!(rotated_user_id, user_id);
+                assert_ne!(rotated, token);
+            }
+            rotation => panic!("expected the token to be rotated, got {:?}", rotation),
+        }
+    }
+
+    #[test]
+    fn it_rejects_an_unknown_refresh_token() {
+        let rotated = rotate(&get_pool(), "not-a-token");
+        assert!(rotated.is_err());
+    }
+
+    fn rotated(token: &str) -> String {
+        match rotate(&get_pool(), token).unwrap() {
+            Rotation::Rotated(_, _, rotated) => rotated,
+            rotation => panic!("expected the token to be rotated, got {:?}", rotation),
+        }
+    }
+
+    #[test]
+    fn it_revokes_the_family_when_a_refresh_token_is_reused() {
+        let session_id = Uuid::new_v4();
+        let token = create(&get_pool(), Uuid::new_v4(), session_id).unwrap();
+        let rotated = rotated(&token);
+
+        // Reusing the original token is rejected and revokes the family
+        assert_eq!(
+            rotate(&get_pool(), &token).unwrap(),
+            Rotation::Reused(session_id)
+        );
+        assert!(rotate(&get_pool(), &rotated).is_err());
+    }
+
+    #[test]
+    fn it_revokes_the_session_when_a_refresh_token_is_reused() {
+        let user_id = Uuid::new_v4();
+        let session = session::create(&get_pool(), user_id, None, None).unwrap();
+        let session_id = Uuid::parse_str(&session.id).unwrap();
+        let token = create(&get_pool(), user_id, session_id).unwrap();
+        rotated(&token);
+        rotate(&get_pool(), &token).unwrap();
+        assert!(!session::is_active(&get_pool(), user_id, session_id).unwrap());
+    }
+
+    #[test]
+    fn it_revokes_all_refresh_tokens_for_a_user() {
+        let user_id = Uuid::new_v4();
+        let token = create(&get_pool(), user_id, Uuid::new_v4()).unwrap();
+        let other_token = create(&get_pool(), user_id, Uuid::new_v4()).unwrap();
+        revoke_all(&get_pool(), user_id).unwrap();
+        assert!(rotate(&get_pool(), &token).is_err());
+        assert!(rotate(&get_pool(), &other_token).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use crate::config::CONFIG;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::mfa::is_enabled as is_mfa_enabled;
+use crate::schema::user_roles;
+use diesel::prelude::*;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Insertable)]
+pub struct UserRole {
+    pub user_id: String,
+    pub role_id: String,
+}
+
+/// Find the names of the roles and permissions granted to a user
+pub fn find_grants(pool: &PoolType, user: Uuid) -> Result<(Vec<String>, Vec<String>), ApiError> {
+    load_grants(pool, user, &[])
+}
+
+/// Find the grants a user logs in with, see find_grants_requiring_mfa
+pub fn find_login_grants(
+    pool: &PoolType,
+    user: Uuid,
+) -> Result<(Vec<String>, Vec<String>), ApiError> {
+    find_grants_requiring_mfa(pool, user, &CONFIG.mfa_required_roles)
+}
+
+/// Find the grants of a user, leaving out the roles that require MFA (and the
+/// permissions only they grant) until the user has MFA enabled
+/// The user can still log in and enroll in MFA, see handlers::mfa
+pub fn find_grants_requiring_mfa(
+    pool: &PoolType,
+    user: Uuid,
+    mfa_roles: &[String],
+) -> Result<(Vec<String>, Vec<String>), ApiError> {
+    if mfa_roles.is_empty() || is_mfa_enabled(pool, user)? {
+        return find_grants(pool, user);
+    }
+    load_grants(pool, user, mfa_roles)
+}
+
+fn load_grants(
+    pool: &PoolType,
+    user: Uuid,
+    without_roles: &[String],
+) -> Result<(Vec<String>, Vec<String>), ApiError> {
+    use crate::schema::permissions::dsl::{name as permission_name, permissions};
+    use crate::schema::role_permissions::dsl::{role_id as permission_role_id, role_permissions};
+    use crate::schema::roles::dsl::{id as role_id, name as role_name, roles};
+    use crate::schema::user_roles::dsl::{role_id as user_role_id, user_id, user_roles};
+
+    let conn = pool.get()?;
+    let user_role_ids = user_roles
+        .filter(user_id.eq(user.to_string()))
+        .select(user_role_id)
+        .load::<String>(&conn)?;
+
+    let (granted_role_ids, granted_roles): (Vec<String>, Vec<String>) = roles
+        .filter(role_id.eq_any(&user_role_ids))
+        .filter(role_name.ne_all(without_roles))
+        .select((role_id, role_name))
+        .load::<(String, String)>(&conn)?
+        .into_iter()
+        .unzip();
+
+    let granted_permissions = role_permissions
+        .inner_join(permissions)
+        .filter(permission_role_id.eq_any(&granted_role_ids))
+        .select(permission_name)
+        .distinct()
+        .load::<String>(&conn)?;
+
+    Ok((granted_roles, granted_permissions))
+}
+
+/// Grant a role to a user by the role's name
+pub fn assign(pool: &PoolType, user: Uuid, role: &str) -> Result<(), ApiError> {
+    use crate::schema::roles::dsl::{id, name, roles};
+    use crate::schema::user_roles::dsl::user_roles;
+
+    let conn = pool.get()?;
+    let role_id = roles
+        .filter(name.eq(role.to_string()))
+        .select(id)
+        .first::<String>(&conn)
+        .map_err(|_| ApiError::NotFound(format!("Role {} not found", role)))?;
+
+    diesel::insert_into(user_roles)
+        .values(&UserRole {
+            user_id: user.to_string(),
+            role_id,
+        })
+        .execute(&conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::models::mfa::tests::enable_mfa;
+    use crate::models::user::tests::create_user;
+    use crate::tests::helpers::tests::get_pool;
+
+    #[test]
+    fn it_finds_the_grants_for_a_user() {
+        let user = create_user().unwrap();
+        assign(&get_pool(), user.id, "admin").unwrap();
+        let (roles, permissions) = find_grants(&get_pool(), user.id).unwrap();
+        assert_eq!(roles, vec!["admin".to_string()]);
+        assert!(permissions.contains(&"user:delete".to_string()));
+    }
+
+    #[test]
+    fn it_finds_no_grants_for_a_user_without_roles() {
+        let user = create_user().unwrap();
+        let (roles, permissions) = find_grants(&get_pool(), user.id).unwrap();
+        assert!(roles.is_empty());
+        assert!(permissions.is_empty());
+    }
+
+    #[test]
+    fn it_requires_mfa_for_a_role() {
+        let user = create_user().unwrap();
+        assign(&get_pool(), user.id, "admin").unwrap();
+        let mfa_roles = vec!["admin".to_string()];
+
+        let (roles, permissions) =
+            find_grants_requiring_mfa(&get_pool(), user.id, &mfa_roles).unwrap();
+        assert!(roles.is_empty());
+        assert!(permissions.is_empty());
+
+        enable_mfa(user.id);
+        let (roles, permissions) =
+            find_grants_requiring_mfa(&get_pool(), user.id, &mfa_roles).unwrap();
+        assert_eq!(roles, vec!["admin".to_string()]);
+        assert!(permissions.contains(&"user:delete".to_string()));
+    }
+
+    #[test]
+    fn it_doesnt_assign_an_unknown_role() {
+        let user = create_user().unwrap();
+        let assigned = assign(&get_pool(), user.id, "not-a-role");
+        assert!(assigned.is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::models::refresh_token::{revoke_all as revoke_all_refresh_tokens, revoke_family};
+use crate::schema::sessions;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+/// A login, from the password (or OIDC) step until it's revoked
+///
+/// The session's id is the family id of its refresh tokens, and is embedded
+/// in each JWT issued for it.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+/// Start a session for a user
+/// The user agent comes from helpers::user_agent, which truncates it
+pub fn create(
+    pool: &PoolType,
+    user: Uuid,
+    session_user_agent: Option<&str>,
+    session_ip: Option<&str>,
+) -> Result<Session, ApiError> {
+    use crate::schema::sessions::dsl::sessions;
+
+    let now = Utc::now().naive_utc();
+    let session = Session {
+        id: Uuid::new_v4().to_string(),
+        user_id: user.to_string(),
+        user_agent: session_user_agent.map(String::from),
+        ip: session_ip.map(String::from),
+        created_at: now,
+        last_seen_at: now,
+        revoked_at: None,
+    };
+
+    let conn = pool.get()?;
+    diesel::insert_into(sessions)
+        .values(&session)
+        .execute(&conn)?;
+    Ok(session)
+}
+
+/// Get a user's active sessions, most recently seen first
+pub fn find_all(pool: &PoolType, user: Uuid) -> Result<Vec<Session>, ApiError> {
+    use crate::schema::sessions::dsl::{last_seen_at, revoked_at, sessions, user_id};
+
+    let conn = pool.get()?;
+    let active = sessions
+        .filter(user_id.eq(user.to_string()))
+        .filter(revoked_at.is_null())
+        .order(last_seen_at.desc())
+        .load::<Session>(&conn)?;
+    Ok(active)
+}
+
+/// Determine if a session belongs to the user and hasn't been revoked
+pub fn is_active(pool: &PoolType, user: Uuid, session_id: Uuid) -> Result<bool, ApiError> {
+    use crate::schema::sessions::dsl::{id, revoked_at, sessions, user_id};
+
+    let conn = pool.get()?;
+    let active = sessions
+        .filter(id.eq(session_id.to_string()))
+        .filter(user_id.eq(user.to_string()))
+        .filter(revoked_at.is_null())
+        .count()
+        .get_result::<i64>(&conn)?;
+    Ok(active > 0)
+}
+
+/// Record that a session was just used
+pub fn touch(pool: &PoolType, session_id: Uuid) -> Result<(), ApiError> {
+    use crate::schema::sessions::dsl::{id, last_seen_at, sessions};
+
+    let conn = pool.get()?;
+    diesel::update(sessions)
+        .filter(id.eq(session_id.to_string()))
+        .set(last_seen_at.eq(Utc::now().naive_utc()))
+        .execute(&conn)?;
+    Ok(())
+}
+
+/// Revoke one of a user's active sessions, along with its refresh tokens
+pub fn revoke(pool: &PoolType, user: Uuid, session_id: Uuid) -> Result<(), ApiError> {
+    use crate::schema::sessions::dsl::{id, revoked_at, sessions, user_id};
+
+    let conn = pool.get()?;
+    let revoked = diesel::update(sessions)
+        .filter(id.eq(session_id.to_string()))
+        .filter(user_id.eq(user.to_string()))
+        .filter(revoked_at.is_null())
+        .set(revoked_at.eq(Utc::now().naive_utc()))
+        .execute(&conn)?;
+
+    if revoked == 0 {
+        return Err(ApiError::NotFound(format!(
+            "Session {} not found",
+            session_id
+        )));
+    }
+    revoke_family(pool, &session_id.to_string())
+}
+
+/// Revoke all of a user's sessions, along with their refresh tokens
+pub fn revoke_all(pool: &PoolType, user: Uuid) -> Result<(), ApiError> {
+    use crate::schema::sessions::dsl::{revoked_at, sessions, user_id};
+
+    let conn = pool.get()?;
+    diesel::update(sessions)
+        .filter(user_id.eq(user.to_string()))
+        .filter(revoked_at.is_null())
+        .set(revoked_at.eq(Utc::now().naive_utc()))
+        .execute(&conn)?;
+    revoke_all_refresh_tokens(pool, user)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::models::refresh_token::{create as create_refresh_token, rotate};
+    use crate::tests::helpers::tests::get_pool;
+
+    fn create_session(user_id: Uuid) -> Uuid {
+        let session = create(&get_pool(), user_id, Some("curl/7.64.1"), Some("127.0.0.1")).unwrap();
+        Uuid::parse_str(&session.id).unwrap()
+    }
+
+    #[test]
+    fn it_lists_active_sessions() {
+        let user_id = Uuid::new_v4();
+        let session_id = create_session(user_id);
+        create_session(user_id);
+        revoke(&get_pool(), user_id, session_id).unwrap();
+
+        let active = find_all(&get_pool(), user_id).unwrap();
+        assert_eq!(active.len(), 1);
+        assert_ne!(active[0].id, session_id.to_string());
+    }
+
+    #[test]
+    fn it_revokes_a_sessions_refresh_tokens() {
+        let user_id = Uuid::new_v4();
+        let session_id = create_session(user_id);
+        let token = create_refresh_token(&get_pool(), user_id, session_id).unwrap();
+        revoke(&get_pool(), user_id, session_id).unwrap();
+        assert!(rotate(&get_pool(), &token).is_err());
+    }
+
+    #[test]
+    fn it_doesnt_revoke_another_users_session() {
+        let session_id = create_session(Uuid::new_v4());
+        let revoked = revoke(&get_pool(), Uuid::new_v4(), session_id);
+        assert!(revoked.is_err());
+    }
+
+    #[test]
+    fn it_revokes_all_sessions() {
+        let user_id = Uuid::new_v4();
+        create_session(user_id);
+        create_session(user_id);
+        revoke_all(&get_pool(), user_id).unwrap();
+        assert!(find_all(&get_pool(), user_id).unwrap().is_empty());
+    }
+}
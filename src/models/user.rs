@@ -1,11 +1,13 @@
-use crate::auth::hash;
-use crate::database::PoolType;
+use crate::auth::{hash, needs_rehash, verify, PrivateClaim};
+use crate::database::{Backend, PoolType};
 use crate::errors::ApiError;
 use crate::handlers::user::{UserResponse, UsersResponse};
-use crate::pagination::{PaginationRequest, PaginationResponse};
+use crate::listing::{prefix_pattern, FieldType, FilterValue, ListRequest, ListSpec, Seek};
+use crate::pagination::{
+    get_per_page, paginate, paginate_by_cursor, CursorResponse, Paginate, PaginationResponse,
+};
 use crate::schema::users;
 use chrono::{NaiveDateTime, Utc};
-use diesel::dsl::count_star;
 use diesel::prelude::*;
 use proc_macro::timestamps;
 use uuid::Uuid;
@@ -18,6 +20,7 @@ pub struct User {
     pub last_name: String,
     pub email: String,
     pub password: String,
+    pub email_verified_at: Option<NaiveDateTime>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -45,29 +48,218 @@ pub struct UpdateUser {
 pub struct AuthUser {
     pub id: String,
     pub email: String,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+
+    /// The id of the admin acting as this user, see handlers::impersonation
+    pub impersonator: Option<String>,
+}
+
+/// Who made a change, recorded in the created_by and updated_by columns
+#[derive(Clone, Debug, PartialEq)]
+pub enum Actor {
+    /// A user, by id
+    User(String),
+    /// The framework itself, e.g. when signing up with an OIDC provider
+    System,
+}
+
+/// The created_by/updated_by value for changes made by the framework itself
+pub const SYSTEM_ACTOR: &str = "system";
+
+impl Actor {
+    pub fn id(&self) -> String {
+        match self {
+            Actor::User(user_id) => user_id.clone(),
+            Actor::System => SYSTEM_ACTOR.into(),
+        }
+    }
 }
 
-/// Get all users
+/// The admin is the actor while impersonating, not the impersonated user
+impl From<&AuthUser> for Actor {
+    fn from(user: &AuthUser) -> Self {
+        Actor::User(user.impersonator.clone().unwrap_or_else(|| user.id.clone()))
+    }
+}
+
+impl From<Uuid> for Actor {
+    fn from(user_id: Uuid) -> Self {
+        Actor::User(user_id.to_string())
+    }
+}
+
+impl AuthUser {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|granted| granted == role)
+    }
+
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|granted| granted == permission)
+    }
+}
+
+impl<T> From<PrivateClaim<T>> for AuthUser {
+    fn from(private_claim: PrivateClaim<T>) -> Self {
+        AuthUser {
+            id: private_claim.user_id.to_string(),
+            email: private_claim.email,
+            roles: private_claim.roles,
+            permissions: private_claim.permissions,
+            impersonator: private_claim
+                .impersonator
+                .map(|impersonator| impersonator.user_id.to_string()),
+        }
+    }
+}
+
+/// The fields users can be listed by, see listing
+pub const USER_LIST: ListSpec = ListSpec {
+    sortable: &[
+        ("first_name", FieldType::Text),
+        ("last_name", FieldType::Text),
+        ("email", FieldType::Text),
+        ("created_at", FieldType::DateTime),
+        ("updated_at", FieldType::DateTime),
+    ],
+    filterable: &[
+        ("first_name", FieldType::Text),
+        ("last_name", FieldType::Text),
+        ("email", FieldType::Text),
+        ("created_at", FieldType::DateTime),
+        ("updated_at", FieldType::DateTime),
+    ],
+    searchable: true,
+    default_sort: "created_at",
+};
+
+/// The users matching a list request's filters and search
+/// q= matches the start of the first name, last name or email
+fn filtered(params: &ListRequest) -> users::BoxedQuery<'static, Backend> {
+    use crate::schema::users::dsl::{created_at, email, first_name, last_name, updated_at, users};
+
+    let mut query = users.into_boxed();
+    for filter in &params.filters {
+        let (operator, value) = (filter.operator, &filter.value);
+        query = match filter.field.as_str() {
+            "first_name" => crate::filter_by!(query, first_name, operator, value.text()),
+            "last_name" => crate::filter_by!(query, last_name, operator, value.text()),
+            "email" => crate::filter_by!(query, email, operator, value.text()),
+            "created_at" => crate::filter_by!(query, created_at, operator, value.datetime()),
+            "updated_at" => crate::filter_by!(query, updated_at, operator, value.datetime()),
+            _ => query,
+        };
+    }
+    if let Some(search) = &params.search {
+        let pattern = prefix_pattern(search);
+        query = query.filter(
+            first_name
+                .like(pattern.clone())
+                .or(last_name.like(pattern.clone()))
+                .or(email.like(pattern)),
+        );
+    }
+    query
+}
+
+/// Get all users, filtered, searched and sorted
 /// Paginate the results
 pub fn get_all(
     pool: &PoolType,
-    params: PaginationRequest,
+    params: ListRequest,
     base: String,
 ) -> Result<PaginationResponse<UsersResponse>, ApiError> {
-    use crate::schema::users::dsl::users;
+    use crate::schema::users::dsl::{created_at, email, first_name, id, last_name, updated_at};
 
-    crate::paginate!(pool, users, User, params, UsersResponse, base)
+    let conn = pool.get()?;
+    let mut query = filtered(&params);
+    for sort in &params.sort {
+        query = match sort.field.as_str() {
+            "first_name" => crate::sort_by!(query, first_name, sort.direction),
+            "last_name" => crate::sort_by!(query, last_name, sort.direction),
+            "email" => crate::sort_by!(query, email, sort.direction),
+            "created_at" => crate::sort_by!(query, created_at, sort.direction),
+            "updated_at" => crate::sort_by!(query, updated_at, sort.direction),
+            _ => query,
+        };
+    }
+    let (listed, pagination) = query
+        .then_order_by(id.asc())
+        .paginate(params.pagination())
+        .load_with_total::<User, _>(&conn)?;
+
+    paginate(pagination, listed.into(), base)
+}
+
+/// Get a page of users after (or before) a cursor, filtered and searched
+/// Only counts the total when asked to
+pub fn get_all_by_cursor(
+    pool: &PoolType,
+    params: ListRequest,
+    base: String,
+) -> Result<CursorResponse<UsersResponse>, ApiError> {
+    use crate::schema::users::dsl::{created_at, email, first_name, id, last_name, updated_at};
+
+    let seek = params
+        .seek
+        .clone()
+        .unwrap_or_else(|| Seek::from(&USER_LIST));
+    let per_page = get_per_page(params.per_page);
+    let conn = pool.get()?;
+    let total = if params.total {
+        Some(filtered(&params).count().get_result(&conn)?)
+    } else {
+        None
+    };
+    let query = filtered(&params);
+    let query = match seek.sort.field.as_str() {
+        "first_name" => crate::seek_by!(query, first_name, id, &seek, text),
+        "last_name" => crate::seek_by!(query, last_name, id, &seek, text),
+        "email" => crate::seek_by!(query, email, id, &seek, text),
+        "updated_at" => crate::seek_by!(query, updated_at, id, &seek, datetime),
+        _ => crate::seek_by!(query, created_at, id, &seek, datetime),
+    };
+    let rows = query.limit(per_page + 1).load::<User>(&conn)?;
+
+    paginate_by_cursor(rows, &seek, per_page, total, base, |user: &User| {
+        let value = match seek.sort.field.as_str() {
+            "first_name" => FilterValue::Text(user.first_name.clone()),
+            "last_name" => FilterValue::Text(user.last_name.clone()),
+            "email" => FilterValue::Text(user.email.clone()),
+            "updated_at" => FilterValue::DateTime(user.updated_at),
+            _ => FilterValue::DateTime(user.created_at),
+        };
+        (value, user.id.clone())
+    })
 }
 
 /// Find a user by the user's id or issue a NOT_FOUND
 pub fn find(pool: &PoolType, user_id: Uuid) -> Result<UserResponse, ApiError> {
+    Ok(find_with_password(pool, user_id)?.into())
+}
+
+/// Find a user by the user's id, including their password hash
+/// Issue a NOT_FOUND if it doesn't exist
+pub fn find_with_password(pool: &PoolType, user_id: Uuid) -> Result<User, ApiError> {
     use crate::schema::users::dsl::{id, users};
 
     let not_found = format!("User {} not found", user_id);
     let conn = pool.get()?;
-    let user = users
+    users
         .filter(id.eq(user_id.to_string()))
         .first::<User>(&conn)
+        .map_err(|_| ApiError::NotFound(not_found))
+}
+
+/// Find a user by the user's email or issue a NOT_FOUND
+pub fn find_by_email(pool: &PoolType, user_email: &str) -> Result<UserResponse, ApiError> {
+    use crate::schema::users::dsl::{email, users};
+
+    let not_found = format!("User {} not found", user_email);
+    let conn = pool.get()?;
+    let user = users
+        .filter(email.eq(user_email.to_string()))
+        .first::<User>(&conn)
         .map_err(|_| ApiError::NotFound(not_found))?;
 
     Ok(user.into())
@@ -75,19 +267,42 @@ pub fn find(pool: &PoolType, user_id: Uuid) -> Result<UserResponse, ApiError> {
 
 /// Find a user by the user's authentication information (email + password)
 /// Return an Unauthorized error if it doesn't match
+///
+/// Passwords are verified against the stored hash rather than in SQL.
+/// Hashes using outdated parameters are transparently replaced.
+///
+/// When require_verified is set, users who haven't verified their email
+/// are rejected with an EmailNotVerified error.
 pub fn find_by_auth(
     pool: &PoolType,
     user_email: &str,
     user_password: &str,
+    require_verified: bool,
 ) -> Result<UserResponse, ApiError> {
-    use crate::schema::users::dsl::{email, password, users};
+    use crate::schema::users::dsl::{email, id, password, users};
 
+    let unauthorized = || ApiError::Unauthorized("Invalid login".into());
     let conn = pool.get()?;
     let user = users
         .filter(email.eq(user_email.to_string()))
-        .filter(password.eq(user_password.to_string()))
         .first::<User>(&conn)
-        .map_err(|_| ApiError::Unauthorized("Invalid login".into()))?;
+        .map_err(|_| unauthorized())?;
+
+    if !verify(user_password, &user.password) {
+        return Err(unauthorized());
+    }
+
+    if require_verified && user.email_verified_at.is_none() {
+        return Err(ApiError::EmailNotVerified("Email not verified".into()));
+    }
+
+    if needs_rehash(&user.password) {
+        diesel::update(users)
+            .filter(id.eq(user.id.clone()))
+            .set(password.eq(hash(user_password)))
+            .execute(&conn)?;
+    }
+
     Ok(user.into())
 }
 
@@ -102,27 +317,87 @@ pub fn create(pool: &PoolType, new_user: &User) -> Result<UserResponse, ApiError
 
 /// Update a user
 pub fn update(pool: &PoolType, update_user: &UpdateUser) -> Result<UserResponse, ApiError> {
-    use crate::schema::users::dsl::{id, users};
+    use crate::schema::users::dsl::{email, email_verified_at, id, users};
+
+    let conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|| {
+        // A new email isn't verified until the user proves they own it
+        diesel::update(users)
+            .filter(id.eq(update_user.id.clone()))
+            .filter(email.ne(update_user.email.clone()))
+            .set(email_verified_at.eq(None::<NaiveDateTime>))
+            .execute(&conn)?;
+        diesel::update(users)
+            .filter(id.eq(update_user.id.clone()))
+            .set(update_user)
+            .execute(&conn)?;
+        Ok(())
+    })?;
+    find(&pool, Uuid::parse_str(&update_user.id)?)
+}
+
+/// Replace a user's password, hashing the new one
+pub fn update_password(
+    pool: &PoolType,
+    user_id: Uuid,
+    new_password: &str,
+    actor: &Actor,
+) -> Result<(), ApiError> {
+    use crate::schema::users::dsl::{id, password, updated_at, updated_by, users};
 
     let conn = pool.get()?;
     diesel::update(users)
-        .filter(id.eq(update_user.id.clone()))
-        .set(update_user)
+        .filter(id.eq(user_id.to_string()))
+        .set((
+            password.eq(hash(new_password)),
+            updated_by.eq(actor.id()),
+            updated_at.eq(Utc::now().naive_utc()),
+        ))
         .execute(&conn)?;
-    find(&pool, Uuid::parse_str(&update_user.id)?)
+    Ok(())
 }
 
-/// Delete a user
-pub fn delete(pool: &PoolType, user_id: Uuid) -> Result<(), ApiError> {
-    use crate::schema::users::dsl::{id, users};
+/// Mark a user's email as verified
+pub fn mark_email_verified(pool: &PoolType, user_id: Uuid) -> Result<(), ApiError> {
+    use crate::schema::users::dsl::{email_verified_at, id, users};
 
     let conn = pool.get()?;
-    diesel::delete(users)
+    diesel::update(users)
         .filter(id.eq(user_id.to_string()))
+        .set(email_verified_at.eq(Utc::now().naive_utc()))
         .execute(&conn)?;
     Ok(())
 }
 
+/// Delete a user, along with their sessions, tokens, keys, MFA and identities
+/// Their audit events are kept
+pub fn delete(pool: &PoolType, user_id: Uuid) -> Result<(), ApiError> {
+    use crate::schema::{
+        api_keys, email_verifications, mfa_recovery_codes, password_resets, refresh_tokens,
+        sessions, user_identities, user_mfa,
+    };
+
+    let user = user_id.to_string();
+    let conn = pool.get()?;
+    conn.transaction::<_, ApiError, _>(|| {
+        diesel::delete(api_keys::table.filter(api_keys::user_id.eq(&user))).execute(&conn)?;
+        diesel::delete(email_verifications::table.filter(email_verifications::user_id.eq(&user)))
+            .execute(&conn)?;
+        diesel::delete(mfa_recovery_codes::table.filter(mfa_recovery_codes::user_id.eq(&user)))
+            .execute(&conn)?;
+        diesel::delete(password_resets::table.filter(password_resets::user_id.eq(&user)))
+            .execute(&conn)?;
+        diesel::delete(refresh_tokens::table.filter(refresh_tokens::user_id.eq(&user)))
+            .execute(&conn)?;
+        diesel::delete(sessions::table.filter(sessions::user_id.eq(&user))).execute(&conn)?;
+        diesel::delete(user_identities::table.filter(user_identities::user_id.eq(&user)))
+            .execute(&conn)?;
+        diesel::delete(user_mfa::table.filter(user_mfa::user_id.eq(&user))).execute(&conn)?;
+        diesel::delete(users::table.filter(users::id.eq(&user))).execute(&conn)?;
+        Ok(())
+    })
+}
+
 impl From<NewUser> for User {
     fn from(user: NewUser) -> Self {
         User {
@@ -131,6 +406,7 @@ impl From<NewUser> for User {
             last_name: user.last_name,
             email: user.email,
             password: hash(&user.password),
+            email_verified_at: None,
             created_by: user.created_by,
             created_at: Utc::now().naive_utc(),
             updated_by: user.updated_by,
@@ -148,16 +424,20 @@ pub mod tests {
         let pool = get_pool();
         let params = get_pagination_params();
         let base = "http://fake/api/v1/user";
-        get_all(&pool, params, base.into())
+        get_all(&pool, params.into(), base.into())
     }
 
     pub fn create_user() -> Result<UserResponse, ApiError> {
+        create_user_with_email("model-test@nothing.org")
+    }
+
+    pub fn create_user_with_email(email: &str) -> Result<UserResponse, ApiError> {
         let user_id = Uuid::new_v4();
         let new_user = NewUser {
             id: user_id.to_string(),
             first_name: "Model".to_string(),
             last_name: "Test".to_string(),
-            email: "model-test@nothing.org".to_string(),
+            email: email.to_string(),
             password: "123456".to_string(),
             created_by: user_id.to_string(),
             updated_by: user_id.to_string(),
@@ -172,6 +452,44 @@ pub mod tests {
         assert!(users.is_ok());
     }
 
+    fn list(query: &str) -> PaginationResponse<UsersResponse> {
+        let params = ListRequest::from_query(query, &USER_LIST).unwrap();
+        get_all(&get_pool(), params, "http://fake/api/v1/user".into()).unwrap()
+    }
+
+    #[test]
+    fn it_filters_and_searches_users() {
+        let email = format!("list-{}@nothing.org", Uuid::new_v4());
+        let user = create_user_with_email(&email).unwrap();
+
+        let filtered = list(&format!("filter[email]={}", email));
+        assert_eq!(filtered.pagination.total, 1);
+        assert_eq!(filtered.data.0[0], user);
+
+        let searched = list(&format!("q={}", &email[..20]));
+        assert_eq!(searched.pagination.total, 1);
+        assert_eq!(searched.data.0[0], user);
+
+        let excluded = list(&format!(
+            "filter[email]={}&filter[created_at][lt]=2000-01-01",
+            email
+        ));
+        assert_eq!(excluded.pagination.total, 0);
+    }
+
+    #[test]
+    fn it_sorts_users() {
+        let emails: Vec<String> = list("sort=-email&per_page=100")
+            .data
+            .0
+            .into_iter()
+            .map(|user| user.email)
+            .collect();
+        let mut sorted = emails.clone();
+        sorted.sort_by(|a, b| b.cmp(a));
+        assert_eq!(emails, sorted);
+    }
+
     #[test]
     fn test_find() {
         let users = get_all_users().unwrap();
@@ -187,6 +505,81 @@ pub mod tests {
         assert!(not_found_user.is_err());
     }
 
+    #[test]
+    fn it_finds_a_user_by_auth() {
+        let created = create_user().unwrap();
+        let found_user = find_by_auth(&get_pool(), &created.email, "123456", false);
+        assert!(found_user.is_ok());
+    }
+
+    #[test]
+    fn it_doesnt_find_a_user_by_auth_with_a_bad_password() {
+        let created = create_user().unwrap();
+        let found_user = find_by_auth(&get_pool(), &created.email, "bad-password", false);
+        assert_eq!(
+            found_user.unwrap_err(),
+            ApiError::Unauthorized("Invalid login".into())
+        );
+    }
+
+    #[test]
+    fn it_finds_a_user_by_email() {
+        let created = create_user().unwrap();
+        let found_user = find_by_email(&get_pool(), &created.email);
+        assert!(found_user.is_ok());
+    }
+
+    #[test]
+    fn it_updates_a_password() {
+        let created = create_user().unwrap();
+        update_password(&get_pool(), created.id, "new-password", &Actor::System).unwrap();
+
+        use crate::schema::users::dsl::{id, users};
+        let conn = get_pool().get().unwrap();
+        let user = users
+            .filter(id.eq(created.id.to_string()))
+            .first::<User>(&conn)
+            .unwrap();
+        assert!(verify("new-password", &user.password));
+        assert_eq!(user.updated_by, SYSTEM_ACTOR);
+    }
+
+    #[test]
+    fn it_doesnt_find_an_unverified_user_by_auth_when_verification_is_required() {
+        let email = format!("unverified-{}@nothing.org", Uuid::new_v4());
+        create_user_with_email(&email).unwrap();
+        let found_user = find_by_auth(&get_pool(), &email, "123456", true);
+        assert_eq!(
+            found_user.unwrap_err(),
+            ApiError::EmailNotVerified("Email not verified".into())
+        );
+    }
+
+    #[test]
+    fn it_finds_a_verified_user_by_auth_when_verification_is_required() {
+        let email = format!("verified-{}@nothing.org", Uuid::new_v4());
+        let created = create_user_with_email(&email).unwrap();
+        mark_email_verified(&get_pool(), created.id).unwrap();
+        let found_user = find_by_auth(&get_pool(), &email, "123456", true);
+        assert!(found_user.is_ok());
+    }
+
+    #[test]
+    fn it_records_the_impersonator_as_the_actor() {
+        let mut user = AuthUser {
+            id: Uuid::new_v4().to_string(),
+            email: "actor@nothing.org".into(),
+            roles: vec![],
+            permissions: vec![],
+            impersonator: None,
+        };
+        assert_eq!(Actor::from(&user).id(), user.id);
+
+        let admin_id = Uuid::new_v4().to_string();
+        user.impersonator = Some(admin_id.clone());
+        assert_eq!(Actor::from(&user).id(), admin_id);
+    }
+
     #[test]
     fn it_creates_a_user() {
         let created = create_user();
@@ -237,4 +630,61 @@ pub mod tests {
         let user = find(&get_pool(), user_id);
         assert!(user.is_err());
     }
+
+    #[test]
+    fn it_deletes_everything_that_belongs_to_a_user() {
+        use crate::models::mfa::tests::enable_mfa;
+        use crate::models::{api_key, email_verification, password_reset, refresh_token, session};
+        use crate::schema::{
+            api_keys, email_verifications, mfa_recovery_codes, password_resets, refresh_tokens,
+            sessions, user_mfa,
+        };
+
+        let email = format!("delete-{}@nothing.org", Uuid::new_v4());
+        let user_id = create_user_with_email(&email).unwrap().id;
+        let pool = get_pool();
+        let session = session::create(&pool, user_id, None, None).unwrap();
+        refresh_token::create(&pool, user_id, Uuid::parse_str(&session.id).unwrap()).unwrap();
+        api_key::create(&pool, user_id, "delete", &[], None).unwrap();
+        email_verification::create(&pool, user_id).unwrap();
+        password_reset::create(&pool, user_id).unwrap();
+        enable_mfa(user_id);
+        delete(&pool, user_id).unwrap();
+
+        let user = user_id.to_string();
+        let conn = pool.get().unwrap();
+        let remaining = [
+            api_keys::table
+                .filter(api_keys::user_id.eq(&user))
+                .count()
+                .get_result::<i64>(&conn),
+            email_verifications::table
+                .filter(email_verifications::user_id.eq(&user))
+                .count()
+                .get_result::<i64>(&conn),
+            mfa_recovery_codes::table
+                .filter(mfa_recovery_codes::user_id.eq(&user))
+                .count()
+                .get_result::<i64>(&conn),
+            password_resets::table
+                .filter(password_resets::user_id.eq(&user))
+                .count()
+                .get_result::<i64>(&conn),
+            refresh_tokens::table
+                .filter(refresh_tokens::user_id.eq(&user))
+                .count()
+                .get_result::<i64>(&conn),
+            sessions::table
+                .filter(sessions::user_id.eq(&user))
+                .count()
+                .get_result::<i64>(&conn),
+            user_mfa::table
+                .filter(user_mfa::user_id.eq(&user))
+                .count()
+                .get_result::<i64>(&conn),
+        ];
+        for count in remaining.iter() {
+            assert_eq!(count.as_ref().unwrap(), &0);
+        }
+    }
 }
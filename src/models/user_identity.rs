@@ -0,0 +1,233 @@
+use crate::auth::generate_token;
+use crate::database::PoolType;
+use crate::errors::ApiError;
+use crate::handlers::user::UserResponse;
+use crate::models::user::{Actor, NewUser, User};
+use crate::oidc::IdTokenClaims;
+use crate::schema::user_identities;
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use uuid::Uuid;
+
+/// Links a user to their account at an OIDC provider
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Queryable, Identifiable, Insertable)]
+#[table_name = "user_identities"]
+pub struct UserIdentity {
+    pub id: String,
+    pub user_id: String,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+/// Find the user signing in with a provider, linking or creating one if needed
+///
+/// An identity already linked to a user signs in as that user.  Otherwise,
+/// it's linked to the user with the same email, but only if the provider
+/// has verified the email.  If there's no such user, one is created with a
+/// random password.
+///
+/// When require_verified is set, users who haven't verified their email
+/// are rejected with an EmailNotVerified error.
+pub fn find_or_create_user(
+    pool: &PoolType,
+    identity_provider: &str,
+    claims: &IdTokenClaims,
+    require_verified: bool,
+) -> Result<UserResponse, ApiError> {
+    use crate::schema::user_identities::dsl::{provider, subject, user_identities};
+    use crate::schema::users::dsl::{email, id, users};
+
+    let conn = pool.get()?;
+    let linked = user_identities
+        .filter(provider.eq(identity_provider))
+        .filter(subject.eq(&claims.sub))
+        .first::<UserIdentity>(&conn)
+        .optional()?;
+
+    let user = match linked {
+        Some(identity) => users
+            .filter(id.eq(identity.user_id))
+            .first::<User>(&conn)
+            .map_err(|_| ApiError::Unauthorized("Invalid login".into()))?,
+        None => {
+            let claims_email = claims.email.as_ref().ok_or_else(|| {
+                ApiError::BadRequest("The identity provider didn't share an email".into())
+            })?;
+            let existing = users
+                .filter(email.eq(claims_email))
+                .first::<User>(&conn)
+                .optional()?;
+
+            let user = match existing {
+                Some(_) if !claims.email_verified => {
+                    return Err(ApiError::BadRequest(
+                        "Cannot link an identity with an unverified email".into(),
+                    ))
+                }
+                // Otherwise whoever set the email could sign in as its owner
+                Some(user) if user.email_verified_at.is_none() => {
+                    return Err(ApiError::BadRequest(
+                        "Cannot link an identity to an unverified account".into(),
+                    ))
+                }
+                Some(user) => user,
+                None => create_user(pool, claims_email, claims)?,
+            };
+            link(pool, identity_provider, claims, Uuid::parse_str(&user.id)?)?;
+            user
+        }
+    };
+
+    if require_verified && user.email_verified_at.is_none() {
+        return Err(ApiError::EmailNotVerified("Email not verified".into()));
+    }
+
+    Ok(user.into())
+}
+
+/// Find all of a user's linked identities
+pub fn find_all(pool: &PoolType, user: Uuid) -> Result<Vec<UserIdentity>, ApiError> {
+    use crate::schema::user_identities::dsl::{created_at, user_id, user_identities};
+
+    let conn = pool.get()?;
+    let identities = user_identities
+        .filter(user_id.eq(user.to_string()))
+        .order(created_at.asc())
+        .load::<UserIdentity>(&conn)?;
+    Ok(identities)
+}
+
+/// Link an identity at a provider to a user
+fn link(
+    pool: &PoolType,
+    identity_provider: &str,
+    claims: &IdTokenClaims,
+    user: Uuid,
+) -> Result<(), ApiError> {
+    use crate::schema::user_identities::dsl::user_identities;
+
+    let identity = UserIdentity {
+        id: Uuid::new_v4().to_string(),
+        user_id: user.to_string(),
+        provider: identity_provider.into(),
+        subject: claims.sub.clone(),
+        email: claims.email.clone(),
+        created_at: Utc::now().naive_utc(),
+    };
+
+    let conn = pool.get()?;
+    diesel::insert_into(user_identities)
+        .values(&identity)
+        .execute(&conn)?;
+    Ok(())
+}
+
+/// Create a user from an ID token's claims
+fn create_user(
+    pool: &PoolType,
+    user_email: &str,
+    claims: &IdTokenClaims,
+) -> Result<User, ApiError> {
+    use crate::schema::users::dsl::users;
+
+    let mut user: User = NewUser {
+        id: Uuid::new_v4().to_string(),
+        first_name: claims.given_name.clone().unwrap_or_default(),
+        last_name: claims.family_name.clone().unwrap_or_default(),
+        email: user_email.into(),
+        password: generate_token(),
+        created_by: Actor::System.id(),
+        updated_by: Actor::System.id(),
+    }
+    .into();
+    if claims.email_verified {
+        user.email_verified_at = Some(Utc::now().naive_utc());
+    }
+
+    let conn = pool.get()?;
+    diesel::insert_into(users).values(&user).execute(&conn)?;
+    Ok(user)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::models::user::mark_email_verified;
+    use crate::models::user::tests::create_user_with_email;
+    use crate::tests::helpers::tests::get_pool;
+
+    fn get_claims(email: &str, email_verified: bool) -> IdTokenClaims {
+        IdTokenClaims {
+            sub: Uuid::new_v4().to_string(),
+            email: Some(email.into()),
+            email_verified,
+            given_name: Some("Satoshi".into()),
+            family_name: Some("Nakamoto".into()),
+            nonce: None,
+        }
+    }
+
+    fn get_email() -> String {
+        format!("oidc-{}@nothing.org", Uuid::new_v4())
+    }
+
+    #[test]
+    fn it_creates_a_user_for_a_new_identity() {
+        let claims = get_claims(&get_email(), true);
+        let user = find_or_create_user(&get_pool(), "mock", &claims, true).unwrap();
+        assert_eq!(user.first_name, "Satoshi");
+        assert_eq!(find_all(&get_pool(), user.id).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_finds_the_user_for_a_linked_identity() {
+        let claims = get_claims(&get_email(), true);
+        let created = find_or_create_user(&get_pool(), "mock", &claims, false).unwrap();
+        let found = find_or_create_user(&get_pool(), "mock", &claims, false).unwrap();
+        assert_eq!(created, found);
+        assert_eq!(find_all(&get_pool(), found.id).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_links_an_identity_to_a_user_with_a_verified_email() {
+        let email = get_email();
+        let existing = create_user_with_email(&email).unwrap();
+        mark_email_verified(&get_pool(), existing.id).unwrap();
+        let claims = get_claims(&email, true);
+        let user = find_or_create_user(&get_pool(), "mock", &claims, false).unwrap();
+        assert_eq!(user.id, existing.id);
+    }
+
+    #[test]
+    fn it_doesnt_link_an_identity_to_an_unverified_account() {
+        let email = get_email();
+        create_user_with_email(&email).unwrap();
+        let claims = get_claims(&email, true);
+        let user = find_or_create_user(&get_pool(), "mock", &claims, false);
+        assert_eq!(
+            user.unwrap_err(),
+            ApiError::BadRequest("Cannot link an identity to an unverified account".into())
+        );
+    }
+
+    #[test]
+    fn it_doesnt_link_an_identity_to_a_user_with_an_unverified_email() {
+        let email = get_email();
+        create_user_with_email(&email).unwrap();
+        let claims = get_claims(&email, false);
+        let user = find_or_create_user(&get_pool(), "mock", &claims, false);
+        assert!(user.is_err());
+    }
+
+    #[test]
+    fn it_doesnt_sign_in_an_unverified_user_when_verification_is_required() {
+        let claims = get_claims(&get_email(), false);
+        let user = find_or_create_user(&get_pool(), "mock", &claims, true);
+        assert_eq!(
+            user.unwrap_err(),
+            ApiError::EmailNotVerified("Email not verified".into())
+        );
+    }
+}
@@ -0,0 +1,542 @@
+//! Sign in with an OpenID Connect (OIDC) identity provider
+//!
+//! Uses the authorization code flow with PKCE (RFC 7636).  Each provider
+//! named in OIDC_PROVIDERS is configured with OIDC_<NAME>_* variables, e.g.
+//! for OIDC_PROVIDERS=google:
+//!
+//! OIDC_GOOGLE_ISSUER=https://accounts.google.com
+//! OIDC_GOOGLE_CLIENT_ID=...
+//! OIDC_GOOGLE_CLIENT_SECRET=...
+//!
+//! Endpoints and signing keys are found through OIDC discovery, so any
+//! compliant provider works, including a local mock server.
+
+use crate::auth::generate_token;
+use crate::cache::{get_del, set_ex, Cache};
+use crate::config::{Config, CONFIG};
+use crate::errors::ApiError;
+use crate::keys::{is_ec, is_rsa};
+use actix_web::{
+    client::Client,
+    cookie::{Cookie, SameSite},
+};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use time::Duration as TimeDuration;
+
+/// How long a user has to sign in with the provider, in seconds
+const PENDING_LOGIN_EXPIRATION: i64 = 600;
+
+/// The cookie binding a login's state to the browser that started it
+pub const OIDC_STATE_COOKIE: &str = "oidc_state";
+
+// Throw the providers into a PROVIDERS lazy_static to avoid multiple processing
+lazy_static! {
+    pub static ref PROVIDERS: HashMap<String, ProviderConfig> = get_providers(&CONFIG);
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ProviderConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    #[serde(default = "default_scopes")]
+    pub scopes: String,
+}
+
+/// The claims of an ID token used to find or create a user
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default, deserialize_with = "bool_or_string")]
+    pub email_verified: bool,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+    pub nonce: Option<String>,
+}
+
+/// What's remembered between redirecting to the provider and its callback
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+struct PendingLogin {
+    provider: String,
+    nonce: String,
+    code_verifier: String,
+}
+
+/// The provider metadata found through OIDC discovery
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenRequest<'a> {
+    grant_type: &'a str,
+    code: &'a str,
+    redirect_uri: &'a str,
+    client_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_secret: Option<&'a str>,
+    code_verifier: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderJwks {
+    keys: Vec<ProviderJwk>,
+}
+
+/// A provider's signing key, only the fields needed to verify ID tokens
+#[derive(Debug, Deserialize)]
+struct ProviderJwk {
+    kty: String,
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+/// Start signing in with a provider
+/// Returns the provider's authorization URL to redirect the user to, and the
+/// state to remember in the browser, see state_cookie
+pub async fn begin(cache: Cache, name: &str) -> Result<(String, String), ApiError> {
+    let provider = get_provider(name)?;
+    let discovery = discover(provider).await?;
+
+    let state = generate_token();
+    let pending_login = PendingLogin {
+        provider: name.into(),
+        nonce: generate_token(),
+        code_verifier: generate_token(),
+    };
+    let value = serde_json::to_string(&pending_login)
+        .map_err(|e| ApiError::InternalServerError(e.to_string()))?;
+    set_ex(
+        cache,
+        &pending_login_key(&state),
+        &value,
+        PENDING_LOGIN_EXPIRATION,
+    )
+    .await?;
+
+    let url = authorization_url(
+        name,
+        provider,
+        &discovery.authorization_endpoint,
+        &state,
+        &pending_login,
+    );
+    Ok((url, state))
+}
+
+/// Build the cookie remembering a login's state until the provider redirects back
+///
+/// Only the browser that started a login can finish it, so an attacker can't
+/// log a victim into the attacker's account with their own callback URL.
+/// It's sent on the provider's top-level redirect, but never read by scripts.
+pub fn state_cookie(state: &str) -> Cookie<'static> {
+    Cookie::build(OIDC_STATE_COOKIE, state.to_string())
+        .path("/")
+        .http_only(true)
+        .secure(CONFIG.session_secure)
+        .same_site(SameSite::Lax)
+        .max_age(TimeDuration::seconds(PENDING_LOGIN_EXPIRATION))
+        .finish()
+}
+
+/// Finish signing in with a provider when it redirects back
+///
+/// Exchanges the code for an ID token, which is verified against the
+/// provider's published keys.  Each state can only be used once, and must
+/// match the state_cookie of the browser that started the login.
+pub async fn finish(
+    cache: Cache,
+    name: &str,
+    code: &str,
+    state: &str,
+    cookie_state: Option<&str>,
+) -> Result<IdTokenClaims, ApiError> {
+    let invalid_state = || ApiError::Unauthorized("Invalid or expired OIDC state".into());
+    if cookie_state != Some(state) {
+        return Err(invalid_state());
+    }
+    let provider = get_provider(name)?;
+
+    let value = get_del(cache, &pending_login_key(state)).await?;
+    let pending_login =
+        serde_json::from_str::<PendingLogin>(&value).map_err(|_| invalid_state())?;
+    if pending_login.provider != name {
+        return Err(invalid_state());
+    }
+
+    let discovery = discover(provider).await?;
+    let token_request = TokenRequest {
+        grant_type: "authorization_code",
+        code,
+        redirect_uri: &redirect_uri(name),
+        client_id: &provider.client_id,
+        client_secret: provider.client_secret.as_deref(),
+        code_verifier: &pending_login.code_verifier,
+    };
+    let mut response = Client::default()
+        .post(&discovery.token_endpoint)
+        .send_form(&token_request)
+        .await
+        .map_err(provider_error)?;
+    if !response.status().is_success() {
+        return Err(ApiError::Unauthorized(
+            "Cannot exchange the OIDC code".into(),
+        ));
+    }
+    let token_response = response
+        .json::<TokenResponse>()
+        .await
+        .map_err(provider_error)?;
+
+    let jwks = get_json::<ProviderJwks>(&discovery.jwks_uri).await?;
+    verify_id_token(
+        &token_response.id_token,
+        provider,
+        &jwks,
+        &pending_login.nonce,
+    )
+}
+
+/// Find a configured provider or issue a NOT_FOUND
+fn get_provider(name: &str) -> Result<&'static ProviderConfig, ApiError> {
+    PROVIDERS
+        .get(name)
+        .ok_or_else(|| ApiError::NotFound(format!("OIDC provider {} not found", name)))
+}
+
+async fn discover(provider: &ProviderConfig) -> Result<Discovery, ApiError> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        provider.issuer.trim_end_matches('/')
+    );
+    get_json(&url).await
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, ApiError> {
+    let mut response = Client::default()
+        .get(url)
+        .send()
+        .await
+        .map_err(provider_error)?;
+    if !response.status().is_success() {
+        return Err(provider_error(response.status()));
+    }
+    response
+        .json::<T>()
+        .limit(1024 * 1024)
+        .await
+        .map_err(provider_error)
+}
+
+fn provider_error<E: std::fmt::Display>(error: E) -> ApiError {
+    ApiError::InternalServerError(format!("OIDC provider error: {}", error))
+}
+
+/// Where the provider redirects back to after the user signs in
+fn redirect_uri(name: &str) -> String {
+    format!(
+        "{}/{}/callback",
+        CONFIG.oidc_redirect_url.trim_end_matches('/'),
+        name
+    )
+}
+
+fn authorization_url(
+    name: &str,
+    provider: &ProviderConfig,
+    authorization_endpoint: &str,
+    state: &str,
+    pending_login: &PendingLogin,
+) -> String {
+    let params = [
+        ("response_type", "code"),
+        ("client_id", &provider.client_id),
+        ("redirect_uri", &redirect_uri(name)),
+        ("scope", &provider.scopes),
+        ("state", state),
+        ("nonce", &pending_login.nonce),
+        (
+            "code_challenge",
+            &code_challenge(&pending_login.code_verifier),
+        ),
+        ("code_challenge_method", "S256"),
+    ];
+    let query = params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, utf8_percent_encode(value, NON_ALPHANUMERIC)))
+        .collect::<Vec<String>>()
+        .join("&");
+    let separator = if authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    format!("{}{}{}", authorization_endpoint, separator, query)
+}
+
+/// The S256 PKCE code challenge for a code verifier
+fn code_challenge(code_verifier: &str) -> String {
+    base64::encode_config(
+        Sha256::digest(code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+/// Verify an ID token's signature, issuer, audience, expiry and nonce
+fn verify_id_token(
+    id_token: &str,
+    provider: &ProviderConfig,
+    jwks: &ProviderJwks,
+    nonce: &str,
+) -> Result<IdTokenClaims, ApiError> {
+    let invalid = || ApiError::Unauthorized("Invalid ID token".into());
+    let header = decode_header(id_token).map_err(|_| invalid())?;
+    let jwk = jwks
+        .keys
+        .iter()
+        .find(|jwk| header.kid.is_none() || jwk.kid == header.kid)
+        .ok_or_else(invalid)?;
+    let decoding_key = to_decoding_key(header.alg, jwk).ok_or_else(invalid)?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.iss = Some(provider.issuer.clone());
+    validation.set_audience(&[&provider.client_id]);
+    let claims = decode::<IdTokenClaims>(id_token, &decoding_key, &validation)
+        .map_err(|_| invalid())?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(invalid());
+    }
+    Ok(claims)
+}
+
+/// Only RSA and EC keys are accepted, so a token can't claim to be signed
+/// with a shared secret
+fn to_decoding_key(algorithm: Algorithm, jwk: &ProviderJwk) -> Option<DecodingKey<'static>> {
+    if is_rsa(algorithm) && jwk.kty == "RSA" {
+        let decoding_key = DecodingKey::from_rsa_components(jwk.n.as_ref()?, jwk.e.as_ref()?);
+        return Some(decoding_key.into_static());
+    }
+    if is_ec(algorithm) && jwk.kty == "EC" {
+        // An uncompressed curve point, 0x04 || x || y
+        let mut point = vec![4];
+        point.extend(base64::decode_config(jwk.x.as_ref()?, base64::URL_SAFE_NO_PAD).ok()?);
+        point.extend(base64::decode_config(jwk.y.as_ref()?, base64::URL_SAFE_NO_PAD).ok()?);
+        return Some(DecodingKey::from_ec_der(&point).into_static());
+    }
+    None
+}
+
+fn pending_login_key(state: &str) -> String {
+    format!("oidc:pending:{}", state)
+}
+
+fn default_scopes() -> String {
+    "openid email profile".into()
+}
+
+/// Some providers send email_verified as a string
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+
+    Ok(match BoolOrString::deserialize(deserializer)? {
+        BoolOrString::Bool(value) => value,
+        BoolOrString::String(value) => value == "true",
+    })
+}
+
+/// Load each provider from its OIDC_<NAME>_* variables, panicking if one is
+/// misconfigured
+fn get_providers(config: &Config) -> HashMap<String, ProviderConfig> {
+    config
+        .oidc_providers
+        .iter()
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let prefix = format!("OIDC_{}_", name.to_uppercase());
+            match envy::prefixed(prefix).from_env::<ProviderConfig>() {
+                Ok(provider) => (name.to_string(), provider),
+                Err(error) => panic!("OIDC Provider Error ({}): {:#?}", name, error),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::cache::get;
+    use crate::keys::tests::{EC_PRIVATE_KEY, EC_PUBLIC_KEY};
+    use crate::keys::Keys;
+    use crate::tests::helpers::tests::get_data_cache;
+    use chrono::{Duration, Utc};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        iss: &'a str,
+        aud: &'a str,
+        exp: i64,
+        sub: &'a str,
+        email: &'a str,
+        email_verified: &'a str,
+        nonce: &'a str,
+    }
+
+    fn get_provider() -> ProviderConfig {
+        ProviderConfig {
+            issuer: "http://127.0.0.1:8080".into(),
+            client_id: "rust-actix-framework".into(),
+            client_secret: None,
+            scopes: default_scopes(),
+        }
+    }
+
+    fn get_jwks() -> ProviderJwks {
+        let public_keys = vec![("mock".to_string(), EC_PUBLIC_KEY.as_bytes().to_vec())];
+        let keys = Keys::from_pem(
+            Algorithm::ES256,
+            "mock",
+            EC_PRIVATE_KEY.as_bytes(),
+            &public_keys,
+        )
+        .unwrap();
+        serde_json::from_value(serde_json::to_value(&keys.jwks).unwrap()).unwrap()
+    }
+
+    fn sign(issuer: &str, audience: &str, nonce: &str) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("mock".into());
+        let claims = TestClaims {
+            iss: issuer,
+            aud: audience,
+            exp: (Utc::now() + Duration::minutes(5)).timestamp(),
+            sub: "mock-subject",
+            email: "satoshi@nakamotoinstitute.org",
+            email_verified: "true",
+            nonce,
+        };
+        let key = EncodingKey::from_ec_pem(EC_PRIVATE_KEY.as_bytes()).unwrap();
+        encode(&header, &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn it_verifies_an_id_token() {
+        let provider = get_provider();
+        let id_token = sign(&provider.issuer, &provider.client_id, "nonce");
+        let claims = verify_id_token(&id_token, &provider, &get_jwks(), "nonce").unwrap();
+        assert_eq!(claims.sub, "mock-subject");
+        assert!(claims.email_verified);
+    }
+
+    #[test]
+    fn it_doesnt_verify_an_id_token_with_the_wrong_nonce() {
+        let provider = get_provider();
+        let id_token = sign(&provider.issuer, &provider.client_id, "nonce");
+        let claims = verify_id_token(&id_token, &provider, &get_jwks(), "other-nonce");
+        assert!(claims.is_err());
+    }
+
+    #[test]
+    fn it_doesnt_verify_an_id_token_for_another_client() {
+        let provider = get_provider();
+        let id_token = sign(&provider.issuer, "another-client", "nonce");
+        let claims = verify_id_token(&id_token, &provider, &get_jwks(), "nonce");
+        assert!(claims.is_err());
+    }
+
+    #[test]
+    fn it_doesnt_verify_an_id_token_from_another_issuer() {
+        let provider = get_provider();
+        let id_token = sign("http://evil.example.com", &provider.client_id, "nonce");
+        let claims = verify_id_token(&id_token, &provider, &get_jwks(), "nonce");
+        assert!(claims.is_err());
+    }
+
+    #[test]
+    fn it_creates_an_s256_code_challenge() {
+        // From RFC 7636 appendix B
+        let code_challenge = code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk");
+        assert_eq!(
+            code_challenge,
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn it_creates_an_authorization_url() {
+        let pending_login = PendingLogin {
+            provider: "mock".into(),
+            nonce: "nonce".into(),
+            code_verifier: "verifier".into(),
+        };
+        let url = authorization_url(
+            "mock",
+            &get_provider(),
+            "http://127.0.0.1:8080/authorize",
+            "state",
+            &pending_login,
+        );
+        assert!(url.starts_with("http://127.0.0.1:8080/authorize?response_type=code&"));
+        assert!(url.contains("&scope=openid%20email%20profile&"));
+        assert!(url.contains("&state=state&nonce=nonce&"));
+        assert!(url.ends_with("&code_challenge_method=S256"));
+    }
+
+    #[test]
+    fn it_loads_a_provider_from_the_environment() {
+        std::env::set_var("OIDC_MOCK_ISSUER", "http://127.0.0.1:8080");
+        std::env::set_var("OIDC_MOCK_CLIENT_ID", "rust-actix-framework");
+        let mut config = CONFIG.clone();
+        config.oidc_providers = vec!["mock".into()];
+        let providers = get_providers(&config);
+        assert_eq!(providers.get("mock"), Some(&get_provider()));
+    }
+
+    #[test]
+    fn it_binds_the_state_to_the_browser() {
+        let cookie = state_cookie("state");
+        assert_eq!(cookie.name(), OIDC_STATE_COOKIE);
+        assert_eq!(cookie.value(), "state");
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_finish_a_login_started_in_another_browser() {
+        let cache = get_data_cache();
+        let state = generate_token();
+        let key = pending_login_key(&state);
+        set_ex(cache.clone(), &key, "{}", 60).await.unwrap();
+        let finished = finish(cache.clone(), "mock", "code", &state, Some("other")).await;
+        assert_eq!(
+            finished.unwrap_err(),
+            ApiError::Unauthorized("Invalid or expired OIDC state".into())
+        );
+
+        // The browser that started it can still finish it
+        assert_eq!(get(cache, &key).await.unwrap(), "{}");
+    }
+}
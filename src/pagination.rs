@@ -1,11 +1,36 @@
 //! Utilities to add pagination to responses
+//!
+//! Lists are paginated by page, with a total count and offset, or by an
+//! opaque cursor, which seeks past the last row seen on the sort key and id.
+//! Cursors stay fast on large tables and don't shift when rows are added.
+//!
+//! Both responses also send their links in a Link header (RFC 8288), and
+//! the total in an X-Total-Count header when it's known, so generic clients
+//! can paginate without parsing the body.
 
+use crate::config::CONFIG;
 use crate::errors::ApiError;
-use std::cmp::min;
+use crate::listing::{Direction, FilterValue, Seek};
+use actix_web::{http::header::LINK, Error, HttpRequest, HttpResponse, Responder};
+use diesel::{
+    backend::Backend,
+    prelude::*,
+    query_builder::{AsQuery, AstPass, Query, QueryFragment, QueryId},
+    query_dsl::LoadQuery,
+    sql_types::BigInt,
+};
+use futures::future::{ok, Ready};
+use hmac::{Hmac, Mac, NewMac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::cmp::{max, min};
 
 const DEFAULT_PER_PAGE: i64 = 10;
 
-#[derive(Debug, Deserialize)]
+/// The header holding a list's total
+pub const TOTAL_COUNT_HEADER: &str = "X-Total-Count";
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
 pub struct PaginationRequest {
     pub page: Option<i64>,
     pub per_page: Option<i64>,
@@ -13,11 +38,11 @@ pub struct PaginationRequest {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Links {
-    base: String,
-    first: String,
-    last: String,
-    prev: Option<String>,
-    next: Option<String>,
+    pub base: String,
+    pub first: String,
+    pub last: String,
+    pub prev: Option<String>,
+    pub next: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -36,9 +61,111 @@ pub struct PaginationResponse<T> {
     pub data: T,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CursorLinks {
+    pub base: String,
+    pub prev: Option<String>,
+    pub next: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CursorPagination {
+    pub per_page: i64,
+    /// Only counted when asked for with total=true
+    pub total: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CursorResponse<T> {
+    pub links: CursorLinks,
+    pub pagination: CursorPagination,
+    pub data: T,
+}
+
+/// A position in a cursor-paginated list: the sort value and id of a row
+///
+/// Clients get it as an opaque, signed token, so one can't be edited to
+/// seek on a different field or value.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Cursor {
+    pub field: String,
+    pub descending: bool,
+    /// Page backwards, towards the start of the list
+    pub before: bool,
+    pub value: String,
+    pub id: String,
+}
+
+impl Cursor {
+    /// base64url(JSON) "." base64url(HMAC-SHA256)
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).unwrap_or_default();
+        let payload = base64::encode_config(json, base64::URL_SAFE_NO_PAD);
+        let signature = sign(&payload).finalize().into_bytes();
+        format!(
+            "{}.{}",
+            payload,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Returns None if the token is malformed or the signature doesn't match
+    pub fn decode(token: &str) -> Option<Self> {
+        let mut parts = token.splitn(2, '.');
+        let payload = parts.next()?;
+        let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        sign(payload).verify(&signature).ok()?;
+        let json = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+fn sign(payload: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_varkey(CONFIG.cursor_key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// Check page and per_page are in range, see validate_pagination
+pub fn pagination_errors(page: Option<i64>, per_page: Option<i64>) -> Vec<String> {
+    let mut errors = vec![];
+    if page.map_or(false, |page| page < 1) {
+        errors.push("page must be at least 1".into());
+    }
+    if per_page.map_or(false, |per_page| {
+        per_page < 1 || per_page > CONFIG.max_per_page
+    }) {
+        errors.push(format!(
+            "per_page must be between 1 and {}",
+            CONFIG.max_per_page
+        ));
+    }
+    errors
+}
+
+/// Reject a page or per_page that's out of range, before querying
+pub fn validate_pagination(page: Option<i64>, per_page: Option<i64>) -> Result<(), ApiError> {
+    let errors = pagination_errors(page, per_page);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::ValidationError(errors))
+    }
+}
+
+/// The requested per_page, clamped between 1 and max_per_page
+pub fn get_per_page(per_page: Option<i64>) -> i64 {
+    per_page
+        .unwrap_or(DEFAULT_PER_PAGE)
+        .max(1)
+        .min(CONFIG.max_per_page)
+}
+
+/// Out of range values are clamped, callers should validate_pagination first
 pub fn get_pagination(page: Option<i64>, per_page: Option<i64>, total: i64) -> Pagination {
-    let page = page.unwrap_or(1);
-    let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE);
+    let page = page.unwrap_or(1).max(1);
+    let per_page = get_per_page(per_page);
     let total_pages = (total as f64 / per_page as f64).ceil() as i64;
     let offset = (page - 1) * per_page;
     Pagination {
@@ -50,21 +177,39 @@ pub fn get_pagination(page: Option<i64>, per_page: Option<i64>, total: i64) -> P
     }
 }
 
+/// The base may already carry a query string, e.g. a list's filters
 fn get_url(base: &String, page: i64, per_page: i64) -> String {
-    format!("{}?page={}&per_page={}", base, page, per_page)
+    let separator = if base.contains('?') { '&' } else { '?' };
+    format!("{}{}page={}&per_page={}", base, separator, page, per_page)
+}
+
+fn get_cursor_url(base: &String, cursor: &Cursor, per_page: i64) -> String {
+    let separator = if base.contains('?') { '&' } else { '?' };
+    format!(
+        "{}{}cursor={}&per_page={}",
+        base,
+        separator,
+        cursor.encode(),
+        per_page
+    )
 }
 
 fn first_url(pagination: &Pagination, base: &String) -> String {
     get_url(base, 1, pagination.per_page)
 }
 
+/// An empty list still has a first page to link to
+fn last_page(pagination: &Pagination) -> i64 {
+    max(pagination.total_pages, 1)
+}
+
 fn last_url(pagination: &Pagination, base: &String) -> String {
-    get_url(base, pagination.total_pages, pagination.per_page)
+    get_url(base, last_page(pagination), pagination.per_page)
 }
 
 fn prev_url(pagination: &Pagination, base: &String) -> Option<String> {
     if pagination.page > 1 {
-        let prev_page = min(pagination.page - 1, pagination.total_pages);
+        let prev_page = min(pagination.page - 1, last_page(pagination));
         return Some(get_url(base, prev_page, pagination.per_page));
     }
     None
@@ -102,20 +247,346 @@ pub fn paginate<T>(
     Ok(response)
 }
 
-#[macro_export]
-macro_rules! paginate {
-    ($pool:expr, $model:ident, $model_type:ident, $params:ident, $response_type:ident, $base:ident) => {{
-        use crate::pagination::{get_pagination, paginate};
+/// Build a page of a cursor-paginated list
+///
+/// The rows are the results of a query seeked with seek_by!, limited to
+/// per_page + 1: the extra row shows whether there's another page.  `key`
+/// reads a row's sort value and id, to build the next and prev cursors.
+pub fn paginate_by_cursor<R, T, F>(
+    mut rows: Vec<R>,
+    seek: &Seek,
+    per_page: i64,
+    total: Option<i64>,
+    base: String,
+    key: F,
+) -> Result<CursorResponse<T>, ApiError>
+where
+    T: From<Vec<R>>,
+    F: Fn(&R) -> (FilterValue, String),
+{
+    let has_more = rows.len() as i64 > per_page;
+    rows.truncate(per_page as usize);
+
+    // Paging backwards queries in reverse, so flip the rows back
+    if seek.before {
+        rows.reverse();
+    }
+
+    let cursor_url = |row: &R, before: bool| {
+        let (value, id) = key(row);
+        let cursor = Cursor {
+            field: seek.sort.field.clone(),
+            descending: seek.sort.direction == Direction::Desc,
+            before,
+            value: value.to_string(),
+            id,
+        };
+        get_cursor_url(&base, &cursor, per_page)
+    };
+    let (has_prev, has_next) = if seek.before {
+        (has_more, true)
+    } else {
+        (seek.after.is_some(), has_more)
+    };
+    let prev = rows
+        .first()
+        .filter(|_| has_prev)
+        .map(|row| cursor_url(row, true));
+    let next = rows
+        .last()
+        .filter(|_| has_next)
+        .map(|row| cursor_url(row, false));
+
+    Ok(CursorResponse {
+        links: CursorLinks { base, prev, next },
+        pagination: CursorPagination { per_page, total },
+        data: rows.into(),
+    })
+}
+
+/// `<url>; rel="next", <url>; rel="last"`, skipping missing links
+fn link_header(links: &[(&str, Option<&String>)]) -> String {
+    links
+        .iter()
+        .filter_map(|(rel, url)| url.map(|url| format!("<{}>; rel=\"{}\"", url, rel)))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+impl<T: Serialize> Responder for PaginationResponse<T> {
+    type Error = Error;
+    type Future = Ready<Result<HttpResponse, Error>>;
+
+    fn respond_to(self, _: &HttpRequest) -> Self::Future {
+        let link = link_header(&[
+            ("first", Some(&self.links.first)),
+            ("prev", self.links.prev.as_ref()),
+            ("next", self.links.next.as_ref()),
+            ("last", Some(&self.links.last)),
+        ]);
+        ok(HttpResponse::Ok()
+            .header(LINK, link)
+            .header(TOTAL_COUNT_HEADER, self.pagination.total.to_string())
+            .json(self))
+    }
+}
+
+impl<T: Serialize> Responder for CursorResponse<T> {
+    type Error = Error;
+    type Future = Ready<Result<HttpResponse, Error>>;
+
+    fn respond_to(self, _: &HttpRequest) -> Self::Future {
+        let mut response = HttpResponse::Ok();
+        let link = link_header(&[
+            ("prev", self.links.prev.as_ref()),
+            ("next", self.links.next.as_ref()),
+        ]);
+        if !link.is_empty() {
+            response.header(LINK, link);
+        }
+        if let Some(total) = self.pagination.total {
+            response.header(TOTAL_COUNT_HEADER, total.to_string());
+        }
+        ok(response.json(self))
+    }
+}
+
+/// Paginate any Diesel query, loading a page and the total in one query
+///
+/// ```ignore
+/// use actix_framework::pagination::Paginate;
+///
+/// let (products, pagination) = products
+///     .filter(price.gt(10))
+///     .order(name.asc())
+///     .paginate(params)
+///     .load_with_total::<Product, _>(&conn)?;
+/// ```
+///
+/// The page is limited inside a subquery, right after the query's own
+/// ORDER BY, so the query can't have a limit or offset of its own.  On
+/// MySQL a joined query can't select two columns with the same name.
+pub trait Paginate: AsQuery + Sized {
+    fn paginate(self, params: PaginationRequest) -> Paginated<Self::Query>;
+}
+
+impl<T: AsQuery> Paginate for T {
+    fn paginate(self, params: PaginationRequest) -> Paginated<Self::Query> {
+        Paginated {
+            query: self.as_query(),
+            pagination: get_pagination(params.page, params.per_page, 0),
+        }
+    }
+}
+
+/// A page of a query, see Paginate
+#[derive(Clone, Debug)]
+pub struct Paginated<T> {
+    query: T,
+    pagination: Pagination,
+}
+
+impl<T> Paginated<T> {
+    /// Load the page's rows, along with the pagination and total
+    /// A page past the end has no rows to read the total from, so it's
+    /// counted in a second query
+    pub fn load_with_total<'a, U, Conn>(&'a self, conn: &Conn) -> QueryResult<(Vec<U>, Pagination)>
+    where
+        &'a Self: LoadQuery<Conn, (U, i64)>,
+        Counted<'a, T>: LoadQuery<Conn, i64>,
+    {
+        let (page, per_page) = (self.pagination.page, self.pagination.per_page);
+        let rows = self.load::<(U, i64)>(conn)?;
+        let total = match rows.first() {
+            Some((_, total)) => *total,
+            None if self.pagination.offset > 0 => Counted(&self.query).get_result(conn)?,
+            None => 0,
+        };
+        let rows = rows.into_iter().map(|(row, _)| row).collect();
+        Ok((rows, get_pagination(Some(page), Some(per_page), total)))
+    }
+}
+
+impl<T: Query> Query for Paginated<T> {
+    type SqlType = (T::SqlType, BigInt);
+}
+
+impl<T> QueryId for Paginated<T> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<T, Conn> RunQueryDsl<Conn> for Paginated<T> {}
+
+impl<'a, T, Conn> RunQueryDsl<Conn> for &'a Paginated<T> {}
+
+// A derived table's ORDER BY isn't guaranteed to be kept, so the page is
+// limited in the same query that orders it, and counted separately
+impl<T, DB> QueryFragment<DB> for Paginated<T>
+where
+    DB: Backend,
+    T: QueryFragment<DB>,
+{
+    fn walk_ast(&self, mut out: AstPass<DB>) -> QueryResult<()> {
+        out.push_sql("SELECT paginated.*, (SELECT COUNT(*) FROM (");
+        self.query.walk_ast(out.reborrow())?;
+        out.push_sql(") counted) FROM (");
+        self.query.walk_ast(out.reborrow())?;
+        out.push_sql(" LIMIT ");
+        out.push_bind_param::<BigInt, _>(&self.pagination.per_page)?;
+        out.push_sql(" OFFSET ");
+        out.push_bind_param::<BigInt, _>(&self.pagination.offset)?;
+        out.push_sql(") paginated");
+        Ok(())
+    }
+}
+
+/// Count every row of a paginated query, see Paginated::load_with_total
+#[derive(Debug)]
+pub struct Counted<'a, T>(&'a T);
+
+impl<'a, T> Query for Counted<'a, T> {
+    type SqlType = BigInt;
+}
+
+impl<'a, T> QueryId for Counted<'a, T> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl<'a, T, Conn> RunQueryDsl<Conn> for Counted<'a, T> {}
+
+impl<'a, T, DB> QueryFragment<DB> for Counted<'a, T>
+where
+    DB: Backend,
+    T: QueryFragment<DB>,
+{
+    fn walk_ast(&self, mut out: AstPass<DB>) -> QueryResult<()> {
+        out.push_sql("SELECT COUNT(*) FROM (");
+        self.0.walk_ast(out.reborrow())?;
+        out.push_sql(") counted");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::models::user::tests::create_user_with_email;
+    use crate::tests::helpers::tests::get_pool;
+    use actix_web::test;
+    use diesel::{mysql::Mysql, pg::Pg};
+    use uuid::Uuid;
+
+    #[test]
+    fn it_rejects_out_of_range_pagination() {
+        assert_eq!(validate_pagination(Some(1), Some(10)), Ok(()));
+        assert_eq!(
+            validate_pagination(Some(0), Some(CONFIG.max_per_page + 1)),
+            Err(ApiError::ValidationError(vec![
+                "page must be at least 1".into(),
+                format!("per_page must be between 1 and {}", CONFIG.max_per_page),
+            ]))
+        );
+        assert!(validate_pagination(Some(-1), Some(0)).is_err());
+    }
+
+    #[test]
+    fn it_clamps_pagination() {
+        let pagination = get_pagination(Some(-3), Some(0), 25);
+        assert_eq!(pagination.page, 1);
+        assert_eq!(pagination.per_page, 1);
+        assert_eq!(pagination.total_pages, 25);
+        let pagination = get_pagination(None, Some(1_000_000), 25);
+        assert_eq!(pagination.per_page, CONFIG.max_per_page);
+    }
+
+    #[test]
+    fn it_limits_the_page_inside_the_paginated_query() {
+        use crate::schema::users::dsl::{email, users};
+
+        let params = PaginationRequest {
+            page: Some(3),
+            per_page: Some(5),
+        };
+        let query = users.select(email).order(email.asc()).paginate(params);
+        assert_eq!(
+            diesel::debug_query::<Pg, _>(&query).to_string(),
+            "SELECT paginated.*, (SELECT COUNT(*) FROM (SELECT \"users\".\"email\" FROM \"users\" \
+             ORDER BY \"users\".\"email\" ASC) counted) FROM (SELECT \"users\".\"email\" FROM \"users\" \
+             ORDER BY \"users\".\"email\" ASC LIMIT $1 OFFSET $2) paginated -- binds: [5, 10]"
+        );
+        assert_eq!(
+            diesel::debug_query::<Mysql, _>(&query).to_string(),
+            "SELECT paginated.*, (SELECT COUNT(*) FROM (SELECT `users`.`email` FROM `users` \
+             ORDER BY `users`.`email` ASC) counted) FROM (SELECT `users`.`email` FROM `users` \
+             ORDER BY `users`.`email` ASC LIMIT ? OFFSET ?) paginated -- binds: [5, 10]"
+        );
+    }
 
-        let conn = $pool.get()?;
-        let total = $model.select(count_star()).first(&conn)?;
-        let pagination = get_pagination($params.page, $params.per_page, total);
-        let paginated: $response_type = $model
-            .limit(pagination.per_page)
-            .offset(pagination.offset)
-            .load::<$model_type>(&conn)?
-            .into();
+    #[test]
+    fn it_keeps_the_order_across_pages() {
+        use crate::schema::users::dsl::{email, users};
 
-        Ok(paginate::<$response_type>(pagination, paginated, $base)?)
-    }};
+        let prefix = format!("page-{}", Uuid::new_v4());
+        for name in &["c", "a", "d", "b"] {
+            create_user_with_email(&format!("{}-{}@nothing.org", prefix, name)).unwrap();
+        }
+        let conn = get_pool().get().unwrap();
+        let load_page = |page| {
+            let params = PaginationRequest {
+                page: Some(page),
+                per_page: Some(2),
+            };
+            users
+                .select(email)
+                .filter(email.like(format!("{}-%", prefix)))
+                .order(email.desc())
+                .paginate(params)
+                .load_with_total::<String, _>(&conn)
+                .unwrap()
+        };
+        let (first, pagination) = load_page(1);
+        let (second, _) = load_page(2);
+        assert_eq!(pagination.total, 4);
+        let names: Vec<String> = first
+            .into_iter()
+            .chain(second)
+            .map(|row| row[prefix.len() + 1..prefix.len() + 2].to_string())
+            .collect();
+        assert_eq!(names, vec!["d", "c", "b", "a"]);
+
+        // A page past the end still knows the total
+        let (third, pagination) = load_page(3);
+        assert!(third.is_empty());
+        assert_eq!(pagination.total, 4);
+        assert_eq!(pagination.total_pages, 2);
+    }
+
+    #[test]
+    fn it_links_to_the_first_page_of_an_empty_list() {
+        let pagination = get_pagination(Some(2), Some(10), 0);
+        let response = paginate(pagination, (), "http://fake/api/v1/user".into()).unwrap();
+        let first = "http://fake/api/v1/user?page=1&per_page=10";
+        assert_eq!(response.links.last, first);
+        assert_eq!(response.links.prev, Some(first.into()));
+        assert_eq!(response.links.next, None);
+    }
+
+    #[actix_rt::test]
+    async fn it_sends_link_and_total_count_headers() {
+        let pagination = get_pagination(Some(2), Some(10), 25);
+        let response = paginate(pagination, (), "http://fake/api/v1/user".into()).unwrap();
+        let request = test::TestRequest::default().to_http_request();
+        let response = response.respond_to(&request).await.unwrap();
+        let headers = response.headers();
+        assert_eq!(
+            headers.get(LINK).unwrap(),
+            "<http://fake/api/v1/user?page=1&per_page=10>; rel=\"first\", \
+             <http://fake/api/v1/user?page=1&per_page=10>; rel=\"prev\", \
+             <http://fake/api/v1/user?page=3&per_page=10>; rel=\"next\", \
+             <http://fake/api/v1/user?page=3&per_page=10>; rel=\"last\""
+        );
+        assert_eq!(headers.get(TOTAL_COUNT_HEADER).unwrap(), "25");
+    }
 }
@@ -1,42 +1,113 @@
 //! All routes for the application
 
 use crate::handlers::{
-    auth::{login, logout},
+    api_key::{create_api_key, delete_api_key, get_api_key, get_api_keys, update_api_key},
+    audit::get_audit_events,
+    auth::{login, logout, logout_all, refresh},
+    csrf::get_csrf_token,
     health::get_health,
-    user::{create_user, delete_user, get_user, get_users, update_user},
+    impersonation::{end_impersonation, impersonate_user},
+    jwks::get_jwks,
+    me::{delete_me, get_me, update_me},
+    mfa::{confirm_mfa, disable_mfa, enroll_mfa, verify_mfa},
+    oidc::{oidc_callback, oidc_login},
+    password::{change_password, forgot_password, reset_password},
+    session::{delete_session, delete_sessions, get_sessions},
+    user::{create_user, delete_user, get_user, get_users, unlock_user, update_user},
+    verification::verify_email,
+};
+use crate::middleware::{
+    auth::Auth as AuthMiddleware, csrf::Csrf as CsrfMiddleware, permission::RequirePermission,
 };
-use crate::middleware::auth::Auth as AuthMiddleware;
 use actix_files::Files;
-use actix_web::web;
+use actix_web::{guard, http::Method, web};
 
 pub fn routes(cfg: &mut web::ServiceConfig) {
     cfg
         // Healthcheck
         .route("/health", web::get().to(get_health))
+        // Public keys for verifying JWTs
+        .route("/.well-known/jwks.json", web::get().to(get_jwks))
         // /api/v1 routes
         .service(
             web::scope("/api/v1")
                 // Lock down routes with AUTH Middleware
-                .wrap(AuthMiddleware)
+                .wrap(api_auth())
+                // Require a CSRF token for cookie-authenticated writes
+                .wrap(CsrfMiddleware::new())
+                // AUDIT routes
+                .service(
+                    web::resource("/audit-events")
+                        .wrap(RequirePermission("audit:read"))
+                        .route(web::get().to(get_audit_events)),
+                )
                 // AUTH routes
                 .service(
                     web::scope("/auth")
+                        .route("/csrf", web::get().to(get_csrf_token))
+                        .route("/impersonation/end", web::post().to(end_impersonation))
                         .route("/login", web::post().to(login))
-                        .route("/logout", web::get().to(logout)),
+                        .route("/logout", web::get().to(logout))
+                        .route("/logout/all", web::post().to(logout_all))
+                        .route("/mfa", web::delete().to(disable_mfa))
+                        .route("/mfa/confirm", web::post().to(confirm_mfa))
+                        .route("/mfa/enroll", web::post().to(enroll_mfa))
+                        .route("/mfa/verify", web::post().to(verify_mfa))
+                        .route("/oidc/{provider}/callback", web::get().to(oidc_callback))
+                        .route("/oidc/{provider}/login", web::get().to(oidc_login))
+                        .route("/password/forgot", web::post().to(forgot_password))
+                        .route("/password/reset", web::post().to(reset_password))
+                        .route("/refresh", web::post().to(refresh))
+                        .route("/sessions/{id}", web::delete().to(delete_session))
+                        .route("/sessions", web::get().to(get_sessions))
+                        .route("/sessions", web::delete().to(delete_sessions))
+                        .route("/verify", web::get().to(verify_email)),
+                )
+                // API KEY routes
+                .service(
+                    web::scope("/api-keys")
+                        .route("/{id}", web::get().to(get_api_key))
+                        .route("/{id}", web::put().to(update_api_key))
+                        .route("/{id}", web::delete().to(delete_api_key))
+                        .route("", web::get().to(get_api_keys))
+                        .route("", web::post().to(create_api_key)),
+                )
+                // ME routes
+                .service(
+                    web::resource("/me")
+                        .route(web::get().to(get_me))
+                        .route(web::patch().to(update_me))
+                        .route(web::delete().to(delete_me)),
                 )
                 // USER routes
                 .service(
                     web::scope("/user")
                         .route("/{id}", web::get().to(get_user))
                         .route("/{id}", web::put().to(update_user))
-                        .route("/{id}", web::delete().to(delete_user))
+                        .service(
+                            web::resource("/{id}")
+                                .guard(guard::Delete())
+                                .wrap(RequirePermission("user:delete"))
+                                .route(web::delete().to(delete_user)),
+                        )
+                        .service(
+                            web::resource("/{id}/impersonate")
+                                .wrap(RequirePermission("user:impersonate"))
+                                .route(web::post().to(impersonate_user)),
+                        )
+                        .route("/{id}/password", web::put().to(change_password))
+                        .service(
+                            web::resource("/{id}/unlock")
+                                .wrap(RequirePermission("user:unlock"))
+                                .route(web::post().to(unlock_user)),
+                        )
                         .route("", web::get().to(get_users))
                         .route("", web::post().to(create_user)),
                 ),
         )
         // Serve secure static files from the static-private folder
         .service(
-            web::scope("/secure").wrap(AuthMiddleware).service(
+            web::scope("/secure").wrap(AuthMiddleware::new()).service(
                 Files::new("", "./static-secure")
                     .index_file("index.html")
                     .use_last_modified(true),
@@ -51,3 +122,19 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
             ),
         );
 }
+
+/// Routes under /api/v1 that don't require a JWT or API key
+/// Signing up is optional, so an admin creating a user is still the actor
+fn api_auth() -> AuthMiddleware {
+    AuthMiddleware::new()
+        .public_for(&[Method::GET], "/api/v1/auth/csrf")
+        .public_for(&[Method::POST], "/api/v1/auth/login")
+        .public_for(&[Method::POST], "/api/v1/auth/mfa/verify")
+        .public_for(&[Method::GET], "/api/v1/auth/oidc/*/callback")
+        .public_for(&[Method::GET], "/api/v1/auth/oidc/*/login")
+        .public_for(&[Method::POST], "/api/v1/auth/password/forgot")
+        .public_for(&[Method::POST], "/api/v1/auth/password/reset")
+        .public_for(&[Method::POST], "/api/v1/auth/refresh")
+        .public_for(&[Method::GET], "/api/v1/auth/verify")
+        .optional_for(&[Method::POST], "/api/v1/user")
+}
@@ -1,5 +1,141 @@
 //! Auto-generated file from Diesel
 
+table! {
+    api_keys (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        name -> Varchar,
+        prefix -> Varchar,
+        key_hash -> Varchar,
+        scopes -> Text,
+        expires_at -> Nullable<Timestamp>,
+        last_used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    audit_events (id) {
+        id -> Varchar,
+        actor_id -> Nullable<Varchar>,
+        impersonator_id -> Nullable<Varchar>,
+        action -> Varchar,
+        target_id -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        user_agent -> Nullable<Varchar>,
+        metadata -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    email_verifications (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    mfa_recovery_codes (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    password_resets (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    permissions (id) {
+        id -> Varchar,
+        name -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        family_id -> Varchar,
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        rotated_at -> Nullable<Timestamp>,
+        revoked_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    role_permissions (role_id, permission_id) {
+        role_id -> Varchar,
+        permission_id -> Varchar,
+    }
+}
+
+table! {
+    roles (id) {
+        id -> Varchar,
+        name -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        user_agent -> Nullable<Varchar>,
+        ip -> Nullable<Varchar>,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    user_identities (id) {
+        id -> Varchar,
+        user_id -> Varchar,
+        provider -> Varchar,
+        subject -> Varchar,
+        email -> Nullable<Varchar>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    user_mfa (user_id) {
+        user_id -> Varchar,
+        secret -> Varchar,
+        confirmed_at -> Nullable<Timestamp>,
+        last_used_step -> Nullable<Bigint>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    user_roles (user_id, role_id) {
+        user_id -> Varchar,
+        role_id -> Varchar,
+    }
+}
+
 table! {
     users (id) {
         id -> Varchar,
@@ -7,9 +143,32 @@ table! {
         last_name -> Varchar,
         email -> Varchar,
         password -> Varchar,
+        email_verified_at -> Nullable<Timestamp>,
         created_by -> Varchar,
         created_at -> Timestamp,
         updated_by -> Varchar,
         updated_at -> Timestamp,
     }
 }
+
+joinable!(role_permissions -> permissions (permission_id));
+joinable!(role_permissions -> roles (role_id));
+joinable!(user_roles -> roles (role_id));
+joinable!(user_roles -> users (user_id));
+
+allow_tables_to_appear_in_same_query!(
+    api_keys,
+    audit_events,
+    email_verifications,
+    mfa_recovery_codes,
+    password_resets,
+    permissions,
+    refresh_tokens,
+    role_permissions,
+    roles,
+    sessions,
+    user_identities,
+    user_mfa,
+    user_roles,
+    users,
+);
@@ -4,6 +4,7 @@ use crate::auth::get_identity_service;
 use crate::cache::add_cache;
 use crate::config::CONFIG;
 use crate::database::add_pool;
+use crate::mailer::add_mailer;
 use crate::routes::routes;
 use crate::state::new_state;
 use actix_cors::Cors;
@@ -27,6 +28,7 @@ pub async fn server() -> std::io::Result<()> {
             .wrap(Logger::default())
             .wrap(get_identity_service())
             .configure(add_pool)
+            .configure(add_mailer)
             .app_data(data.clone())
             .configure(routes)
     });
@@ -0,0 +1,45 @@
+#[cfg(test)]
+mod tests {
+    use crate::auth::{get_identity_service, API_KEY_HEADER};
+    use crate::cache::add_cache;
+    use crate::database::add_pool;
+    use crate::models::api_key::create;
+    use crate::models::user::tests::create_user;
+    use crate::routes::routes;
+    use crate::tests::helpers::tests::{app_state, get_pool};
+    use actix_web::{dev::ServiceResponse, http::StatusCode, test, App};
+
+    async fn get_users_with_api_key(api_key: &str) -> ServiceResponse {
+        let mut app = test::init_service(
+            App::new()
+                .configure(add_cache)
+                .app_data(app_state())
+                .wrap(get_identity_service())
+                .configure(add_pool)
+                .configure(routes),
+        )
+        .await;
+        test::call_service(
+            &mut app,
+            test::TestRequest::get()
+                .header(API_KEY_HEADER, api_key)
+                .uri("/api/v1/user")
+                .to_request(),
+        )
+        .await
+    }
+
+    #[actix_rt::test]
+    async fn it_authenticates_with_an_api_key() {
+        let user = create_user().unwrap();
+        let (_, api_key) = create(&get_pool(), user.id, "batch", &[], None).unwrap();
+        let response = get_users_with_api_key(&api_key).await;
+        assert!(response.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn it_doesnt_authenticate_with_an_unknown_api_key() {
+        let response = get_users_with_api_key("rak_not-a-key").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,7 +1,18 @@
 #[cfg(test)]
 mod tests {
-    use crate::handlers::auth::LoginRequest;
-    use crate::tests::helpers::tests::{assert_get, assert_post};
+    use crate::auth::{get_identity_service, TokenSource};
+    use crate::cache::add_cache;
+    use crate::database::add_pool;
+    use crate::handlers::auth::{LoginRequest, LoginResponse};
+    use crate::handlers::password::ForgotPasswordRequest;
+    use crate::handlers::user::CreateUserRequest;
+    use crate::mailer::add_mailer;
+    use crate::routes::routes;
+    use crate::tests::helpers::tests::{app_state, assert_get, assert_post, login};
+    use actix_web::{
+        http::{header::AUTHORIZATION, StatusCode},
+        test, App,
+    };
 
     const PATH: &str = "/api/v1/auth";
 
@@ -9,15 +20,113 @@ mod tests {
     async fn it_logs_a_user_in() {
         let params = LoginRequest {
             email: "satoshi@nakamotoinstitute.org".into(),
-            password: "123456".into(),
+            password: "Bitcoin-2009".into(),
         };
         let url = format!("{}/login", PATH);
         assert_post(&url, params).await;
     }
 
+    #[actix_rt::test]
+    async fn it_authenticates_with_a_bearer_token() {
+        let params = LoginRequest {
+            email: "satoshi@nakamotoinstitute.org".into(),
+            password: "Bitcoin-2009".into(),
+        };
+        let mut app = test::init_service(
+            App::new()
+                .configure(add_cache)
+                .app_data(app_state())
+                .data(TokenSource::Both)
+                .wrap(get_identity_service())
+                .configure(add_pool)
+                .configure(routes),
+        )
+        .await;
+
+        let response = test::call_service(
+            &mut app,
+            test::TestRequest::post()
+                .set_json(&params)
+                .uri(&format!("{}/login", PATH))
+                .to_request(),
+        )
+        .await;
+        let login: LoginResponse = test::read_body_json(response).await;
+
+        let response = test::call_service(
+            &mut app,
+            test::TestRequest::get()
+                .header(AUTHORIZATION, format!("Bearer {}", login.token.unwrap()))
+                .uri("/api/v1/user")
+                .to_request(),
+        )
+        .await;
+        assert!(response.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn it_requests_a_password_reset_without_logging_in() {
+        let params = ForgotPasswordRequest {
+            email: "satoshi@nakamotoinstitute.org".into(),
+        };
+        let mut app = test::init_service(
+            App::new()
+                .configure(add_cache)
+                .app_data(app_state())
+                .wrap(get_identity_service())
+                .configure(add_pool)
+                .configure(add_mailer)
+                .configure(routes),
+        )
+        .await;
+
+        let response = test::call_service(
+            &mut app,
+            test::TestRequest::post()
+                .set_json(&params)
+                .uri(&format!("{}/password/forgot", PATH))
+                .to_request(),
+        )
+        .await;
+        assert!(response.status().is_success());
+    }
+
     #[actix_rt::test]
     async fn it_logs_a_user_out() {
         let url = format!("{}/logout", PATH);
         assert_get(&url).await;
     }
+
+    #[actix_rt::test]
+    async fn it_requires_a_csrf_token_for_cookie_authenticated_writes() {
+        let params = CreateUserRequest {
+            first_name: "Satoshi".into(),
+            last_name: "Nakamoto".into(),
+            email: "satoshi@nakamotoinstitute.org".into(),
+            password: "Bitcoin-2009".into(),
+        };
+        let mut app = test::init_service(
+            App::new()
+                .configure(add_cache)
+                .app_data(app_state())
+                .wrap(get_identity_service())
+                .configure(add_pool)
+                .configure(add_mailer)
+                .configure(routes),
+        )
+        .await;
+        let login = login().await;
+        let cookie = login.response().cookies().next().unwrap().to_owned();
+
+        let response = test::call_service(
+            &mut app,
+            test::TestRequest::post()
+                .set_json(&params)
+                .cookie(cookie)
+                .uri("/api/v1/user")
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
 }
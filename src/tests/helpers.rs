@@ -1,27 +1,34 @@
 #[cfg(test)]
 pub mod tests {
-    use crate::auth::get_identity_service;
-    use crate::cache::add_cache;
+    use crate::auth::{generate_token, get_identity_service, AuthUser};
+    use crate::cache::{add_cache, Cache};
     use crate::config::CONFIG;
     use crate::database::{add_pool, init_pool, Pool};
     use crate::handlers::auth::LoginRequest;
+    use crate::mailer::{add_mailer, Mailer, MailerType, MemoryMailer};
+    use crate::middleware::csrf::{csrf_cookie, CSRF_HEADER};
+    use crate::models::user::tests::create_user_with_email;
     use crate::pagination::PaginationRequest;
     use crate::routes::routes;
     use crate::state::{new_state, AppState};
+    use actix_identity::Identity;
+    use actix_redis::RedisActor;
     use actix_web::{
         dev::ServiceResponse,
         test,
         web::{Data, HttpRequest, Query},
-        App,
+        App, FromRequest,
     };
     use diesel::mysql::MysqlConnection;
     use serde::Serialize;
+    use std::sync::Arc;
+    use uuid::Uuid;
 
     /// Helper for HTTP GET integration tests
     pub async fn test_get(route: &str) -> ServiceResponse {
         let login_request = LoginRequest {
             email: "satoshi@nakamotoinstitute.org".into(),
-            password: "123456".into(),
+            password: "Bitcoin-2009".into(),
         };
 
         let mut app = test::init_service(
@@ -30,6 +37,7 @@ pub mod tests {
                 .app_data(app_state())
                 .wrap(get_identity_service())
                 .configure(add_pool)
+                .configure(add_mailer)
                 .configure(routes),
         )
         .await;
@@ -55,6 +63,7 @@ pub mod tests {
     }
 
     /// Helper for HTTP POST integration tests
+    /// Sends a CSRF token, as the request is authenticated with the identity cookie
     pub async fn test_post<T: Serialize>(route: &str, params: T) -> ServiceResponse {
         let mut app = test::init_service(
             App::new()
@@ -62,16 +71,20 @@ pub mod tests {
                 .app_data(app_state())
                 .wrap(get_identity_service())
                 .configure(add_pool)
+                .configure(add_mailer)
                 .configure(routes),
         )
         .await;
         let login = login().await;
         let cookie = login.response().cookies().next().unwrap().to_owned();
+        let csrf_token = generate_token();
         test::call_service(
             &mut app,
             test::TestRequest::post()
                 .set_json(&params)
                 .cookie(cookie.clone())
+                .cookie(csrf_cookie(&csrf_token))
+                .header(CSRF_HEADER, csrf_token)
                 .uri(route)
                 .to_request(),
         )
@@ -83,18 +96,6 @@ pub mod tests {
         test::TestRequest::get().uri(url).to_http_request()
     }
 
-    /// Helper to login for tests
-    // pub fn login_request() -> Request {
-    //     let login_request = LoginRequest {
-    //         email: "satoshi@nakamotoinstitute.org".into(),
-    //         password: "123456".into(),
-    //     };
-    //     test::TestRequest::post()
-    //         .set_json(&login_request)
-    //         .uri("/api/v1/auth/login")
-    //         .to_request()
-    // }
-
     /// Assert that a route is successful for HTTP GET requests
     pub async fn assert_get(route: &str) -> ServiceResponse {
         let response = test_get(route).await;
@@ -111,7 +112,6 @@ pub mod tests {
 
     /// Returns a r2d2 Pooled Connection to be used in tests
     pub fn get_pool() -> Pool<MysqlConnection> {
-        println!("CONFIG: {:#?}", CONFIG.database_url);
         init_pool::<MysqlConnection>(CONFIG.clone()).unwrap()
     }
 
@@ -120,6 +120,39 @@ pub mod tests {
         Data::new(get_pool())
     }
 
+    /// Returns a redis actor wrapped in Actix Application Data
+    pub fn get_data_cache() -> Cache {
+        Data::new(RedisActor::start(&CONFIG.redis_url))
+    }
+
+    /// Returns an in-memory mailer wrapped in Actix Application Data
+    pub fn get_data_mailer() -> MailerType {
+        Data::from(Arc::new(MemoryMailer::default()) as Arc<dyn Mailer>)
+    }
+
+    /// Returns an Identity extracted from a bare JSON request
+    pub async fn get_identity() -> Identity {
+        let (request, mut payload) =
+            test::TestRequest::with_header("content-type", "application/json").to_http_parts();
+        Option::<Identity>::from_request(&request, &mut payload)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    /// Creates a `<prefix>-<uuid>@nothing.org` user and returns it as an AuthUser
+    pub fn get_auth_user(prefix: &str, roles: Vec<String>, permissions: Vec<String>) -> AuthUser {
+        let email = format!("{}-{}@nothing.org", prefix, Uuid::new_v4());
+        let user = create_user_with_email(&email).unwrap();
+        AuthUser {
+            id: user.id.to_string(),
+            email,
+            roles,
+            permissions,
+            impersonator: None,
+        }
+    }
+
     /// Utility to get pagination params
     pub fn get_pagination_params() -> PaginationRequest {
         PaginationRequest {
@@ -137,12 +170,13 @@ pub mod tests {
     pub async fn login() -> ServiceResponse {
         let login_request = LoginRequest {
             email: "satoshi@nakamotoinstitute.org".into(),
-            password: "123456".into(),
+            password: "Bitcoin-2009".into(),
         };
         let mut app = test::init_service(
             App::new()
                 .wrap(get_identity_service())
                 .configure(add_pool)
+                .configure(add_mailer)
                 .configure(routes),
         )
         .await;
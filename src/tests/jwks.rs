@@ -0,0 +1,9 @@
+#[cfg(test)]
+mod tests {
+    use crate::tests::helpers::tests::assert_get;
+
+    #[actix_rt::test]
+    async fn test_jwks() {
+        assert_get("/.well-known/jwks.json").await;
+    }
+}
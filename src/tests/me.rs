@@ -0,0 +1,11 @@
+#[cfg(test)]
+mod tests {
+    use crate::tests::helpers::tests::assert_get;
+
+    const PATH: &str = "/api/v1/me";
+
+    #[actix_rt::test]
+    async fn it_gets_me() {
+        assert_get(PATH).await;
+    }
+}
@@ -1,6 +1,9 @@
 //! Integration tests
 
+pub mod api_key;
 pub mod auth;
 pub mod health;
 pub mod helpers;
+pub mod jwks;
+pub mod me;
 pub mod user;
@@ -1,8 +1,13 @@
 #[cfg(test)]
 mod tests {
+    use crate::auth::get_identity_service;
+    use crate::cache::add_cache;
+    use crate::database::add_pool;
     use crate::handlers::user::{tests::get_first_users_id, CreateUserRequest};
-    use crate::tests::helpers::tests::{assert_get, assert_post};
-    use actix_web::web::Path;
+    use crate::mailer::add_mailer;
+    use crate::routes::routes;
+    use crate::tests::helpers::tests::{app_state, assert_get, assert_post};
+    use actix_web::{test, web::Path, App};
     use uuid::Uuid;
 
     const PATH: &str = "/api/v1/user";
@@ -25,8 +30,38 @@ mod tests {
             first_name: "Satoshi".into(),
             last_name: "Nakamoto".into(),
             email: "satoshi@nakamotoinstitute.org".into(),
-            password: "123456".into(),
+            password: "Bitcoin-2009".into(),
         };
         assert_post(PATH, params).await;
     }
+
+    #[actix_rt::test]
+    async fn it_signs_up_without_logging_in() {
+        let params = CreateUserRequest {
+            first_name: "Hal".into(),
+            last_name: "Finney".into(),
+            email: format!("signup-{}@nothing.org", Uuid::new_v4()),
+            password: "Bitcoin-2009".into(),
+        };
+        let mut app = test::init_service(
+            App::new()
+                .configure(add_cache)
+                .app_data(app_state())
+                .wrap(get_identity_service())
+                .configure(add_pool)
+                .configure(add_mailer)
+                .configure(routes),
+        )
+        .await;
+
+        let response = test::call_service(
+            &mut app,
+            test::TestRequest::post()
+                .set_json(&params)
+                .uri(PATH)
+                .to_request(),
+        )
+        .await;
+        assert!(response.status().is_success());
+    }
 }
@@ -0,0 +1,182 @@
+//! Brute-force protection for logins
+//!
+//! Failed logins are counted in the cache per email and per IP address.
+//! Once an email reaches login_backoff_threshold failures, each further
+//! failure blocks it for twice as long, starting at login_backoff_base
+//! seconds.  At login_lockout_threshold failures it's locked out for
+//! login_lockout_duration minutes.
+//!
+//! IP addresses are only locked out, at login_ip_lockout_threshold failures,
+//! so users behind a shared address don't slow each other down.
+//!
+//! Failures are forgotten login_lockout_duration minutes after the last one.
+
+use crate::cache::{delete, expire, incr, set_ex, ttl, Cache};
+use crate::config::CONFIG;
+use crate::errors::ApiError;
+
+/// Reject a login attempt if the email or IP address is blocked
+pub async fn check(cache: Cache, email: &str, ip: Option<&str>) -> Result<(), ApiError> {
+    let mut retry_after = ttl(cache.clone(), &blocked_key(&email_subject(email))).await?;
+    if let Some(ip) = ip {
+        retry_after = retry_after.max(ttl(cache, &blocked_key(&ip_subject(ip))).await?);
+    }
+
+    if retry_after > 0 {
+        return Err(ApiError::TooManyRequests(
+            "Too many failed login attempts, try again later".into(),
+            retry_after,
+        ));
+    }
+    Ok(())
+}
+
+/// Count a failed login, blocking the email and IP address once they reach
+/// their thresholds
+pub async fn record_failure(cache: Cache, email: &str, ip: Option<&str>) -> Result<(), ApiError> {
+    let email = email_subject(email);
+    let failures = count_failure(cache.clone(), &email).await?;
+    let seconds = delay(
+        failures,
+        CONFIG.login_backoff_threshold,
+        CONFIG.login_lockout_threshold,
+    );
+    block_for(cache.clone(), &email, seconds).await?;
+
+    if let Some(ip) = ip {
+        let ip = ip_subject(ip);
+        let failures = count_failure(cache.clone(), &ip).await?;
+        let threshold = CONFIG.login_ip_lockout_threshold;
+        block_for(cache, &ip, delay(failures, threshold, threshold)).await?;
+    }
+    Ok(())
+}
+
+/// Forget an email's failed logins once the user logs in
+pub async fn record_success(cache: Cache, email: &str) -> Result<(), ApiError> {
+    unlock(cache, email).await
+}
+
+/// Forget an email's failed logins and lift any block on it
+pub async fn unlock(cache: Cache, email: &str) -> Result<(), ApiError> {
+    let email = email_subject(email);
+    delete(cache.clone(), &failures_key(&email)).await?;
+    delete(cache, &blocked_key(&email)).await?;
+    Ok(())
+}
+
+async fn count_failure(cache: Cache, subject: &str) -> Result<i64, ApiError> {
+    let key = failures_key(subject);
+    let failures = incr(cache.clone(), &key).await?;
+    expire(cache, &key, CONFIG.login_lockout_duration * 60).await?;
+    Ok(failures)
+}
+
+async fn block_for(cache: Cache, subject: &str, seconds: i64) -> Result<(), ApiError> {
+    if seconds > 0 {
+        set_ex(cache, &blocked_key(subject), "1", seconds).await?;
+    }
+    Ok(())
+}
+
+/// The number of seconds to block for after a number of failures
+fn delay(failures: i64, backoff_threshold: i64, lockout_threshold: i64) -> i64 {
+    let lockout = CONFIG.login_lockout_duration * 60;
+    if failures >= lockout_threshold {
+        return lockout;
+    }
+    if failures < backoff_threshold {
+        return 0;
+    }
+
+    let doublings = (failures - backoff_threshold).min(30) as u32;
+    (CONFIG.login_backoff_base * 2_i64.pow(doublings)).min(lockout)
+}
+
+fn email_subject(email: &str) -> String {
+    format!("email:{}", email.trim().to_lowercase())
+}
+
+fn ip_subject(ip: &str) -> String {
+    format!("ip:{}", ip)
+}
+
+fn failures_key(subject: &str) -> String {
+    format!("login:failures:{}", subject)
+}
+
+fn blocked_key(subject: &str) -> String {
+    format!("login:blocked:{}", subject)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::helpers::tests::get_data_cache;
+    use uuid::Uuid;
+
+    fn get_email() -> String {
+        format!("throttle-{}@nothing.org", Uuid::new_v4())
+    }
+
+    async fn fail(cache: Cache, email: &str, ip: Option<&str>, times: i64) {
+        for _ in 0..times {
+            record_failure(cache.clone(), email, ip).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn it_backs_off_exponentially() {
+        let base = CONFIG.login_backoff_base;
+        assert_eq!(delay(2, 3, 10), 0);
+        assert_eq!(delay(3, 3, 10), base);
+        assert_eq!(delay(4, 3, 10), base * 2);
+        assert_eq!(delay(5, 3, 10), base * 4);
+    }
+
+    #[test]
+    fn it_locks_out_at_the_lockout_threshold() {
+        let lockout = CONFIG.login_lockout_duration * 60;
+        assert_eq!(delay(10, 3, 10), lockout);
+        assert_eq!(delay(50, 50, 50), lockout);
+    }
+
+    #[actix_rt::test]
+    async fn it_allows_a_few_failed_logins() {
+        let cache = get_data_cache();
+        let email = get_email();
+        fail(
+            cache.clone(),
+            &email,
+            None,
+            CONFIG.login_backoff_threshold - 1,
+        )
+        .await;
+        assert!(check(cache, &email, None).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn it_locks_out_an_email() {
+        let cache = get_data_cache();
+        let email = get_email();
+        fail(cache.clone(), &email, None, CONFIG.login_lockout_threshold).await;
+        match check(cache.clone(), &email.to_uppercase(), None).await {
+            Err(ApiError::TooManyRequests(_, retry_after)) => assert!(retry_after > 0),
+            _ => panic!("expected the email to be locked out"),
+        }
+
+        unlock(cache.clone(), &email).await.unwrap();
+        assert!(check(cache, &email, None).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn it_locks_out_an_ip_address() {
+        let cache = get_data_cache();
+        let ip = Uuid::new_v4().to_string();
+        for _ in 0..CONFIG.login_ip_lockout_threshold {
+            fail(cache.clone(), &get_email(), Some(&ip), 1).await;
+        }
+        assert!(check(cache.clone(), &get_email(), Some(&ip)).await.is_err());
+        assert!(check(cache, &get_email(), None).await.is_ok());
+    }
+}
@@ -0,0 +1,135 @@
+//! Time-based one-time passwords (TOTP, RFC 6238)
+//!
+//! Codes are 6 digits derived from HMAC-SHA1 over 30 second time steps,
+//! which is what authenticator apps expect by default.
+
+use crate::config::CONFIG;
+use base32::Alphabet;
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::Rng;
+use sha1::Sha1;
+
+const DIGITS: u32 = 6;
+const PERIOD: i64 = 30;
+
+/// Accept codes from one step either side of now, allowing for clock drift
+const SKEW: i64 = 1;
+
+const ALPHABET: Alphabet = Alphabet::RFC4648 { padding: false };
+
+/// Generate a random 160 bit secret, base32 encoded
+pub fn generate_secret() -> String {
+    let bytes: [u8; 20] = rand::thread_rng().gen();
+    base32::encode(ALPHABET, &bytes)
+}
+
+/// The otpauth:// URI that authenticator apps scan as a QR code
+pub fn otpauth_uri(secret: &str, account: &str) -> String {
+    let issuer = utf8_percent_encode(&CONFIG.mfa_issuer, NON_ALPHANUMERIC).to_string();
+    let account = utf8_percent_encode(account, NON_ALPHANUMERIC).to_string();
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, account, secret, issuer, DIGITS, PERIOD
+    )
+}
+
+/// The time step for the current time
+pub fn current_step() -> i64 {
+    Utc::now().timestamp() / PERIOD
+}
+
+/// Generate the code for a time step
+/// Returns None if the secret isn't valid base32
+pub fn code_at(secret: &str, step: i64) -> Option<String> {
+    let key = base32::decode(ALPHABET, secret)?;
+    let mut mac = Hmac::<Sha1>::new_varkey(&key).ok()?;
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation, see RFC 4226 section 5.3
+    let offset = (hash[hash.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    let code = binary % 10_u32.pow(DIGITS);
+    Some(format!("{:0width$}", code, width = DIGITS as usize))
+}
+
+/// Check a code against the current time
+///
+/// Returns the matching time step, which should be stored and passed back
+/// in as last_step so each code can only be used once.
+pub fn verify(secret: &str, code: &str, last_step: Option<i64>) -> Option<i64> {
+    let now = current_step();
+    ((now - SKEW)..=(now + SKEW))
+        .filter(|step| last_step.map_or(true, |last_step| *step > last_step))
+        .find(|step| code_at(secret, *step).as_deref() == Some(code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // "12345678901234567890", the SHA1 secret from RFC 6238 appendix B
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn it_generates_the_rfc_6238_test_vectors() {
+        assert_eq!(code_at(RFC_SECRET, 59 / PERIOD), Some("287082".into()));
+        assert_eq!(
+            code_at(RFC_SECRET, 1111111109 / PERIOD),
+            Some("081804".into())
+        );
+        assert_eq!(
+            code_at(RFC_SECRET, 2000000000 / PERIOD),
+            Some("279037".into())
+        );
+    }
+
+    #[test]
+    fn it_generates_a_base32_secret() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), 32);
+        assert!(code_at(&secret, 1).is_some());
+    }
+
+    #[test]
+    fn it_doesnt_generate_a_code_for_an_invalid_secret() {
+        assert_eq!(code_at("not base32!", 1), None);
+    }
+
+    #[test]
+    fn it_verifies_a_current_code() {
+        let secret = generate_secret();
+        let step = current_step();
+        let code = code_at(&secret, step).unwrap();
+        assert_eq!(verify(&secret, &code, None), Some(step));
+    }
+
+    #[test]
+    fn it_doesnt_verify_a_used_code() {
+        let secret = generate_secret();
+        let step = current_step();
+        let code = code_at(&secret, step).unwrap();
+        assert_eq!(verify(&secret, &code, Some(step)), None);
+    }
+
+    #[test]
+    fn it_doesnt_verify_an_old_code() {
+        let secret = generate_secret();
+        let code = code_at(&secret, current_step() - 10).unwrap();
+        assert_eq!(verify(&secret, &code, None), None);
+    }
+
+    #[test]
+    fn it_creates_an_otpauth_uri() {
+        let uri = otpauth_uri("ABC", "satoshi@nakamotoinstitute.org");
+        assert!(uri.starts_with("otpauth://totp/"));
+        assert!(uri.contains(":satoshi%40nakamotoinstitute%2Eorg?secret=ABC&"));
+    }
+}
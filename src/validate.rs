@@ -1,71 +1,245 @@
 //! Validation-related functions to work with the validator crate
 
+use crate::config::{Config, CONFIG};
 use crate::errors::ApiError;
 use actix_web::web::Json;
+use std::collections::HashSet;
+use std::fs;
 use validator::{Validate, ValidationErrors};
 
+// Throw the policy into a PASSWORD_POLICY lazy_static to avoid reading the
+// breached password list on every request
+lazy_static! {
+    pub static ref PASSWORD_POLICY: PasswordPolicy = PasswordPolicy::from_config(&CONFIG);
+}
+
+/// The rules a new password must follow, see validate_password
+#[derive(Clone, Debug, Default)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub min_character_classes: usize,
+    pub breached: HashSet<String>,
+}
+
 /// Validate a struct and collect and return the errors
 pub fn validate<T>(params: &Json<T>) -> Result<(), ApiError>
 where
-  T: Validate,
+    T: Validate,
 {
-  match params.validate() {
-    Ok(()) => Ok(()),
-    Err(error) => Err(ApiError::ValidationError(collect_errors(error))),
-  }
+    match params.validate() {
+        Ok(()) => Ok(()),
+        Err(error) => Err(ApiError::ValidationError(collect_errors(error))),
+    }
+}
+
+/// Validate a new password against the PASSWORD_POLICY
+///
+/// personal_info holds the user's email and names, which the password must
+/// not contain.  All of the broken rules are returned as a ValidationError.
+pub fn validate_password(password: &str, personal_info: &[&str]) -> Result<(), ApiError> {
+    let errors = PASSWORD_POLICY.check(password, personal_info);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ApiError::ValidationError(errors))
+    }
+}
+
+impl PasswordPolicy {
+    /// Build the policy from PASSWORD_* variables, reading the breached list
+    pub fn from_config(config: &Config) -> Self {
+        let breached = match config.password_breached_list.as_deref() {
+            Some(path) if !path.is_empty() => match fs::read_to_string(path) {
+                Ok(list) => parse_breached_list(&list),
+                Err(error) => panic!("Password Policy Error: cannot read {}: {}", path, error),
+            },
+            _ => HashSet::new(),
+        };
+
+        PasswordPolicy {
+            min_length: config.password_min_length,
+            min_character_classes: config.password_min_character_classes,
+            breached,
+        }
+    }
+
+    /// Check a password, returning a message for each broken rule
+    pub fn check(&self, password: &str, personal_info: &[&str]) -> Vec<String> {
+        let mut errors = vec![];
+        let lowercase = password.to_lowercase();
+
+        if password.chars().count() < self.min_length {
+            errors.push(format!(
+                "password must be at least {} characters",
+                self.min_length
+            ));
+        }
+
+        if character_classes(password) < self.min_character_classes {
+            errors.push(format!(
+        "password must use at least {} of lowercase letters, uppercase letters, digits and symbols",
+        self.min_character_classes
+      ));
+        }
+
+        if personal_parts(personal_info)
+            .iter()
+            .any(|part| lowercase.contains(part.as_str()))
+        {
+            errors.push("password must not contain your email or name".into());
+        }
+
+        if self.breached.contains(&lowercase) {
+            errors.push("password has appeared in a data breach, choose another".into());
+        }
+
+        errors
+    }
+}
+
+/// Count the classes of characters used: lowercase, uppercase, digits, symbols
+fn character_classes(password: &str) -> usize {
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+    classes.iter().filter(|used| **used).count()
+}
+
+/// Lowercase the user's email, the name part of their email, and their names
+/// Parts shorter than 3 characters are too common to reject
+fn personal_parts(personal_info: &[&str]) -> Vec<String> {
+    personal_info
+        .iter()
+        .flat_map(|info| {
+            let info = info.trim().to_lowercase();
+            let name = info.split('@').next().unwrap_or_default().to_string();
+            vec![info, name]
+        })
+        .filter(|part| part.chars().count() >= 3)
+        .collect()
+}
+
+/// One password per line, ignoring blank lines and # comments
+fn parse_breached_list(list: &str) -> HashSet<String> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_lowercase)
+        .collect()
 }
 
 /// Collect ValidationErrors and return a vector of the messages
 /// Adds a default_error when none is supplied
 fn collect_errors(error: ValidationErrors) -> Vec<String> {
-  error
-    .field_errors()
-    .into_iter()
-    .map(|error| {
-      let default_error = format!("{} is required", error.0);
-      error.1[0]
-        .message
-        .as_ref()
-        .unwrap_or(&std::borrow::Cow::Owned(default_error))
-        .to_string()
-    })
-    .collect()
+    error
+        .field_errors()
+        .into_iter()
+        .map(|error| {
+            let default_error = format!("{} is required", error.0);
+            error.1[0]
+                .message
+                .as_ref()
+                .unwrap_or(&std::borrow::Cow::Owned(default_error))
+                .to_string()
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
-  use super::*;
-  use serde_json::json;
-
-  #[derive(Debug, Deserialize, Serialize, Validate)]
-  pub struct TestRequest {
-    #[validate(length(
-      min = 3,
-      message = "first_name is required and must be at least 3 characters"
-    ))]
-    pub first_name: String,
-  }
-
-  fn get_test_request() -> TestRequest {
-    let json = json!({"first_name": "a"});
-    serde_json::from_value::<TestRequest>(json).unwrap()
-  }
-
-  #[test]
-  fn it_collects_errors() {
-    let request = get_test_request();
-    let errors = request.validate().unwrap_err();
-    let response = collect_errors(errors);
-    assert!(response.len() > 0);
-  }
-
-  #[test]
-  fn it_validates() {
-    let request = get_test_request();
-    let response = validate(&Json(request)).unwrap_err();
-    let expected_error = ApiError::ValidationError(vec![
-      "first_name is required and must be at least 3 characters".to_string(),
-    ]);
-    assert_eq!(response, expected_error);
-  }
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize, Serialize, Validate)]
+    pub struct TestRequest {
+        #[validate(length(
+            min = 3,
+            message = "first_name is required and must be at least 3 characters"
+        ))]
+        pub first_name: String,
+    }
+
+    fn get_test_request() -> TestRequest {
+        let json = json!({"first_name": "a"});
+        serde_json::from_value::<TestRequest>(json).unwrap()
+    }
+
+    #[test]
+    fn it_collects_errors() {
+        let request = get_test_request();
+        let errors = request.validate().unwrap_err();
+        let response = collect_errors(errors);
+        assert!(response.len() > 0);
+    }
+
+    #[test]
+    fn it_validates() {
+        let request = get_test_request();
+        let response = validate(&Json(request)).unwrap_err();
+        let expected_error = ApiError::ValidationError(vec![
+            "first_name is required and must be at least 3 characters".to_string(),
+        ]);
+        assert_eq!(response, expected_error);
+    }
+
+    fn get_password_policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            min_character_classes: 3,
+            breached: parse_breached_list("# common\n123456\nPassword1!\n\n"),
+        }
+    }
+
+    #[test]
+    fn it_accepts_a_strong_password() {
+        let errors =
+            get_password_policy().check("Bitcoin-2009", &["satoshi@nothing.org", "Satoshi"]);
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn it_rejects_a_short_password() {
+        let errors = get_password_policy().check("Ab1!", &[]);
+        assert_eq!(
+            errors,
+            vec!["password must be at least 8 characters".to_string()]
+        );
+    }
+
+    #[test]
+    fn it_rejects_a_password_with_too_few_character_classes() {
+        let errors = get_password_policy().check("bitcoin-genesis", &[]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("password must use at least 3"));
+    }
+
+    #[test]
+    fn it_rejects_a_password_containing_the_users_email_or_name() {
+        let policy = get_password_policy();
+        let personal_info = ["nakamoto@nothing.org", "Satoshi", "Nakamoto"];
+        let expected = vec!["password must not contain your email or name".to_string()];
+        assert_eq!(policy.check("Satoshi-2009", &personal_info), expected);
+        assert_eq!(policy.check("2009-NAKAMOTO!", &personal_info), expected);
+    }
+
+    #[test]
+    fn it_rejects_a_breached_password() {
+        let errors = get_password_policy().check("password1!", &[]);
+        assert_eq!(
+            errors,
+            vec!["password has appeared in a data breach, choose another".to_string()]
+        );
+    }
+
+    #[test]
+    fn it_validates_a_password() {
+        let response = validate_password("123456", &[]).unwrap_err();
+        match response {
+            ApiError::ValidationError(errors) => assert!(!errors.is_empty()),
+            _ => panic!("expected a ValidationError"),
+        }
+    }
 }